@@ -1,19 +1,13 @@
 #[macro_use]
 extern crate criterion;
 
-use criterion::{BatchSize, Criterion, ParameterizedBenchmark};
+use criterion::Criterion;
 use kvs::engine::kv::KvStore;
-use kvs::engine::sled::SledKvsEngine;
 use kvs::engine::KvsEngine;
-use rand::prelude::*;
-use sled::Db;
-use std::iter;
-use std::path::Path;
-use std::sync::Arc;
 use tempfile::TempDir;
 
-fn kv(store: &mut dyn KvsEngine) {
-    for j in 1..10 {
+fn kv<E: KvsEngine>(store: &E) {
+    for _ in 1..10 {
         for i in 1..1024 {
             store.set(format!("key{}", i), "value".to_string()).unwrap();
         }
@@ -21,9 +15,9 @@ fn kv(store: &mut dyn KvsEngine) {
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
-    let temp_dir = Path::new("/tmp/db");
-    let mut store = KvStore::open(temp_dir.to_path_buf()).expect("unable to open kvstore");
-    c.bench_function("kvstore", move |b| b.iter(|| kv(&mut store)));
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).expect("unable to open kvstore");
+    c.bench_function("kvstore", move |b| b.iter(|| kv(&store)));
 }
 
 criterion_group!(benches, criterion_benchmark);
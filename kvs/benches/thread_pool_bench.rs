@@ -0,0 +1,67 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::Criterion;
+use kvs::client::KvsClient;
+use kvs::common::{Action, Command};
+use kvs::engine::kv::KvStore;
+use kvs::server::KvsServer;
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const CLIENTS: usize = 8;
+const REQUESTS_PER_CLIENT: usize = 25;
+
+// Starts a server in the background. It lives until the bench process exits.
+fn start_server<P: ThreadPool + 'static>(addr: &'static str, threads: u32) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).expect("unable to open kvstore");
+    thread::spawn(move || {
+        let pool = P::new(threads).expect("unable to create thread pool");
+        KvsServer::new(addr.to_owned(), store, pool).run()
+    });
+    thread::sleep(Duration::from_millis(500));
+    temp_dir
+}
+
+fn concurrent_sets(addr: &'static str) {
+    let handles: Vec<_> = (0..CLIENTS)
+        .map(|c| {
+            thread::spawn(move || {
+                let client = KvsClient::new();
+                for i in 0..REQUESTS_PER_CLIENT {
+                    let key = format!("key{}", c * REQUESTS_PER_CLIENT + i);
+                    let command = Command::new(Action::SET, key.into_bytes(), b"value".to_vec());
+                    client.send_command(&command, addr).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let _naive = start_server::<NaiveThreadPool>("127.0.0.1:4101", 4);
+    let _shared = start_server::<SharedQueueThreadPool>("127.0.0.1:4102", 4);
+    let _rayon = start_server::<RayonThreadPool>("127.0.0.1:4103", 4);
+    c.bench_function("naive_pool_sets", |b| {
+        b.iter(|| concurrent_sets("127.0.0.1:4101"))
+    });
+    c.bench_function("shared_queue_pool_sets", |b| {
+        b.iter(|| concurrent_sets("127.0.0.1:4102"))
+    });
+    c.bench_function("rayon_pool_sets", |b| {
+        b.iter(|| concurrent_sets("127.0.0.1:4103"))
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = criterion_benchmark
+}
+criterion_main!(benches);
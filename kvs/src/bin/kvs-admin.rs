@@ -0,0 +1,575 @@
+extern crate clap;
+#[macro_use]
+extern crate log;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::engine::archive::{self, RestorePoint};
+use kvs::engine::backup;
+use kvs::engine::convert;
+use kvs::engine::dump::{self, DumpFormat};
+use kvs::engine::inspect::{self, Damage};
+use kvs::engine::marker::{self, ENGINES};
+use kvs::engine::migrate::{self, FORMAT_VERSION};
+use kvs::engine::sled::{self, SledKvsEngine, SledOptions};
+use kvs::{KvError, KvStore, KvStoreOptions, KvsEngine, Result};
+use log::LevelFilter;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::process::exit;
+
+fn main() {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let matches = App::new("kvs-admin")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Offline maintenance of kvs data directories")
+        .setting(AppSettings::DisableHelpSubcommand)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Upgrade a kvs store, or a sled 0.24 store with --to, to the current format")
+                .arg(Arg::with_name("DIR").required(true))
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("DEST")
+                        .takes_value(true)
+                        .help("Write the upgraded store to DEST, leaving DIR as it is"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Copy a checkpoint into DIR and check that every value reads back")
+                .arg(
+                    Arg::with_name("SOURCE")
+                        .required(true)
+                        .help("A checkpoint, or an archive with --until"),
+                )
+                .arg(Arg::with_name("DIR").required(true))
+                .arg(
+                    Arg::with_name("until")
+                        .long("until")
+                        .value_name("SEQ|@TIME")
+                        .takes_value(true)
+                        .help(
+                            "Rebuild the store as of a write number, or of the end of a \
+                             second since the Unix epoch, from an archive",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Write every live key of a store to a JSON Lines or CSV file")
+                .arg(Arg::with_name("DIR").required(true))
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("Write to FILE instead of stdout, appending with --after"),
+                )
+                .args(&after_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("load")
+                .about("Write the keys of a dump into a store")
+                .arg(Arg::with_name("DIR").required(true))
+                .arg(Arg::with_name("FILE").help("The dump to read, stdin if not given"))
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("engine")
+                        .long("engine")
+                        .value_name("ENGINE-NAME")
+                        .takes_value(true)
+                        .possible_values(&ENGINES)
+                        .help("Engine of a new store; defaults to kvs"),
+                )
+                .args(&after_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Copy a store into DEST under another engine and check the copy")
+                .arg(Arg::with_name("DIR").required(true))
+                .arg(Arg::with_name("DEST").required(true))
+                .arg(
+                    Arg::with_name("engine")
+                        .long("engine")
+                        .value_name("ENGINE-NAME")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&ENGINES),
+                )
+                .arg(Arg::with_name("cut-over").long("cut-over").help(
+                    "Then swap the copy into DIR and leave the original in DEST; \
+                             fails while a server has either open",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("checksum")
+                .about("Print the number of keys of a store and a checksum of its contents")
+                .arg(Arg::with_name("DIR").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check the framing and checksum of every record of a kvs store")
+                .arg(Arg::with_name("DIR").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("List the records of a kvs store with their offsets, kinds and sizes")
+                .arg(Arg::with_name("DIR").required(true))
+                .arg(
+                    Arg::with_name("segment")
+                        .long("segment")
+                        .value_name("ID")
+                        .takes_value(true)
+                        .help("List only segment ID"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Show the live and dead bytes of every segment of a kvs store")
+                .arg(Arg::with_name("DIR").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Copy every readable record of a kvs store into a new store in DEST")
+                .arg(Arg::with_name("DIR").required(true))
+                .arg(Arg::with_name("DEST").required(true)),
+        )
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        ("migrate", Some(matches)) => run_migrate(matches),
+        ("restore", Some(matches)) => run_restore(matches),
+        ("dump", Some(matches)) => run_dump(matches),
+        ("load", Some(matches)) => run_load(matches),
+        ("convert", Some(matches)) => run_convert(matches),
+        ("checksum", Some(matches)) => run_checksum(matches),
+        ("verify", Some(matches)) => run_verify(matches),
+        ("inspect", Some(matches)) => run_inspect(matches),
+        ("stats", Some(matches)) => run_stats(matches),
+        ("repair", Some(matches)) => run_repair(matches),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+fn run_migrate(matches: &ArgMatches) -> Result<()> {
+    let dir = Path::new(matches.value_of("DIR").unwrap());
+    if marker::recorded_engine(dir)?.as_deref() == Some("sled") {
+        return run_migrate_sled(dir, matches.value_of("to").map(Path::new));
+    }
+    check_kvs(dir)?;
+    match matches.value_of("to") {
+        Some(dest) => {
+            let dest = Path::new(dest);
+            let version = migrate::migrate_to(dir, dest)?;
+            marker::record_engine(dest, "kvs")?;
+            println!(
+                "copied {} (format version {}) to {} (format version {})",
+                dir.display(),
+                version,
+                dest.display(),
+                FORMAT_VERSION
+            );
+        }
+        None => {
+            let version = migrate::migrate(dir)?;
+            if version == FORMAT_VERSION {
+                println!("{} is already at format version {}", dir.display(), version);
+            } else {
+                println!(
+                    "upgraded {} from format version {} to {}",
+                    dir.display(),
+                    version,
+                    FORMAT_VERSION
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// sled stores can only be upgraded into a new directory, by copying every
+/// key across.
+fn run_migrate_sled(dir: &Path, dest: Option<&Path>) -> Result<()> {
+    let version = sled::old_format(dir)?;
+    match (version, dest) {
+        (None, _) => println!("{} is already in the current sled format", dir.display()),
+        (Some(_), None) => {
+            return Err(KvError::InvalidOption(
+                "a sled store is upgraded into a new directory, give one with --to".to_owned(),
+            ))
+        }
+        (Some(version), Some(dest)) => {
+            let keys = migrate::migrate_sled(dir, dest)?;
+            println!(
+                "copied {} keys from {} (sled {}) to {} (sled 0.34)",
+                keys,
+                dir.display(),
+                version,
+                dest.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn run_restore(matches: &ArgMatches) -> Result<()> {
+    let source = Path::new(matches.value_of("SOURCE").unwrap());
+    let dir = Path::new(matches.value_of("DIR").unwrap());
+    if let Some(point) = matches.value_of("until") {
+        let restored = archive::restore(source, dir, point.parse::<RestorePoint>()?)?;
+        println!(
+            "restored {} keys to {} as of write {}",
+            restored.keys,
+            dir.display(),
+            restored.seq
+        );
+        return Ok(());
+    }
+    let restored = backup::restore(source, dir)?;
+    println!(
+        "restored {} keys ({} engine) to {}",
+        restored.keys,
+        restored.engine,
+        dir.display()
+    );
+    Ok(())
+}
+
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .long("format")
+        .value_name("FORMAT")
+        .takes_value(true)
+        .possible_values(&["jsonl", "csv"])
+        .default_value("jsonl")
+}
+
+fn after_args() -> [Arg<'static, 'static>; 2] {
+    [
+        Arg::with_name("after")
+            .long("after")
+            .value_name("KEY")
+            .takes_value(true)
+            .help("Resume after KEY, the last key reported"),
+        Arg::with_name("after-base64")
+            .long("after-base64")
+            .value_name("KEY")
+            .takes_value(true)
+            .conflicts_with("after")
+            .help("Resume after a key given in base64"),
+    ]
+}
+
+fn after_key(matches: &ArgMatches) -> Result<Option<Vec<u8>>> {
+    if let Some(key) = matches.value_of("after-base64") {
+        let key = base64::decode(key)
+            .map_err(|e| KvError::InvalidOption(format!("--after-base64: {}", e)))?;
+        return Ok(Some(key));
+    }
+    Ok(matches.value_of("after").map(|key| key.as_bytes().to_vec()))
+}
+
+/// Reports how far a dump or load got, and how to pick up from there.
+fn report(done: &str, count: u64, key: &[u8]) {
+    match std::str::from_utf8(key) {
+        Ok(key) => info!("{} {} keys, resume with --after {:?}", done, count, key),
+        Err(_) => info!(
+            "{} {} keys, resume with --after-base64 {}",
+            done,
+            count,
+            base64::encode(key)
+        ),
+    }
+}
+
+fn run_dump(matches: &ArgMatches) -> Result<()> {
+    let dir = Path::new(matches.value_of("DIR").unwrap());
+    let format = matches.value_of("format").unwrap().parse::<DumpFormat>()?;
+    let mut after = after_key(matches)?;
+    let engine = marker::recorded_engine(dir)?
+        .ok_or_else(|| KvError::StoreNotFound(dir.display().to_string()))?;
+    let out: Box<dyn Write> = match matches.value_of("output") {
+        // A resumed dump carries on where the interrupted one stopped, which
+        // may be past the last key it reported.
+        Some(path) if after.is_some() => {
+            let file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path)?;
+            if let Some(last) = dump::truncate_to_last_record(&file, format)? {
+                after = after.max(Some(last));
+            }
+            Box::new(file)
+        }
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let out = BufWriter::new(out);
+    let count = match engine.as_str() {
+        "sled" => {
+            let options = SledOptions::new().read_only(true);
+            dump_engine(SledKvsEngine::open_with(dir, options)?, out, format, after)
+        }
+        _ => {
+            let options = KvStoreOptions::new().read_only(true).upgrade(true);
+            dump_engine(KvStore::open_with(dir, options)?, out, format, after)
+        }
+    }?;
+    info!("dumped {} keys from {}", count, dir.display());
+    Ok(())
+}
+
+fn dump_engine<E: KvsEngine>(
+    engine: E,
+    out: impl Write,
+    format: DumpFormat,
+    after: Option<Vec<u8>>,
+) -> Result<u64> {
+    dump::dump(&engine, out, format, after.as_deref(), |count, key| {
+        report("dumped", count, key)
+    })
+}
+
+fn run_load(matches: &ArgMatches) -> Result<()> {
+    let dir = Path::new(matches.value_of("DIR").unwrap());
+    let format = matches.value_of("format").unwrap().parse::<DumpFormat>()?;
+    let after = after_key(matches)?;
+    let recorded = marker::recorded_engine(dir)?;
+    let engine = match (recorded.clone(), matches.value_of("engine")) {
+        (Some(recorded), Some(requested)) if recorded != requested => {
+            return Err(KvError::EngineMismatch {
+                recorded,
+                requested: requested.to_owned(),
+            });
+        }
+        (Some(recorded), _) => recorded,
+        (None, requested) => requested.unwrap_or("kvs").to_owned(),
+    };
+    let input: Box<dyn Read> = match matches.value_of("FILE") {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin()),
+    };
+    let input = BufReader::new(input);
+    let record = || match recorded {
+        Some(_) => Ok(()),
+        None => marker::record_engine(dir, &engine),
+    };
+    let count = match engine.as_str() {
+        "sled" => {
+            let store = SledKvsEngine::open(dir)?;
+            record()?;
+            load_engine(store, input, format, after)
+        }
+        _ => {
+            let store = KvStore::open(dir)?;
+            record()?;
+            load_engine(store, input, format, after)
+        }
+    }?;
+    info!("loaded {} keys into {}", count, dir.display());
+    Ok(())
+}
+
+fn load_engine<E: KvsEngine>(
+    engine: E,
+    input: impl Read,
+    format: DumpFormat,
+    after: Option<Vec<u8>>,
+) -> Result<u64> {
+    dump::load(&engine, input, format, after.as_deref(), |count, key| {
+        report("loaded", count, key)
+    })
+}
+
+fn run_convert(matches: &ArgMatches) -> Result<()> {
+    let dir = Path::new(matches.value_of("DIR").unwrap());
+    let dest = Path::new(matches.value_of("DEST").unwrap());
+    let engine = matches.value_of("engine").unwrap();
+    let converted = convert::convert(dir, dest, engine, |count| info!("copied {} keys", count))?;
+    println!(
+        "copied {} to {} ({} engine): {}",
+        dir.display(),
+        dest.display(),
+        engine,
+        converted.checksum
+    );
+    if matches.is_present("cut-over") {
+        convert::cut_over(dir, dest)?;
+        println!(
+            "{} is now the {} store, the original is in {}",
+            dir.display(),
+            engine,
+            dest.display()
+        );
+    }
+    Ok(())
+}
+
+fn run_checksum(matches: &ArgMatches) -> Result<()> {
+    let dir = Path::new(matches.value_of("DIR").unwrap());
+    let engine = marker::recorded_engine(dir)?
+        .ok_or_else(|| KvError::StoreNotFound(dir.display().to_string()))?;
+    let checksum = match engine.as_str() {
+        "sled" => {
+            let options = SledOptions::new().read_only(true);
+            convert::checksum(&SledKvsEngine::open_with(dir, options)?)
+        }
+        _ => {
+            let options = KvStoreOptions::new().read_only(true).upgrade(true);
+            convert::checksum(&KvStore::open_with(dir, options)?)
+        }
+    }?;
+    println!("{}", checksum);
+    Ok(())
+}
+
+fn run_verify(matches: &ArgMatches) -> Result<()> {
+    let dir = Path::new(matches.value_of("DIR").unwrap());
+    check_kvs(dir)?;
+    let verified = inspect::verify(dir)?;
+    for damage in &verified.damage {
+        println!("{}", describe(damage));
+    }
+    for problem in &verified.problems {
+        println!("{}", problem);
+    }
+    println!(
+        "{} records in {} segments, {} damaged ranges",
+        verified.records,
+        verified.segments,
+        verified.damage.len()
+    );
+    if !verified.is_ok() {
+        exit(1);
+    }
+    Ok(())
+}
+
+fn run_inspect(matches: &ArgMatches) -> Result<()> {
+    let dir = Path::new(matches.value_of("DIR").unwrap());
+    check_kvs(dir)?;
+    let segments = match matches.value_of("segment") {
+        Some(id) => vec![id
+            .parse()
+            .map_err(|_| KvError::InvalidOption(format!("unknown segment: {}", id)))?],
+        None => inspect::layout(dir)?.segments,
+    };
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    for id in segments {
+        writeln!(out, "segment {}", id)?;
+        let mut written = Ok(());
+        let damage = inspect::inspect(dir, id, |record| {
+            if written.is_err() {
+                return;
+            }
+            let mut line = format!("{:>12} {:>8} {:<6}", record.offset, record.len, record.kind);
+            if record.in_batch {
+                line.insert_str(0, "  ");
+            }
+            if record.kind != "batch" {
+                line.push_str(&format!(
+                    " {} value {}",
+                    show_key(&record.key),
+                    record.value_len
+                ));
+            }
+            if let Some(deadline) = record.expires_at {
+                line.push_str(&format!(" expires {}", deadline));
+            }
+            written = writeln!(out, "{}", line);
+        })?;
+        written?;
+        for damage in &damage {
+            writeln!(out, "{}", describe(damage))?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn run_stats(matches: &ArgMatches) -> Result<()> {
+    let dir = Path::new(matches.value_of("DIR").unwrap());
+    check_kvs(dir)?;
+    let options = KvStoreOptions::new().read_only(true).upgrade(true);
+    let stats = KvStore::open_with(dir, options)?.stats();
+    println!(
+        "{:>10} {:>14} {:>14} {:>6}",
+        "segment", "live", "dead", "dead%"
+    );
+    for segment in &stats.segments {
+        println!(
+            "{:>10} {:>14} {:>14} {:>6.1}",
+            segment.id,
+            segment.live_bytes,
+            segment.dead_bytes,
+            segment.dead_ratio() * 100.0
+        );
+    }
+    println!(
+        "{:>10} {:>14} {:>14} {:>6.1}",
+        "total",
+        stats.live_bytes(),
+        stats.dead_bytes(),
+        stats.dead_ratio() * 100.0
+    );
+    println!("{} keys", stats.keys);
+    Ok(())
+}
+
+fn run_repair(matches: &ArgMatches) -> Result<()> {
+    let dir = Path::new(matches.value_of("DIR").unwrap());
+    let dest = Path::new(matches.value_of("DEST").unwrap());
+    check_kvs(dir)?;
+    let repaired = inspect::repair(dir, dest)?;
+    for damage in &repaired.damage {
+        println!("lost {}", describe(damage));
+    }
+    println!(
+        "salvaged {} records, {} keys, to {}",
+        repaired.records,
+        repaired.keys,
+        dest.display()
+    );
+    Ok(())
+}
+
+fn describe(damage: &Damage) -> String {
+    format!(
+        "damaged: log_{} bytes {}..{}",
+        damage.segment, damage.start, damage.end
+    )
+}
+
+/// A key as UTF-8 in quotes, or in hex if it is not UTF-8.
+fn show_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) => format!("{:?}", key),
+        Err(_) => format!("0x{}", hex::encode(key)),
+    }
+}
+
+/// Fails unless `dir` holds a kvs store, the only engine with a format of
+/// its own.
+fn check_kvs(dir: &Path) -> Result<()> {
+    match marker::recorded_engine(dir)? {
+        Some(ref engine) if engine == "kvs" => Ok(()),
+        Some(engine) => Err(KvError::EngineMismatch {
+            recorded: engine,
+            requested: "kvs".to_owned(),
+        }),
+        None => Err(KvError::StoreNotFound(dir.display().to_string())),
+    }
+}
@@ -1,16 +1,22 @@
 extern crate clap;
 extern crate log;
 
-use clap::{App, AppSettings, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use kvs::client::KvsClient;
 use kvs::common::{Action, Command, Response};
-use kvs::Result;
+use kvs::engine::scan::prefix_range;
+use kvs::{KvError, Result};
 use log::LevelFilter;
 use std::net::SocketAddr;
+use std::ops::Bound;
 use std::process::exit;
 use std::str;
+use std::time::Duration;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+const SCAN_PAGE: usize = 100;
+/// Exit code of a conditional write that lost to a concurrent one.
+const CONFLICT_EXIT_CODE: i32 = 2;
 
 fn main() -> Result<()> {
     env_logger::builder().filter_level(LevelFilter::Info).init();
@@ -27,6 +33,14 @@ fn main() -> Result<()> {
                 Err(String::from("the ip address format is error: IP:PORT"))
             }
         });
+    let encoding_arg = Arg::with_name("encoding")
+        .value_name("ENCODING")
+        .takes_value(true)
+        .short("e")
+        .long("encoding")
+        .possible_values(&["utf8", "hex", "base64"])
+        .default_value("utf8")
+        .help("How keys and values are written on the command line");
 
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -40,56 +54,321 @@ fn main() -> Result<()> {
                 .about("Set the value of a string key to a string")
                 .arg(Arg::with_name("KEY").required(true))
                 .arg(Arg::with_name("VALUE").required(true))
-                .arg(&addr_arg),
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .value_name("TTL")
+                        .takes_value(true)
+                        .validator(validate_ttl)
+                        .help("Expire the key after TTL: seconds, or a number ending in ms, s, m or h"),
+                )
+                .arg(&addr_arg)
+                .arg(&encoding_arg),
         )
         .subcommand(
             SubCommand::with_name("get")
                 .about("Get the string value of a given string key")
                 .arg(Arg::with_name("KEY").required(true))
-                .arg(&addr_arg),
+                .arg(&addr_arg)
+                .arg(&encoding_arg),
         )
         .subcommand(
             SubCommand::with_name("rm")
                 .about("Remove a given key")
                 .arg(Arg::with_name("KEY").required(true))
-                .arg(&addr_arg),
+                .arg(&addr_arg)
+                .arg(&encoding_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("ttl")
+                .about("Print the time left before a key expires")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(&addr_arg)
+                .arg(&encoding_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("expire")
+                .about("Expire an existing key after TTL")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(Arg::with_name("TTL").required(true).validator(validate_ttl))
+                .arg(&addr_arg)
+                .arg(&encoding_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("persist")
+                .about("Remove the expiry of a key")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(&addr_arg)
+                .arg(&encoding_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .about("Replace the value of a key only if it is the expected one")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(
+                    Arg::with_name("expected")
+                        .long("expected")
+                        .takes_value(true)
+                        .help("The current value; the key must be absent if omitted"),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .long("new")
+                        .takes_value(true)
+                        .help("The value to set; the key is removed if omitted"),
+                )
+                .arg(&addr_arg)
+                .arg(&encoding_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("set-if-absent")
+                .about("Set the value of a key that does not exist yet")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(Arg::with_name("VALUE").required(true))
+                .arg(&addr_arg)
+                .arg(&encoding_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("rm-if-equals")
+                .about("Remove a key only if it has the given value")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(Arg::with_name("VALUE").required(true))
+                .arg(&addr_arg)
+                .arg(&encoding_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("checkpoint")
+                .about("Have the server write a copy of its store to DEST")
+                .arg(
+                    Arg::with_name("DEST")
+                        .required(true)
+                        .help("An empty or missing directory, relative to the server's backup directory"),
+                )
+                .arg(&addr_arg)
+                .arg(&encoding_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("List keys and values in key order, one tab-separated pair per line")
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .takes_value(true)
+                        .conflicts_with_all(&["start", "end"]),
+                )
+                .arg(Arg::with_name("start").long("start").takes_value(true))
+                .arg(Arg::with_name("end").long("end").takes_value(true))
+                .arg(
+                    Arg::with_name("cursor")
+                        .long("cursor")
+                        .takes_value(true)
+                        .help("Resume a scan where a limited one stopped"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .validator(|n| match n.parse::<usize>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err(String::from("the limit must be a positive integer")),
+                        }),
+                )
+                .arg(&addr_arg)
+                .arg(&encoding_arg),
         )
         .get_matches();
 
-    let mut command = None;
-    let mut addr = None;
-    if let Some(_matches) = matches.subcommand_matches("get") {
-        let key = _matches.value_of("KEY").unwrap();
-        addr = _matches.value_of("addr");
-        command = Some(Command::new(Action::GET, key.to_owned(), "".to_owned()));
-    } else if let Some(_matches) = matches.subcommand_matches("set") {
-        let key = _matches.value_of("KEY").unwrap();
-        let value = _matches.value_of("VALUE").unwrap();
-        addr = _matches.value_of("addr");
-        command = Some(Command::new(Action::SET, key.to_owned(), value.to_owned()));
-    } else if let Some(_matches) = matches.subcommand_matches("rm") {
-        let key = _matches.value_of("KEY").unwrap();
-        command = Some(Command::new(Action::RM, key.to_owned(), "".to_owned()));
-        addr = _matches.value_of("addr");
+    let (name, _matches) = matches.subcommand();
+    let _matches = _matches.unwrap();
+    let addr = _matches
+        .value_of("addr")
+        .unwrap_or(DEFAULT_LISTENING_ADDRESS);
+    let encoding = _matches.value_of("encoding").unwrap();
+    if name == "scan" {
+        return scan(_matches, addr, encoding);
+    }
+    if name == "checkpoint" {
+        let dest = _matches.value_of("DEST").unwrap().to_owned();
+        let command = Command::new(Action::CHECKPOINT { dest }, Vec::new(), Vec::new());
+        if let Response::Err(err) = KvsClient::new().send_command(&command, addr)? {
+            eprintln!("{}", err);
+            exit(1)
+        }
+        return Ok(());
     }
+    let key = decode_arg(encoding, _matches.value_of("KEY").unwrap());
+    let value = _matches
+        .value_of("VALUE")
+        .map_or_else(Vec::new, |value| decode_arg(encoding, value));
+    let command = match name {
+        "get" => Command::new(Action::GET, key, Vec::new()),
+        "set" => match _matches.value_of("ttl") {
+            Some(ttl) => Command::new(
+                Action::SETEX {
+                    ttl_ms: ttl_millis(ttl),
+                },
+                key,
+                value,
+            ),
+            None => Command::new(Action::SET, key, value),
+        },
+        "ttl" => Command::new(Action::TTL, key, Vec::new()),
+        "expire" => {
+            let ttl_ms = ttl_millis(_matches.value_of("TTL").unwrap());
+            Command::new(Action::EXPIRE { ttl_ms }, key, Vec::new())
+        }
+        "persist" => Command::new(Action::PERSIST, key, Vec::new()),
+        "cas" => {
+            let expected = _matches
+                .value_of("expected")
+                .map(|value| decode_arg(encoding, value));
+            let new = _matches
+                .value_of("new")
+                .map(|value| decode_arg(encoding, value));
+            Command::new(Action::CAS { expected, new }, key, Vec::new())
+        }
+        "set-if-absent" => Command::new(Action::SETNX, key, value),
+        "rm-if-equals" => Command::new(Action::RMEQ, key, value),
+        _ => Command::new(Action::RM, key, Vec::new()),
+    };
 
     let client = KvsClient::new();
-    let res = client.send_command(&command, addr.unwrap_or(DEFAULT_LISTENING_ADDRESS));
+    let res = client.send_command(&command, addr)?;
 
     match res {
-        Response::Err(err) => match command.unwrap().action {
+        Response::Err(err) => match command.action {
             Action::RM => {
                 eprintln!("{}", err);
                 exit(-1)
             }
-            _ => {
+            Action::GET | Action::SET | Action::TTL => {
                 println!("{}", err);
                 exit(0)
             }
+            _ => {
+                eprintln!("{}", err);
+                exit(1)
+            }
+        },
+        Response::Conflict => {
+            eprintln!("conflict: the key does not hold the expected value");
+            exit(CONFLICT_EXIT_CODE)
+        }
+        Response::Ok(Some(val)) => match encode(encoding, val) {
+            Ok(val) => println!("{}", val),
+            Err(_) => {
+                eprintln!("value is not valid UTF-8, retry with --encoding hex or base64");
+                exit(1)
+            }
         },
-        Response::Ok(Some(val)) => println!("{}", val),
-        Response::Ok(None) => {}
+        Response::Ttl(Some(ttl_ms)) => println!("{}ms", ttl_ms),
+        Response::Ttl(None) => println!("no expiry"),
+        Response::Ok(None) | Response::Scan { .. } => {}
     };
 
     Ok(())
 }
+
+fn scan(matches: &ArgMatches, addr: &str, encoding: &str) -> Result<()> {
+    let (mut start, end) = match matches.value_of("prefix") {
+        Some(prefix) => match prefix_range(&decode_arg(encoding, prefix)) {
+            (Bound::Included(start), Bound::Excluded(end)) => (start, Some(end)),
+            (Bound::Included(start), _) => (start, None),
+            _ => unreachable!(),
+        },
+        None => (
+            matches
+                .value_of("start")
+                .map_or_else(Vec::new, |start| decode_arg(encoding, start)),
+            matches.value_of("end").map(|end| decode_arg(encoding, end)),
+        ),
+    };
+    if let Some(cursor) = matches.value_of("cursor") {
+        start = decode_arg(encoding, cursor);
+    }
+    let mut remaining = matches
+        .value_of("limit")
+        .map_or(usize::MAX, |n| n.parse().unwrap());
+
+    let client = KvsClient::new();
+    while remaining > 0 {
+        let limit = remaining.min(SCAN_PAGE);
+        let action = Action::SCAN {
+            end: end.clone(),
+            limit: limit as u32,
+        };
+        let (pairs, cursor) =
+            match client.send_command(&Command::new(action, start, Vec::new()), addr)? {
+                Response::Scan { pairs, cursor } => (pairs, cursor),
+                Response::Err(err) => {
+                    eprintln!("{}", err);
+                    exit(1)
+                }
+                Response::Ok(_) | Response::Conflict | Response::Ttl(_) => unreachable!(),
+            };
+        remaining -= pairs.len();
+        for (key, value) in pairs {
+            match (encode(encoding, key), encode(encoding, value)) {
+                (Ok(key), Ok(value)) => println!("{}\t{}", key, value),
+                _ => {
+                    eprintln!("pair is not valid UTF-8, retry with --encoding hex or base64");
+                    exit(1)
+                }
+            }
+        }
+        match cursor {
+            Some(cursor) if remaining == 0 => {
+                eprintln!("cursor: {}", encode(encoding, cursor)?);
+                break;
+            }
+            Some(cursor) => start = cursor,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// Parses a time to live: a number of seconds, or a number followed by `ms`,
+/// `s`, `m` or `h`.
+fn parse_ttl(ttl: &str) -> Option<Duration> {
+    let split = ttl.find(|c: char| !c.is_ascii_digit()).unwrap_or(ttl.len());
+    let (n, unit) = ttl.split_at(split);
+    let n: u64 = n.parse().ok().filter(|&n| n > 0)?;
+    match unit {
+        "ms" => Some(Duration::from_millis(n)),
+        "" | "s" => Some(Duration::from_secs(n)),
+        "m" => n.checked_mul(60).map(Duration::from_secs),
+        "h" => n.checked_mul(3600).map(Duration::from_secs),
+        _ => None,
+    }
+}
+
+fn validate_ttl(ttl: String) -> std::result::Result<(), String> {
+    parse_ttl(&ttl).map(|_| ()).ok_or_else(|| {
+        String::from("the ttl must be a positive number, optionally ending in ms, s, m or h")
+    })
+}
+
+fn ttl_millis(ttl: &str) -> u64 {
+    parse_ttl(ttl).unwrap().as_millis() as u64
+}
+
+fn decode_arg(encoding: &str, arg: &str) -> Vec<u8> {
+    let decoded = match encoding {
+        "hex" => hex::decode(arg).map_err(|e| e.to_string()),
+        "base64" => base64::decode(arg).map_err(|e| e.to_string()),
+        _ => Ok(arg.as_bytes().to_vec()),
+    };
+    decoded.unwrap_or_else(|e| {
+        eprintln!("invalid {} argument {:?}: {}", encoding, arg, e);
+        exit(1)
+    })
+}
+
+fn encode(encoding: &str, bytes: Vec<u8>) -> Result<String> {
+    match encoding {
+        "hex" => Ok(hex::encode(bytes)),
+        "base64" => Ok(base64::encode(bytes)),
+        _ => String::from_utf8(bytes).map_err(KvError::from),
+    }
+}
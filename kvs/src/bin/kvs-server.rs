@@ -2,19 +2,35 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 use clap::{App, Arg};
-use kvs::engine::kv::KvStore;
-use kvs::engine::sled::SledKvsEngine;
+use kvs::config::ServerConfig;
+use kvs::engine::convert::DualWrite;
+use kvs::engine::kv::{KvStore, KvStoreOptions};
+use kvs::engine::marker::{self, ENGINES};
+use kvs::engine::sled::{SledKvsEngine, SledOptions};
 use kvs::engine::KvsEngine;
-use kvs::server::KvsServer;
-use kvs::Result;
+use kvs::server::{KvsServer, DEFAULT_IDLE_TIMEOUT};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{Durability, KvError, Result};
 use log::LevelFilter;
 use std::env::current_dir;
+use std::path::{Path, PathBuf};
+use std::process::exit;
 use std::str;
+use std::thread;
+use std::time::Duration;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+const DEFAULT_ENGINE: &str = "kvs";
 
-fn main() -> Result<()> {
+fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
+    if let Err(e) = serve() {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+fn serve() -> Result<()> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -24,44 +40,252 @@ fn main() -> Result<()> {
                 .value_name("IP_PORT")
                 .takes_value(true)
                 .short("a")
-                .long("addr")
-                .default_value(DEFAULT_LISTENING_ADDRESS),
+                .long("addr"),
         )
         .arg(
             Arg::with_name("engine")
                 .value_name("ENGINE-NAME")
                 .takes_value(true)
                 .short("e")
-                .long("engine"),
+                .long("engine")
+                .possible_values(&ENGINES)
+                .help("defaults to the engine that created the directory, or kvs"),
+        )
+        .arg(
+            Arg::with_name("pool")
+                .value_name("POOL-NAME")
+                .takes_value(true)
+                .short("p")
+                .long("pool")
+                .possible_values(&["naive", "shared", "rayon"]),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .value_name("N")
+                .takes_value(true)
+                .short("t")
+                .long("threads")
+                .validator(|n| match n.parse::<u32>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(String::from(
+                        "the number of threads must be a positive integer",
+                    )),
+                }),
+        )
+        .arg(
+            Arg::with_name("durability")
+                .value_name("MODE")
+                .takes_value(true)
+                .short("d")
+                .long("durability")
+                .help("none, flush, fsync, fsync-every:<millis> or group-commit")
+                .validator(|mode| {
+                    mode.parse::<Durability>()
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                }),
+        )
+        .arg(
+            Arg::with_name("config")
+                .value_name("FILE")
+                .takes_value(true)
+                .short("c")
+                .long("config")
+                .help("TOML file with server and storage settings"),
+        )
+        .arg(
+            Arg::with_name("dual-write")
+                .value_name("DIR")
+                .takes_value(true)
+                .long("dual-write")
+                .help("Copy the store to the other engine in DIR and mirror every write there"),
+        )
+        .arg(
+            Arg::with_name("backup-dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .long("backup-dir")
+                .help("Where CHECKPOINT requests write; without it they are refused"),
         )
         .get_matches();
-    let mut address = "";
-    let mut engine = "";
-    if let Some(addr) = matches.value_of("addr") {
-        address = addr;
-    }
-
-    if let Some(_engine) = matches.value_of("engine") {
-        engine = _engine;
+    let config = match matches.value_of("config") {
+        Some(path) => ServerConfig::load(Path::new(path))?,
+        None => ServerConfig::default(),
+    };
+    let address = matches
+        .value_of("addr")
+        .or(config.addr.as_deref())
+        .unwrap_or(DEFAULT_LISTENING_ADDRESS);
+    // The directory remembers its engine, which a request has to match.
+    let dir = current_dir()?;
+    let recorded = marker::recorded_engine(&dir)?;
+    let engine = match (
+        matches.value_of("engine").or(config.engine.as_deref()),
+        recorded.as_deref(),
+    ) {
+        (Some(requested), Some(recorded)) if requested != recorded => {
+            return Err(KvError::EngineMismatch {
+                recorded: recorded.to_owned(),
+                requested: requested.to_owned(),
+            })
+        }
+        (Some(engine), _) | (None, Some(engine)) => engine,
+        (None, None) => DEFAULT_ENGINE,
+    };
+    let pool = matches
+        .value_of("pool")
+        .or(config.pool.as_deref())
+        .unwrap_or("shared");
+    if !["naive", "shared", "rayon"].contains(&pool) {
+        return Err(KvError::InvalidOption(format!("unknown pool: {}", pool)));
     }
+    let threads = match matches.value_of("threads") {
+        Some(n) => n.parse::<u32>().unwrap(),
+        None => config
+            .threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get() as u32)),
+    };
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
+    info!("Thread pool: {} ({} threads)", pool, threads);
     info!("Listening on {}", address);
-    if engine == "sled" {
-        run_with_engine(
-            address.to_owned(),
-            SledKvsEngine::open(current_dir().unwrap().as_path()).unwrap(),
-        )?
-    } else if engine == "kvs" {
-        run_with_engine(
-            address.to_owned(),
-            KvStore::open(current_dir().unwrap().as_path()).unwrap(),
-        )?
+    let durability = match matches.value_of("durability") {
+        Some(mode) => Some(mode.parse::<Durability>()?),
+        None => config.durability()?,
+    };
+    let idle_timeout = config.idle_timeout()?.unwrap_or(DEFAULT_IDLE_TIMEOUT);
+    let mut kvs_options = config.kvs.apply(KvStoreOptions::new())?;
+    // A kvs store mirrored to is tuned like a served one, but always written
+    // to, never archived, and expected to exist once dual writes are on.
+    let mut mirror_options = config
+        .kvs
+        .apply_tuning(KvStoreOptions::new())?
+        .read_only(false)
+        .error_if_exists(false);
+    if let Some(durability) = durability {
+        kvs_options = kvs_options.durability(durability);
+        mirror_options = mirror_options.durability(durability);
     }
-    Ok(())
+    let dual_write = matches
+        .value_of("dual-write")
+        .map(PathBuf::from)
+        .or(config.dual_write);
+    let backup_dir = matches
+        .value_of("backup-dir")
+        .map(PathBuf::from)
+        .or(config.backup_dir);
+    let serve = Serve {
+        address: address.to_owned(),
+        pool,
+        threads,
+        idle_timeout,
+        durability,
+        mirror_options,
+        dual_write,
+        backup_dir,
+    };
+    match engine {
+        "sled" => {
+            let mut options = SledOptions::new();
+            if let Some(durability) = durability {
+                options = options.durability(durability);
+            }
+            let engine = SledKvsEngine::open_with(dir.as_path(), options)?;
+            if recorded.is_none() {
+                marker::record_engine(&dir, "sled")?;
+            }
+            serve.run(engine, "kvs")
+        }
+        "kvs" => {
+            let engine = KvStore::open_with(dir.as_path(), kvs_options)?;
+            if recorded.is_none() {
+                marker::record_engine(&dir, "kvs")?;
+            }
+            serve.run(engine, "sled")
+        }
+        _ => Err(KvError::InvalidOption(format!(
+            "unknown engine: {}",
+            engine
+        ))),
+    }
+}
+
+/// How to serve an opened engine.
+struct Serve<'a> {
+    address: String,
+    pool: &'a str,
+    threads: u32,
+    idle_timeout: Duration,
+    durability: Option<Durability>,
+    mirror_options: KvStoreOptions,
+    dual_write: Option<PathBuf>,
+    backup_dir: Option<PathBuf>,
 }
 
-fn run_with_engine<T: KvsEngine>(address: String, engine: T) -> Result<()> {
-    let mut server = KvsServer::new(address.to_owned(), engine);
-    server.run()
+impl Serve<'_> {
+    /// Serves `engine`, mirrored to a store of `other` if dual writes are on.
+    fn run<T: KvsEngine>(self, engine: T, other: &str) -> Result<()> {
+        let dir = match self.dual_write {
+            Some(ref dir) => dir.clone(),
+            None => return self.run_with_pool(engine),
+        };
+        let recorded = marker::recorded_engine(&dir)?;
+        if let Some(recorded) = recorded.clone().filter(|recorded| recorded != other) {
+            return Err(KvError::EngineMismatch {
+                recorded,
+                requested: other.to_owned(),
+            });
+        }
+        info!("Dual writes: {} engine in {}", other, dir.display());
+        match other {
+            "sled" => {
+                let mut options = SledOptions::new();
+                if let Some(durability) = self.durability {
+                    options = options.durability(durability);
+                }
+                let secondary = SledKvsEngine::open_with(&dir, options)?;
+                if recorded.is_none() {
+                    marker::record_engine(&dir, "sled")?;
+                }
+                self.run_dual(DualWrite::new(engine, secondary))
+            }
+            _ => {
+                let secondary = KvStore::open_with(&dir, self.mirror_options.clone())?;
+                if recorded.is_none() {
+                    marker::record_engine(&dir, "kvs")?;
+                }
+                self.run_dual(DualWrite::new(engine, secondary))
+            }
+        }
+    }
+
+    /// Copies the existing keys to the secondary in the background while
+    /// serving.
+    fn run_dual<P: KvsEngine, S: KvsEngine>(self, engine: DualWrite<P, S>) -> Result<()> {
+        let backfill = engine.clone();
+        thread::spawn(move || {
+            match backfill.backfill(|count| info!("Dual writes: copied {} keys", count)) {
+                Ok(count) => info!("Dual writes: caught up after copying {} keys", count),
+                Err(e) => error!("Dual writes: copy failed: {}", e),
+            }
+        });
+        self.run_with_pool(engine)
+    }
+
+    fn run_with_pool<T: KvsEngine>(&self, engine: T) -> Result<()> {
+        match self.pool {
+            "naive" => self.run_with_engine(engine, NaiveThreadPool::new(self.threads)?),
+            "rayon" => self.run_with_engine(engine, RayonThreadPool::new(self.threads)?),
+            _ => self.run_with_engine(engine, SharedQueueThreadPool::new(self.threads)?),
+        }
+    }
+
+    fn run_with_engine<T: KvsEngine, P: ThreadPool>(&self, engine: T, pool: P) -> Result<()> {
+        let mut server =
+            KvsServer::new(self.address.clone(), engine, pool).idle_timeout(self.idle_timeout);
+        if let Some(ref dir) = self.backup_dir {
+            server = server.backup_dir(dir);
+        }
+        server.run()
+    }
 }
@@ -1,9 +1,9 @@
-use crate::common::{Command, Response};
-use byteorder::{ReadBytesExt, WriteBytesExt, LE};
-use std::io::{BufReader, BufWriter, Read, Write};
+use crate::common::{self, Command, Response};
+use crate::error::Result;
+use std::io::{BufReader, BufWriter};
 use std::net::TcpStream;
-use std::str;
 
+#[derive(Default)]
 pub struct KvsClient {}
 
 impl KvsClient {
@@ -11,21 +11,29 @@ impl KvsClient {
         KvsClient {}
     }
 
-    pub fn send_command(&self, command: &std::option::Option<Command>, addr: &str) -> Response {
-        //let stream = TcpStream::connect(addr.unwrap_or(DEFAULT_LISTENING_ADDRESS))
-        let stream = TcpStream::connect(addr).expect("connection failed");
-        let mut writer = BufWriter::new(&stream);
-        let mut reader = BufReader::new(&stream);
-        let serialized = serde_json::to_string(&command).unwrap();
-        writer.write_u32::<LE>(serialized.len() as u32).unwrap();
-        writer.flush().unwrap();
-        writer.write(serialized.as_bytes()).unwrap();
-        writer.flush().unwrap();
+    pub fn send_command(&self, command: &Command, addr: &str) -> Result<Response> {
+        self.connect(addr)?.send(command)
+    }
+
+    /// Opens a connection for several commands, such as a `MULTI`/`EXEC`
+    /// transaction.
+    pub fn connect(&self, addr: &str) -> Result<Connection> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+}
+
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
 
-        let res_len = reader.read_u32::<LE>().unwrap();
-        let mut buf = vec![0; res_len as usize];
-        reader.read_exact(&mut buf).unwrap();
-        let response: Response = serde_json::from_str(str::from_utf8(&buf).unwrap()).unwrap();
-        response
+impl Connection {
+    pub fn send(&mut self, command: &Command) -> Result<Response> {
+        common::write_message(&mut self.writer, command)?;
+        common::read_message(&mut self.reader)
     }
 }
@@ -1,27 +1,94 @@
+//! Messages exchanged by `KvsClient` and `KvsServer`.
+//!
+//! Each message is a little-endian `u32` length followed by that many bytes
+//! of bincode, so keys and values travel as raw bytes.
+//!
+//! A connection carries any number of commands, each answered before the
+//! next is read. Between `MULTI` and `EXEC` they form a transaction: `GET`
+//! reads through it, `SET` and `RM` are buffered, and `EXEC` commits them or
+//! answers `Response::Conflict` if a key read has changed since.
+
+use crate::engine::batch::WriteBatch;
+use crate::error::{KvError, Result};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Action {
     GET,
     SET,
     RM,
+    /// Up to `limit` pairs from the command's key (inclusive) to `end`
+    /// (exclusive, or the last key if `None`).
+    SCAN {
+        end: Option<Vec<u8>>,
+        limit: u32,
+    },
+    /// Applies the batch all-or-nothing. The command's key and value are
+    /// unused.
+    BATCH(WriteBatch),
+    /// `KvsEngine::compare_and_swap` on the command's key.
+    CAS {
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    /// Sets the key to the command's value unless the key exists.
+    SETNX,
+    /// Removes the key if its value is the command's value.
+    RMEQ,
+    /// Sets the key to the command's value, expiring in `ttl_ms`
+    /// milliseconds.
+    SETEX {
+        ttl_ms: u64,
+    },
+    /// Expires an existing key in `ttl_ms` milliseconds.
+    EXPIRE {
+        ttl_ms: u64,
+    },
+    /// Removes the deadline of an existing key.
+    PERSIST,
+    /// Answered with `Response::Ttl`.
+    TTL,
+    /// `KvsEngine::checkpoint` into `dest`, a relative path under the
+    /// server's backup directory. The command's key and value are unused.
+    CHECKPOINT {
+        dest: String,
+    },
+    /// Starts a transaction on this connection.
+    MULTI,
+    /// Commits the connection's transaction.
+    EXEC,
+    /// Drops the connection's transaction.
+    DISCARD,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Command {
     pub action: Action,
-    pub key: String,
-    pub value: String,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    Ok(Option<String>),
+    Ok(Option<Vec<u8>>),
     Err(String),
+    /// A conditional write found a value other than the expected one.
+    Conflict,
+    /// Milliseconds left before a key expires, or `None` if it never does.
+    Ttl(Option<u64>),
+    /// A page of a `SCAN`. `cursor` is where the next page starts, if the
+    /// range may hold more keys.
+    Scan {
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+        cursor: Option<Vec<u8>>,
+    },
 }
 
 impl Command {
-    pub fn new(a: Action, k: String, v: String) -> Self {
+    pub fn new(a: Action, k: Vec<u8>, v: Vec<u8>) -> Self {
         Command {
             action: a,
             key: k,
@@ -29,3 +96,21 @@ impl Command {
         }
     }
 }
+
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let buf = bincode::serialize(message).map_err(|e| KvError::Protocol(e.to_string()))?;
+    writer.write_u32::<LE>(buf.len() as u32)?;
+    writer.write_all(&buf)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T> {
+    let len = reader.read_u32::<LE>()?;
+    let mut buf = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut buf)?;
+    if buf.len() != len as usize {
+        return Err(KvError::Protocol("truncated message".to_owned()));
+    }
+    bincode::deserialize(&buf).map_err(|e| KvError::Protocol(e.to_string()))
+}
@@ -0,0 +1,121 @@
+use crate::engine::durability::Durability;
+use crate::engine::kv::{CompactionPolicy, KvStoreOptions};
+use crate::error::{KvError, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Settings read from a `kvs-server --config` TOML file.
+///
+/// Every field is optional; command-line flags take precedence over the file
+/// and built-in defaults fill in the rest.
+///
+/// ```toml
+/// addr = "127.0.0.1:4000"
+/// engine = "kvs"
+/// durability = "group-commit"
+///
+/// [kvs]
+/// segment_size = 16777216
+/// compaction = "segments:4"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub addr: Option<String>,
+    pub engine: Option<String>,
+    pub pool: Option<String>,
+    pub threads: Option<u32>,
+    pub durability: Option<String>,
+    /// How long a connection may go without sending a command before it is
+    /// closed, see `KvsServer::idle_timeout`.
+    pub idle_timeout_ms: Option<u64>,
+    /// A directory the other engine mirrors every write to, see
+    /// `engine::convert::DualWrite`.
+    pub dual_write: Option<PathBuf>,
+    /// The directory `CHECKPOINT` destinations are resolved under, see
+    /// `KvsServer::backup_dir`.
+    pub backup_dir: Option<PathBuf>,
+    pub kvs: KvsConfig,
+}
+
+/// The `[kvs]` table: `KvStoreOptions` for the kvs engine.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KvsConfig {
+    pub segment_size: Option<u64>,
+    pub compaction: Option<String>,
+    pub read_buffer_size: Option<usize>,
+    pub create_if_missing: Option<bool>,
+    pub error_if_exists: Option<bool>,
+    pub read_only: Option<bool>,
+    pub repair: Option<bool>,
+    pub reap_interval_ms: Option<u64>,
+    pub archive_dir: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> Result<ServerConfig> {
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content)
+            .map_err(|e| KvError::InvalidOption(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn idle_timeout(&self) -> Result<Option<Duration>> {
+        match self.idle_timeout_ms {
+            Some(0) => Err(KvError::InvalidOption(
+                "idle timeout must be positive".to_owned(),
+            )),
+            millis => Ok(millis.map(Duration::from_millis)),
+        }
+    }
+
+    pub fn durability(&self) -> Result<Option<Durability>> {
+        self.durability
+            .as_ref()
+            .map(|mode| mode.parse())
+            .transpose()
+    }
+}
+
+impl KvsConfig {
+    /// Overrides `options` with every setting present in the file.
+    pub fn apply(&self, options: KvStoreOptions) -> Result<KvStoreOptions> {
+        let mut options = self.apply_tuning(options)?;
+        if let Some(read_buffer_size) = self.read_buffer_size {
+            options = options.read_buffer_size(read_buffer_size);
+        }
+        if let Some(create_if_missing) = self.create_if_missing {
+            options = options.create_if_missing(create_if_missing);
+        }
+        if let Some(error_if_exists) = self.error_if_exists {
+            options = options.error_if_exists(error_if_exists);
+        }
+        if let Some(read_only) = self.read_only {
+            options = options.read_only(read_only);
+        }
+        if let Some(repair) = self.repair {
+            options = options.repair(repair);
+        }
+        if let Some(millis) = self.reap_interval_ms {
+            options = options.reap_interval(Duration::from_millis(millis));
+        }
+        if let Some(ref dir) = self.archive_dir {
+            options = options.archive(dir);
+        }
+        Ok(options)
+    }
+
+    /// Overrides `options` with the segment size and compaction policy only,
+    /// the settings that tune a store rather than say how it is used.
+    pub fn apply_tuning(&self, mut options: KvStoreOptions) -> Result<KvStoreOptions> {
+        if let Some(segment_size) = self.segment_size {
+            options = options.segment_size(segment_size);
+        }
+        if let Some(ref compaction) = self.compaction {
+            options = options.compaction(compaction.parse::<CompactionPolicy>()?);
+        }
+        Ok(options)
+    }
+}
@@ -0,0 +1,303 @@
+//! Archive mode: every segment a `KvStore` fills with writes is copied to an
+//! archive directory as it is sealed, before compaction can delete it, so
+//! the store can be rebuilt as of any write since archiving began.
+//!
+//! An archive holds
+//!
+//! - `base/`, a checkpoint taken when archiving started, and `BASE`, written
+//!   once that checkpoint is complete: the number of writes it holds and the
+//!   time it was taken;
+//! - `<first>-<last>.log`, a sealed segment holding writes `first` to
+//!   `last`;
+//! - `times`, the number and time of the first write made in every second,
+//!   which is what lets a restore point be given as a time.
+//!
+//! Times are milliseconds since the Unix epoch, like key deadlines.
+
+use crate::engine::backup;
+use crate::engine::expiry;
+use crate::engine::kv::{KvStore, KvStoreOptions};
+use crate::engine::record::{self, Tag};
+use crate::engine::KvsEngine;
+use crate::error::{KvError, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const BASE_DIR: &str = "base";
+const BASE_FILE: &str = "BASE";
+const TIMES_FILE: &str = "times";
+
+/// The archive a `KvStore` writes to.
+pub(crate) struct Archive {
+    dir: PathBuf,
+    times: File,
+    // Second of the last write noted in `times`.
+    last_second: Option<u64>,
+}
+
+impl Archive {
+    /// Opens the archive in `dir` for a store that has made `seq` writes
+    /// before its active segment. Fails if a segment was sealed without
+    /// being archived since the archive was last written to.
+    pub(crate) fn open(dir: &Path, seq: u64) -> Result<Archive> {
+        fs::create_dir_all(dir)?;
+        if let Some(base) = read_base(dir)? {
+            let archived = list_segments(dir)?
+                .iter()
+                .map(|segment| segment.last)
+                .fold(base.seq, u64::max);
+            if archived < seq {
+                return Err(KvError::Archive(format!(
+                    "writes {} to {} were never archived, start a new archive",
+                    archived + 1,
+                    seq
+                )));
+            }
+        }
+        let times = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(TIMES_FILE))?;
+        Ok(Archive {
+            dir: dir.to_owned(),
+            times,
+            last_second: None,
+        })
+    }
+
+    /// Notes the time of write `seq` if it is the first in a new second.
+    pub(crate) fn note_write(&mut self, seq: u64) -> Result<()> {
+        let now = expiry::now();
+        if self.last_second != Some(now / 1000) {
+            writeln!(self.times, "{} {}", seq, now)?;
+            self.last_second = Some(now / 1000);
+        }
+        Ok(())
+    }
+
+    /// Adds the sealed segment at `path`, which holds writes `first` to
+    /// `last`.
+    pub(crate) fn add_segment(&self, path: &Path, first: u64, last: u64) -> Result<()> {
+        let name = format!("{:020}-{:020}.log", first, last);
+        let temp = self.dir.join(format!("{}.tmp", name));
+        if temp.exists() {
+            fs::remove_file(&temp)?;
+        }
+        backup::link_or_copy(path, &temp)?;
+        fs::rename(&temp, self.dir.join(name))?;
+        Ok(())
+    }
+}
+
+/// Takes the base checkpoint of `store` unless the archive in `dir` has one.
+pub(crate) fn start(dir: &Path, store: &KvStore) -> Result<()> {
+    if dir.join(BASE_FILE).exists() {
+        return Ok(());
+    }
+    let base_dir = dir.join(BASE_DIR);
+    // Left by an attempt that did not finish.
+    if base_dir.exists() {
+        fs::remove_dir_all(&base_dir)?;
+    }
+    let time = expiry::now();
+    let seq = store.checkpoint_seq(&base_dir)?;
+    let temp = dir.join(format!("{}.tmp", BASE_FILE));
+    let mut file = File::create(&temp)?;
+    writeln!(file, "{} {}", seq, time)?;
+    file.sync_all()?;
+    fs::rename(&temp, dir.join(BASE_FILE))?;
+    info!("archiving to {:?} from write {}", dir, seq);
+    Ok(())
+}
+
+/// How far `restore` replays an archive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestorePoint {
+    /// Up to and including write `n`.
+    Seq(u64),
+    /// Every write made before the end of this second, in seconds since the
+    /// Unix epoch.
+    Time(u64),
+}
+
+/// Parses a write number, or `@` followed by seconds since the Unix epoch.
+impl FromStr for RestorePoint {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<RestorePoint> {
+        let point = match s.strip_prefix('@') {
+            Some(secs) => secs.parse().map(RestorePoint::Time),
+            None => s.parse().map(RestorePoint::Seq),
+        };
+        point.map_err(|_| KvError::InvalidOption(format!("unknown restore point: {}", s)))
+    }
+}
+
+/// What `restore` rebuilt.
+#[derive(Debug)]
+pub struct PointInTime {
+    /// The last write replayed.
+    pub seq: u64,
+    pub keys: u64,
+}
+
+/// Rebuilds the store as of `point` in `dest` from the archive in `dir`:
+/// copies the base checkpoint, then replays the archived writes after it.
+///
+/// `point` must fall between the base checkpoint and the last archived
+/// write; writes still in the store's active segment are not archived yet.
+/// `dest` must not exist or be empty, and is removed again if the restore
+/// fails.
+pub fn restore(dir: &Path, dest: &Path, point: RestorePoint) -> Result<PointInTime> {
+    let base = read_base(dir)?.ok_or_else(|| KvError::StoreNotFound(dir.display().to_string()))?;
+    let segments = list_segments(dir)?;
+    let archived = segments.iter().fold(base.seq, |archived, segment| {
+        if segment.first <= archived + 1 {
+            archived.max(segment.last)
+        } else {
+            archived
+        }
+    });
+    let seq = match point {
+        RestorePoint::Seq(seq) => seq,
+        RestorePoint::Time(secs) => {
+            let end = secs.saturating_add(1).saturating_mul(1000);
+            if end <= base.time {
+                return Err(KvError::Archive(format!(
+                    "{} is before the archive starts",
+                    secs
+                )));
+            }
+            // The first write noted after the second ends.
+            read_times(dir)?
+                .into_iter()
+                .find(|&(_, time)| time >= end)
+                .map_or(u64::MAX, |(seq, _)| seq - 1)
+        }
+    };
+    if seq < base.seq {
+        return Err(KvError::Archive(format!(
+            "write {} is before the archive starts at write {}",
+            seq, base.seq
+        )));
+    }
+    if seq > archived {
+        return Err(KvError::Archive(format!(
+            "the archive only reaches write {}",
+            archived
+        )));
+    }
+
+    backup::restore(&dir.join(BASE_DIR), dest)?;
+    match replay(&segments, dest, base.seq, seq) {
+        Ok(keys) => Ok(PointInTime { seq, keys }),
+        Err(e) => {
+            let _ = fs::remove_dir_all(dest);
+            Err(e)
+        }
+    }
+}
+
+/// Applies writes `from + 1` to `to` from `segments` to the store in `dest`,
+/// then counts its keys.
+fn replay(segments: &[Segment], dest: &Path, from: u64, to: u64) -> Result<u64> {
+    let store = KvStore::open_with(dest, KvStoreOptions::new().create_if_missing(false))?;
+    let mut applied = from;
+    for segment in segments {
+        if applied >= to {
+            break;
+        }
+        if segment.last <= applied {
+            continue;
+        }
+        let damaged = || KvError::Archive(format!("{} is damaged", segment.path.display()));
+        let mut reader = BufReader::new(File::open(&segment.path)?);
+        let mut offset = 0;
+        for seq in segment.first..=segment.last.min(to) {
+            let entry = record::read_record(&mut reader, 0, offset)
+                .map_err(|_| damaged())?
+                .ok_or_else(damaged)?;
+            let len = entry.encoded_len();
+            if seq > applied {
+                let entries = if entry.tag == Tag::Batch {
+                    record::decode_batch(entry, 0, offset)
+                        .map_err(|_| damaged())?
+                        .into_iter()
+                        .map(|(_, entry)| entry)
+                        .collect()
+                } else {
+                    vec![entry]
+                };
+                store.append_entries(entries)?;
+                applied = seq;
+            }
+            offset += len;
+        }
+    }
+    store.scan(..).try_fold(0, |n, pair| pair.map(|_| n + 1))
+}
+
+struct Base {
+    seq: u64,
+    time: u64,
+}
+
+fn read_base(dir: &Path) -> Result<Option<Base>> {
+    let content = match fs::read_to_string(dir.join(BASE_FILE)) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match parse_pair(&content) {
+        Some((seq, time)) => Ok(Some(Base { seq, time })),
+        None => Err(KvError::Archive(format!("damaged {} file", BASE_FILE))),
+    }
+}
+
+/// Every write noted in `times`, oldest first. A line cut short by a crash
+/// is skipped.
+fn read_times(dir: &Path) -> Result<Vec<(u64, u64)>> {
+    let content = match fs::read_to_string(dir.join(TIMES_FILE)) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(content.lines().filter_map(parse_pair).collect())
+}
+
+fn parse_pair(line: &str) -> Option<(u64, u64)> {
+    let mut fields = line.split_whitespace().map(|field| field.parse().ok());
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(Some(a)), Some(Some(b)), None) => Some((a, b)),
+        _ => None,
+    }
+}
+
+/// An archived segment.
+struct Segment {
+    first: u64,
+    last: u64,
+    path: PathBuf,
+}
+
+/// Every archived segment, by first write and then last. A segment archived
+/// just before a crash may be archived again later with more writes in it.
+fn list_segments(dir: &Path) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let range = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|range| range.split_once('-'))
+            .and_then(|(first, last)| Some((first.parse().ok()?, last.parse().ok()?)));
+        if let Some((first, last)) = range {
+            segments.push(Segment { first, last, path });
+        }
+    }
+    segments.sort_by_key(|segment| (segment.first, segment.last));
+    Ok(segments)
+}
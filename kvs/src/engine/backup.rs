@@ -0,0 +1,102 @@
+//! Restoring the checkpoints written by `KvsEngine::checkpoint`.
+
+use crate::engine::kv::{KvStore, KvStoreOptions};
+use crate::engine::marker;
+use crate::engine::sled::SledKvsEngine;
+use crate::engine::KvsEngine;
+use crate::error::{KvError, Result};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+/// What `restore` found in a checkpoint.
+#[derive(Debug)]
+pub struct Restored {
+    pub engine: String,
+    pub keys: u64,
+}
+
+/// Copies the checkpoint in `src` to `dest`, then opens the copy and reads
+/// back every value in it. `dest` must not exist or be empty, and is
+/// removed again if the checkpoint turns out to be incomplete or damaged.
+pub fn restore(src: &Path, dest: &Path) -> Result<Restored> {
+    // The engine file is written last, so a checkpoint without one is
+    // incomplete.
+    let engine = match marker::recorded_engine(src)? {
+        Some(engine) if src.join(marker::ENGINE_FILE).exists() => engine,
+        _ => return Err(KvError::StoreNotFound(src.display().to_string())),
+    };
+    create_empty_dir(dest)?;
+    let verified = copy_dir(src, dest).and_then(|()| count_keys(dest, &engine));
+    match verified {
+        Ok(keys) => Ok(Restored { engine, keys }),
+        Err(e) => {
+            let _ = fs::remove_dir_all(dest);
+            Err(e)
+        }
+    }
+}
+
+fn count_keys(dir: &Path, engine: &str) -> Result<u64> {
+    fn count<E: KvsEngine>(engine: E) -> Result<u64> {
+        engine.scan(..).try_fold(0, |n, pair| pair.map(|_| n + 1))
+    }
+    match engine {
+        "sled" => count(SledKvsEngine::open(dir)?),
+        _ => count(KvStore::open_with(
+            dir,
+            KvStoreOptions::new().create_if_missing(false),
+        )?),
+    }
+}
+
+/// Creates `dir` for a checkpoint or restore, failing with
+/// `KvError::StoreExists` if it already has anything in it.
+pub(crate) fn create_empty_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(KvError::StoreExists(dir.display().to_string()));
+    }
+    Ok(())
+}
+
+/// Hard-links `src` to `dest`, or copies it where a link is not possible.
+/// Only for files that are never written again.
+pub(crate) fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest)?;
+        File::open(dest)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Copies the first `len` bytes of `src` to a new file at `dest`.
+pub(crate) fn copy_prefix(src: &Path, dest: &Path, len: u64) -> Result<()> {
+    let mut dest = File::create(dest)?;
+    match File::open(src) {
+        Ok(src) => {
+            io::copy(&mut src.take(len), &mut dest)?;
+        }
+        // Nothing was written to it yet.
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && len == 0 => {}
+        Err(e) => return Err(e.into()),
+    }
+    dest.sync_all()?;
+    Ok(())
+}
+
+/// Copies a directory tree, leaving out the source's lock file.
+fn copy_dir(src: &Path, dest: &Path) -> Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            fs::create_dir(&target)?;
+            copy_dir(&entry.path(), &target)?;
+        } else if entry.file_name() != "LOCK" {
+            fs::copy(entry.path(), &target)?;
+            File::open(&target)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
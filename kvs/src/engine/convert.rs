@@ -0,0 +1,466 @@
+//! Moving a store from one engine to the other.
+//!
+//! Offline, `convert` copies every live key of a store into a new directory
+//! under the other engine, and only records the engine there once the
+//! checksums of both stores agree. `cut_over` then swaps the copy in for
+//! the original.
+//!
+//! Online, `DualWrite` serves from one engine while mirroring every write to
+//! the other, and `DualWrite::backfill` copies the keys written before the
+//! mirror started. Once it has caught up, the server can be stopped and
+//! started again on the copy.
+
+use crate::engine::backup;
+use crate::engine::batch::WriteBatch;
+use crate::engine::kv::{KvStore, KvStoreOptions};
+use crate::engine::lock::DirLock;
+use crate::engine::manifest;
+use crate::engine::marker::{self, ENGINES};
+use crate::engine::scan::KeyRange;
+use crate::engine::sled::{SledKvsEngine, SledOptions};
+use crate::engine::KvsEngine;
+use crate::error::{KvError, Result};
+use byteorder::{WriteBytesExt, LE};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often `convert` and `DualWrite::backfill` report progress, in keys.
+pub const PROGRESS_INTERVAL: u64 = 10_000;
+
+/// Most keys copied at a time.
+const COPY_BATCH: usize = 1000;
+
+/// A digest of every live key and value of a store, in key order.
+/// Deadlines are left out: a copy gets the same time to live, not the same
+/// deadline to the millisecond.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Checksum {
+    pub keys: u64,
+    pub crc: u32,
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} keys, crc32 {:08x}", self.keys, self.crc)
+    }
+}
+
+pub fn checksum<E: KvsEngine>(engine: &E) -> Result<Checksum> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut keys = 0;
+    let mut buf = Vec::new();
+    for pair in engine.scan(..) {
+        let (key, value) = pair?;
+        buf.clear();
+        buf.write_u64::<LE>(key.len() as u64)?;
+        buf.extend_from_slice(&key);
+        buf.write_u64::<LE>(value.len() as u64)?;
+        buf.extend_from_slice(&value);
+        hasher.update(&buf);
+        keys += 1;
+    }
+    Ok(Checksum {
+        keys,
+        crc: hasher.finalize(),
+    })
+}
+
+/// Makes `dest` hold what `src` does: copies every live key of `src` with
+/// its time to live, and removes the keys only `dest` has. Nothing else may
+/// write to either meanwhile.
+pub fn copy<S: KvsEngine, D: KvsEngine>(
+    src: &S,
+    dest: &D,
+    mut progress: impl FnMut(u64),
+) -> Result<u64> {
+    let mut start = Bound::Unbounded;
+    let mut count = 0;
+    loop {
+        let (copied, next) = copy_page(src, dest, start)?;
+        report(&mut progress, count, copied);
+        count += copied;
+        match next {
+            Some(next) => start = next,
+            None => return Ok(count),
+        }
+    }
+}
+
+/// What `convert` copied.
+#[derive(Debug)]
+pub struct Converted {
+    pub keys: u64,
+    pub checksum: Checksum,
+}
+
+/// Copies the store in `src` into a new store of `engine` in `dest`, which
+/// must not exist or be empty, and checks that both hold the same keys and
+/// values. `dest` only gets its engine file once they do, and is removed
+/// again if they do not.
+///
+/// `src` is opened read-only, and must not be in use.
+pub fn convert(
+    src: &Path,
+    dest: &Path,
+    engine: &str,
+    progress: impl FnMut(u64),
+) -> Result<Converted> {
+    if !ENGINES.contains(&engine) {
+        return Err(KvError::InvalidOption(format!(
+            "unknown engine: {}",
+            engine
+        )));
+    }
+    let recorded = marker::recorded_engine(src)?
+        .ok_or_else(|| KvError::StoreNotFound(src.display().to_string()))?;
+    backup::create_empty_dir(dest)?;
+    let converted = match recorded.as_str() {
+        "sled" => {
+            let options = SledOptions::new().read_only(true);
+            convert_from(
+                SledKvsEngine::open_with(src, options)?,
+                dest,
+                engine,
+                progress,
+            )
+        }
+        _ => {
+            let options = KvStoreOptions::new().read_only(true).upgrade(true);
+            convert_from(KvStore::open_with(src, options)?, dest, engine, progress)
+        }
+    }
+    .and_then(|converted| {
+        marker::record_engine(dest, engine)?;
+        Ok(converted)
+    });
+    if converted.is_err() {
+        let _ = fs::remove_dir_all(dest);
+    }
+    converted
+}
+
+/// Swaps the store in `dir` for the converted copy in `dest`, so that `dir`
+/// holds the copy, under its engine, and `dest` the original. Each engine
+/// file moves with its store.
+///
+/// Both directories are locked while they are renamed, so this fails with
+/// `KvError::Locked` if either store is open. For the time between the
+/// renames the original is at `<dir>.cutover`, where it is left if the swap
+/// is cut short. Nothing may have written to `dir` since the conversion.
+pub fn cut_over(dir: &Path, dest: &Path) -> Result<()> {
+    if marker::recorded_engine(dir)?.is_none() {
+        return Err(KvError::StoreNotFound(dir.display().to_string()));
+    }
+    // Only a finished conversion has an engine file.
+    if !dest.join(marker::ENGINE_FILE).exists() {
+        return Err(KvError::StoreNotFound(dest.display().to_string()));
+    }
+    let aside = aside_path(dir);
+    if aside.exists() {
+        return Err(KvError::StoreExists(aside.display().to_string()));
+    }
+    {
+        let _original = DirLock::acquire(dir)?;
+        let _copy = DirLock::acquire(dest)?;
+        fs::rename(dir, &aside)?;
+        fs::rename(dest, dir)?;
+        fs::rename(&aside, dest)?;
+    }
+    for parent in [dir, dest].iter().map(|path| parent_dir(path)) {
+        manifest::sync_dir(parent)?;
+    }
+    Ok(())
+}
+
+fn aside_path(dir: &Path) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".cutover");
+    dir.with_file_name(name)
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+fn convert_from<S: KvsEngine>(
+    src: S,
+    dest: &Path,
+    engine: &str,
+    progress: impl FnMut(u64),
+) -> Result<Converted> {
+    match engine {
+        "sled" => copy_and_check(&src, &SledKvsEngine::open(dest)?, progress),
+        _ => copy_and_check(&src, &KvStore::open(dest)?, progress),
+    }
+}
+
+fn copy_and_check<S: KvsEngine, D: KvsEngine>(
+    src: &S,
+    dest: &D,
+    progress: impl FnMut(u64),
+) -> Result<Converted> {
+    let keys = copy(src, dest, progress)?;
+    let original = checksum(src)?;
+    let copied = checksum(dest)?;
+    if original != copied {
+        return Err(KvError::Convert(format!(
+            "the copy does not match: {} in the original, {} in the copy",
+            original, copied
+        )));
+    }
+    Ok(Converted {
+        keys,
+        checksum: original,
+    })
+}
+
+/// An engine that reads from `primary` and applies every write to both
+/// `primary` and `secondary`, in the same order.
+///
+/// A write that fails on the primary is not applied to the secondary. A
+/// write that fails on the secondary after succeeding on the primary stops
+/// the mirror: the error is logged, the secondary gets no further writes,
+/// and `backfill` fails from then on.
+pub struct DualWrite<P: KvsEngine, S: KvsEngine> {
+    primary: P,
+    secondary: S,
+    // Held across both halves of a write, and while `backfill` copies a
+    // page.
+    state: Arc<Mutex<MirrorState>>,
+}
+
+struct MirrorState {
+    broken: bool,
+}
+
+impl<P: KvsEngine, S: KvsEngine> Clone for DualWrite<P, S> {
+    fn clone(&self) -> Self {
+        DualWrite {
+            primary: self.primary.clone(),
+            secondary: self.secondary.clone(),
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+impl<P: KvsEngine, S: KvsEngine> DualWrite<P, S> {
+    pub fn new(primary: P, secondary: S) -> DualWrite<P, S> {
+        DualWrite {
+            primary,
+            secondary,
+            state: Arc::new(Mutex::new(MirrorState { broken: false })),
+        }
+    }
+
+    /// Brings the secondary in line with the primary: copies every key of
+    /// the primary and removes the keys only the secondary has. Writes carry
+    /// on meanwhile, and once it returns the secondary holds what the
+    /// primary does. Returns the number of keys copied.
+    pub fn backfill(&self, mut progress: impl FnMut(u64)) -> Result<u64> {
+        let mut start = Bound::Unbounded;
+        let mut count = 0;
+        loop {
+            let mut state = self.state.lock().unwrap();
+            if state.broken {
+                return Err(KvError::Convert(
+                    "a write to the secondary failed, it no longer mirrors the primary".to_owned(),
+                ));
+            }
+            let (copied, next) = match copy_page(&self.primary, &self.secondary, start) {
+                Ok(page) => page,
+                Err(e) => {
+                    state.broken = true;
+                    return Err(e);
+                }
+            };
+            drop(state);
+            report(&mut progress, count, copied);
+            count += copied;
+            match next {
+                Some(next) => start = next,
+                None => return Ok(count),
+            }
+        }
+    }
+
+    /// Applies a write to the primary, then to the secondary. The secondary
+    /// may not have a key the backfill has not reached yet, so it failing
+    /// with `KeyNotExit` is expected.
+    fn mirror(
+        &self,
+        primary: impl FnOnce(&P) -> Result<()>,
+        secondary: impl FnOnce(&S) -> Result<()>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        primary(&self.primary)?;
+        if !state.broken {
+            match secondary(&self.secondary) {
+                Ok(()) | Err(KvError::KeyNotExit) => {}
+                Err(e) => {
+                    error!(
+                        "a write to the secondary failed, it no longer mirrors the primary: {}",
+                        e
+                    );
+                    state.broken = true;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<P: KvsEngine, S: KvsEngine> KvsEngine for DualWrite<P, S> {
+    type Snapshot = P::Snapshot;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let (k, v) = (key.clone(), value.clone());
+        self.mirror(move |p| p.set_bytes(k, v), move |s| s.set_bytes(key, value))
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.primary.get_bytes(key)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.mirror(|p| p.remove_bytes(key), |s| s.remove_bytes(key))
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let (k, v) = (key.clone(), value.clone());
+        self.mirror(
+            move |p| p.set_with_ttl(k, v, ttl),
+            move |s| s.set_with_ttl(key, value, ttl),
+        )
+    }
+
+    fn expire(&self, key: &[u8], ttl: Duration) -> Result<()> {
+        self.mirror(|p| p.expire(key, ttl), |s| s.expire(key, ttl))
+    }
+
+    fn persist(&self, key: &[u8]) -> Result<()> {
+        self.mirror(|p| p.persist(key), |s| s.persist(key))
+    }
+
+    fn ttl(&self, key: &[u8]) -> Result<Option<Duration>> {
+        self.primary.ttl(key)
+    }
+
+    fn deadline(&self, key: &[u8]) -> Result<Option<u64>> {
+        self.primary.deadline(key)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let copy = batch.clone();
+        self.mirror(move |p| p.write_batch(copy), move |s| s.write_batch(batch))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let (k, n) = (key.clone(), new.clone());
+        self.mirror(
+            move |p| p.compare_and_swap(k, expected, n),
+            move |s| {
+                let mut batch = WriteBatch::new();
+                match new {
+                    Some(value) => batch.set(key, value),
+                    None => batch.remove(key),
+                };
+                s.write_batch(batch)
+            },
+        )
+    }
+
+    fn compare_and_write_batch(
+        &self,
+        expected: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let copy = batch.clone();
+        self.mirror(
+            move |p| p.compare_and_write_batch(expected, copy),
+            move |s| s.write_batch(batch),
+        )
+    }
+
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        self.primary.checkpoint(dest)
+    }
+
+    fn snapshot(&self) -> Result<P::Snapshot> {
+        self.primary.snapshot()
+    }
+
+    fn scan_page(&self, range: KeyRange, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.primary.scan_page(range, limit)
+    }
+}
+
+/// Copies up to `COPY_BATCH` keys of `src` from `start` on to `dest`, and
+/// removes the keys `dest` has in the range they cover but `src` does not.
+/// Returns how many keys were copied and where the next page starts, or
+/// `None` after the last page.
+fn copy_page<S: KvsEngine, D: KvsEngine>(
+    src: &S,
+    dest: &D,
+    start: Bound<Vec<u8>>,
+) -> Result<(u64, Option<Bound<Vec<u8>>>)> {
+    let page = src.scan_page((start.clone(), Bound::Unbounded), COPY_BATCH)?;
+    let end = match page.last() {
+        Some((key, _)) if page.len() == COPY_BATCH => Bound::Included(key.clone()),
+        _ => Bound::Unbounded,
+    };
+    let copied: HashSet<&[u8]> = page.iter().map(|(key, _)| key.as_slice()).collect();
+    let mut batch = WriteBatch::new();
+    for pair in dest.scan((start, end.clone())) {
+        let (key, _) = pair?;
+        if !copied.contains(key.as_slice()) {
+            batch.remove(key);
+        }
+    }
+    let mut expiring = Vec::new();
+    for (key, value) in &page {
+        match src.ttl(key) {
+            Ok(None) => {
+                batch.set(key.clone(), value.clone());
+            }
+            Ok(Some(ttl)) => expiring.push((key.clone(), value.clone(), ttl)),
+            // Expired since the scan read it.
+            Err(KvError::KeyNotExit) => {
+                batch.remove(key.clone());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if !batch.is_empty() {
+        dest.write_batch(batch)?;
+    }
+    for (key, value, ttl) in expiring {
+        dest.set_with_ttl(key, value, ttl)?;
+    }
+    let next = match end {
+        Bound::Included(key) => Some(Bound::Excluded(key)),
+        _ => None,
+    };
+    Ok((page.len() as u64, next))
+}
+
+/// Calls `progress` when `copied` more keys take the count past a multiple
+/// of `PROGRESS_INTERVAL`.
+fn report(progress: &mut impl FnMut(u64), before: u64, copied: u64) {
+    let after = before + copied;
+    if after / PROGRESS_INTERVAL > before / PROGRESS_INTERVAL {
+        progress(after);
+    }
+}
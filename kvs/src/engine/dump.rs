@@ -0,0 +1,402 @@
+//! Logical dumps: every live key of a store with its value and deadline,
+//! as JSON Lines or CSV. Any engine can be dumped and loaded, so a dump also
+//! moves data from one engine to another.
+//!
+//! A record whose key or value is not UTF-8 has both written in base64 and
+//! says so in its `encoding`. In JSON Lines
+//!
+//! ```text
+//! {"key":"user:1","value":"alice","expires_at":1700000000000}
+//! {"key":"AAE=","value":"/w==","encoding":"base64"}
+//! ```
+//!
+//! and in CSV, below a `key,value,encoding,expires_at` header,
+//!
+//! ```text
+//! user:1,alice,,1700000000000
+//! AAE=,/w==,base64,
+//! ```
+//!
+//! Deadlines are in milliseconds since the Unix epoch. Records come in key
+//! order, so an interrupted dump or load can pick up after the last key it
+//! got to.
+
+use crate::engine::batch::WriteBatch;
+use crate::engine::expiry;
+use crate::engine::KvsEngine;
+use crate::error::{KvError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::str::FromStr;
+
+/// How often `dump` and `load` report progress, in records.
+pub const PROGRESS_INTERVAL: u64 = 10_000;
+
+/// Most records `load` writes in one batch.
+const LOAD_BATCH: usize = 1000;
+
+const CSV_HEADER: [&str; 4] = ["key", "value", "encoding", "expires_at"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpFormat {
+    JsonLines,
+    Csv,
+}
+
+/// Parses `jsonl` and `csv`.
+impl FromStr for DumpFormat {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<DumpFormat> {
+        match s {
+            "jsonl" => Ok(DumpFormat::JsonLines),
+            "csv" => Ok(DumpFormat::Csv),
+            _ => Err(KvError::InvalidOption(format!(
+                "unknown dump format: {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonRecord {
+    key: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+struct Record {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    expires_at: Option<u64>,
+}
+
+/// Writes every live key of `engine` after `after`, or every key, to `out`,
+/// and returns how many were written. `progress` is called every
+/// `PROGRESS_INTERVAL` records with the count so far and the last key.
+///
+/// A CSV dump only starts with a header when it starts from the first key.
+pub fn dump<E: KvsEngine>(
+    engine: &E,
+    out: impl Write,
+    format: DumpFormat,
+    after: Option<&[u8]>,
+    mut progress: impl FnMut(u64, &[u8]),
+) -> Result<u64> {
+    let start = match after {
+        Some(key) => Bound::Excluded(key.to_vec()),
+        None => Bound::Unbounded,
+    };
+    let mut writer = RecordWriter::new(out, format, after.is_none())?;
+    let mut count = 0;
+    for pair in engine.scan((start, Bound::Unbounded)) {
+        let (key, value) = pair?;
+        let expires_at = match engine.deadline(&key) {
+            Ok(deadline) => deadline,
+            // Expired or removed since the scan read it.
+            Err(KvError::KeyNotExit) => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write(&Record {
+            key,
+            value,
+            expires_at,
+        })?;
+        count += 1;
+        if count % PROGRESS_INTERVAL == 0 {
+            writer.flush()?;
+            progress(count, &writer.last_key);
+        }
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Writes every record of the dump in `input` with a key after `after`, or
+/// every record, to `engine`, and returns how many were written. Records
+/// whose deadline has passed are skipped. `progress` is called every
+/// `PROGRESS_INTERVAL` records, once they are all written, with the count
+/// so far and the last key.
+pub fn load<E: KvsEngine>(
+    engine: &E,
+    input: impl Read,
+    format: DumpFormat,
+    after: Option<&[u8]>,
+    mut progress: impl FnMut(u64, &[u8]),
+) -> Result<u64> {
+    let mut reader = RecordReader::new(input, format);
+    let mut batch = WriteBatch::new();
+    let mut pending = 0;
+    let mut count = 0;
+    while let Some(record) = reader.next_record()? {
+        if after.is_some_and(|after| record.key.as_slice() <= after) {
+            continue;
+        }
+        let key = record.key.clone();
+        match record.expires_at {
+            None => {
+                batch.set(record.key, record.value);
+                pending += 1;
+            }
+            Some(deadline) => match expiry::remaining(deadline) {
+                Some(ttl) => engine.set_with_ttl(record.key, record.value, ttl)?,
+                None => continue,
+            },
+        }
+        count += 1;
+        let report = count % PROGRESS_INTERVAL == 0;
+        if pending == LOAD_BATCH || (report && pending > 0) {
+            engine.write_batch(std::mem::take(&mut batch))?;
+            pending = 0;
+        }
+        if report {
+            progress(count, &key);
+        }
+    }
+    if pending > 0 {
+        engine.write_batch(batch)?;
+    }
+    Ok(count)
+}
+
+/// Cuts `file`, a dump an interrupted `dump` was writing, back to its last
+/// complete record and returns that record's key, for the dump to resume
+/// after. `None` if no record is complete; the file is then left empty, or
+/// with just a CSV header.
+pub fn truncate_to_last_record(file: &File, format: DumpFormat) -> Result<Option<Vec<u8>>> {
+    // Where the last two complete records end. In CSV a line break inside
+    // quotes belongs to a value.
+    let (mut before, mut last) = (0, 0);
+    let mut offset = 0;
+    let mut quoted = false;
+    let mut input = BufReader::new(file);
+    input.seek(SeekFrom::Start(0))?;
+    loop {
+        let buf = input.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        for &byte in buf {
+            offset += 1;
+            match byte {
+                b'"' if format == DumpFormat::Csv => quoted = !quoted,
+                b'\n' if !quoted => {
+                    before = last;
+                    last = offset;
+                }
+                _ => {}
+            }
+        }
+        let len = buf.len();
+        input.consume(len);
+    }
+    file.set_len(last)?;
+    let mut record = Vec::new();
+    input.seek(SeekFrom::Start(before))?;
+    input.take(last - before).read_to_end(&mut record)?;
+    // A CSV header alone is skipped, as in a full read.
+    let record = RecordReader::new(&record[..], format).next_record()?;
+    Ok(record.map(|record| record.key))
+}
+
+/// Writes records in either format, remembering the last key written.
+struct RecordWriter<W: Write> {
+    out: Output<W>,
+    count: u64,
+    last_key: Vec<u8>,
+}
+
+enum Output<W: Write> {
+    JsonLines(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> RecordWriter<W> {
+    fn new(out: W, format: DumpFormat, header: bool) -> Result<RecordWriter<W>> {
+        let out = match format {
+            DumpFormat::JsonLines => Output::JsonLines(out),
+            DumpFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(out);
+                if header {
+                    writer
+                        .write_record(CSV_HEADER)
+                        .map_err(|e| csv_error(e, 0))?;
+                }
+                Output::Csv(Box::new(writer))
+            }
+        };
+        Ok(RecordWriter {
+            out,
+            count: 0,
+            last_key: Vec::new(),
+        })
+    }
+
+    fn write(&mut self, record: &Record) -> Result<()> {
+        self.count += 1;
+        let (key, value, encoding) = match (
+            std::str::from_utf8(&record.key),
+            std::str::from_utf8(&record.value),
+        ) {
+            (Ok(key), Ok(value)) => (key.to_owned(), value.to_owned(), None),
+            _ => (
+                base64::encode(&record.key),
+                base64::encode(&record.value),
+                Some("base64"),
+            ),
+        };
+        match self.out {
+            Output::JsonLines(ref mut out) => {
+                let json = JsonRecord {
+                    key,
+                    value,
+                    encoding: encoding.map(str::to_owned),
+                    expires_at: record.expires_at,
+                };
+                serde_json::to_writer(&mut *out, &json).map_err(io::Error::from)?;
+                out.write_all(b"\n")?;
+            }
+            Output::Csv(ref mut out) => {
+                let expires_at = record
+                    .expires_at
+                    .map_or_else(String::new, |deadline| deadline.to_string());
+                out.write_record([
+                    key.as_str(),
+                    value.as_str(),
+                    encoding.unwrap_or(""),
+                    expires_at.as_str(),
+                ])
+                .map_err(|e| csv_error(e, self.count))?;
+            }
+        }
+        self.last_key.clone_from(&record.key);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self.out {
+            Output::JsonLines(ref mut out) => out.flush()?,
+            Output::Csv(ref mut out) => out.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Reads records in either format, numbering them from 1 for error
+/// messages. A CSV header is recognised and skipped, so a dump that was
+/// resumed into a new file loads too.
+struct RecordReader<R: Read> {
+    input: Input<R>,
+    number: u64,
+}
+
+enum Input<R: Read> {
+    JsonLines(io::Lines<BufReader<R>>),
+    Csv(csv::StringRecordsIntoIter<R>),
+}
+
+impl<R: Read> RecordReader<R> {
+    fn new(input: R, format: DumpFormat) -> RecordReader<R> {
+        let input = match format {
+            DumpFormat::JsonLines => Input::JsonLines(BufReader::new(input).lines()),
+            DumpFormat::Csv => Input::Csv(
+                csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    // Row lengths are checked against the header below.
+                    .flexible(true)
+                    .from_reader(input)
+                    .into_records(),
+            ),
+        };
+        RecordReader { input, number: 0 }
+    }
+
+    fn next_record(&mut self) -> Result<Option<Record>> {
+        loop {
+            self.number += 1;
+            let (key, value, encoding, expires_at) = match self.input {
+                Input::JsonLines(ref mut lines) => {
+                    let line = match lines.next() {
+                        Some(line) => line?,
+                        None => return Ok(None),
+                    };
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let json: JsonRecord =
+                        serde_json::from_str(&line).map_err(|e| self.malformed(e))?;
+                    (json.key, json.value, json.encoding, json.expires_at)
+                }
+                Input::Csv(ref mut records) => {
+                    let record = match records.next() {
+                        Some(record) => record.map_err(|e| csv_error(e, self.number))?,
+                        None => return Ok(None),
+                    };
+                    if self.number == 1 && record.iter().eq(CSV_HEADER.iter().cloned()) {
+                        continue;
+                    }
+                    if record.len() != CSV_HEADER.len() {
+                        return Err(self.malformed(format!(
+                            "expected {} fields, found {}",
+                            CSV_HEADER.len(),
+                            record.len()
+                        )));
+                    }
+                    let expires_at = match &record[3] {
+                        "" => None,
+                        deadline => Some(deadline.parse().map_err(|e| self.malformed(e))?),
+                    };
+                    let encoding = Some(record[2].to_owned()).filter(|e| !e.is_empty());
+                    (
+                        record[0].to_owned(),
+                        record[1].to_owned(),
+                        encoding,
+                        expires_at,
+                    )
+                }
+            };
+            let (key, value) = match encoding.as_deref() {
+                None => (key.into_bytes(), value.into_bytes()),
+                Some("base64") => (
+                    base64::decode(&key).map_err(|e| self.malformed(e))?,
+                    base64::decode(&value).map_err(|e| self.malformed(e))?,
+                ),
+                Some(other) => {
+                    return Err(self.malformed(format!("unknown encoding {}", other)));
+                }
+            };
+            return Ok(Some(Record {
+                key,
+                value,
+                expires_at,
+            }));
+        }
+    }
+
+    fn malformed(&self, reason: impl ToString) -> KvError {
+        KvError::BadDump {
+            record: self.number,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+fn csv_error(e: csv::Error, record: u64) -> KvError {
+    if e.is_io_error() {
+        match e.into_kind() {
+            csv::ErrorKind::Io(e) => return KvError::IoError(e),
+            _ => unreachable!(),
+        }
+    }
+    KvError::BadDump {
+        record,
+        reason: e.to_string(),
+    }
+}
@@ -0,0 +1,118 @@
+use crate::engine::stats::SyncStats;
+use crate::error::{KvError, Result};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// How hard an engine tries to get a write onto disk before acknowledging it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// Writes are buffered in memory. A process crash can lose any write that
+    /// has not been flushed by a later one filling the buffer.
+    None,
+    /// Every write reaches the OS before it returns. Survives a process crash
+    /// but not an OS crash or power loss.
+    FlushOnly,
+    /// Every write is fsynced before it returns.
+    FsyncEveryWrite,
+    /// Writes are flushed as with `FlushOnly` and fsynced in the background at
+    /// this interval, bounding what an OS crash can lose.
+    FsyncEvery(Duration),
+    /// Every write is fsynced before it returns, but writers that arrive
+    /// while an fsync is running share the next one.
+    GroupCommit,
+}
+
+/// Parses the names used by `kvs-server --durability`: `none`, `flush`,
+/// `fsync`, `fsync-every:<millis>` and `group-commit`.
+impl FromStr for Durability {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Durability> {
+        match s {
+            "none" => Ok(Durability::None),
+            "flush" => Ok(Durability::FlushOnly),
+            "fsync" => Ok(Durability::FsyncEveryWrite),
+            "group-commit" => Ok(Durability::GroupCommit),
+            _ => s
+                .strip_prefix("fsync-every:")
+                .and_then(|millis| millis.parse::<u64>().ok())
+                .filter(|&millis| millis > 0)
+                .map(|millis| Durability::FsyncEvery(Duration::from_millis(millis)))
+                .ok_or_else(|| KvError::InvalidOption(format!("unknown durability: {}", s))),
+        }
+    }
+}
+
+/// Counts behind `SyncStats`, shared by every handle of an engine.
+#[derive(Default)]
+pub(crate) struct SyncCounter {
+    flushes: AtomicU64,
+    fsyncs: AtomicU64,
+}
+
+impl SyncCounter {
+    pub fn flushed(&self) {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn synced(&self) {
+        self.fsyncs.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn stats(&self) -> SyncStats {
+        SyncStats {
+            flushes: self.flushes.load(Ordering::SeqCst),
+            fsyncs: self.fsyncs.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Batches the fsyncs of concurrent writers.
+///
+/// Writes are numbered in the order they are appended. A writer that needs
+/// its write to be durable either waits for an fsync already covering it or
+/// runs the next fsync itself, covering everything appended so far.
+#[derive(Default)]
+pub(crate) struct GroupCommit {
+    state: Mutex<GroupState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct GroupState {
+    synced_seq: u64,
+    syncing: bool,
+}
+
+impl GroupCommit {
+    /// Blocks until write `seq` is durable. `sync` makes every appended
+    /// write durable and returns the number of the last one it covered.
+    pub fn wait<F>(&self, seq: u64, sync: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<u64>,
+    {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced_seq >= seq {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = self.synced.wait(state).unwrap();
+        }
+
+        state.syncing = true;
+        drop(state);
+        let result = sync();
+        let mut state = self.state.lock().unwrap();
+        state.syncing = false;
+        if let Ok(covered) = result {
+            state.synced_seq = state.synced_seq.max(covered);
+        }
+        self.synced.notify_all();
+        result.map(|_| ())
+    }
+}
@@ -0,0 +1,29 @@
+//! Key expiration deadlines.
+//!
+//! A deadline is stored as milliseconds since the Unix epoch, so it means
+//! the same thing after a restart.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// The deadline of a key that expires `ttl` from now.
+pub fn deadline(ttl: Duration) -> u64 {
+    now().saturating_add(ttl.as_millis() as u64)
+}
+
+pub fn is_expired(deadline: u64) -> bool {
+    deadline <= now()
+}
+
+/// Time left before `deadline`, or `None` if it has passed.
+pub fn remaining(deadline: u64) -> Option<Duration> {
+    deadline
+        .checked_sub(now())
+        .filter(|&millis| millis > 0)
+        .map(Duration::from_millis)
+}
@@ -0,0 +1,106 @@
+//! Hint files: a compact copy of a sealed segment's index.
+//!
+//! `hint_<id>` sits next to `log_<id>` and lists every record in the segment
+//! without its value, so opening a store does not have to read the values
+//! back. The file is a sequence of entries
+//!
+//! | bytes   | field                   |
+//! |---------|-------------------------|
+//! | 1       | tag                     |
+//! | 8       | deadline, only if tag 3 |
+//! | 8       | record offset           |
+//! | 8       | record length           |
+//! | 4       | key length              |
+//! | key len | key                     |
+//!
+//! followed by a CRC32 of everything before it. A hint that is missing or
+//! fails its checksum is ignored and the segment is scanned instead.
+
+use crate::engine::record::{Tag, EXPIRING_TAG};
+use crate::error::Result;
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+#[derive(Debug)]
+pub struct HintEntry {
+    pub key: Vec<u8>,
+    pub offset: u64,
+    pub len: u64,
+    pub tag: Tag,
+    pub expires_at: Option<u64>,
+}
+
+pub fn write_hint(path: &Path, entries: &[HintEntry]) -> Result<()> {
+    let mut buf = Vec::new();
+    for entry in entries {
+        match entry.expires_at {
+            Some(deadline) => {
+                buf.write_u8(EXPIRING_TAG)?;
+                buf.write_u64::<LE>(deadline)?;
+            }
+            None => buf.write_u8(entry.tag.to_u8())?,
+        }
+        buf.write_u64::<LE>(entry.offset)?;
+        buf.write_u64::<LE>(entry.len)?;
+        buf.write_u32::<LE>(entry.key.len() as u32)?;
+        buf.extend_from_slice(&entry.key);
+    }
+    let crc = checksum(&buf);
+    buf.write_u32::<LE>(crc)?;
+    fs::write(path, buf)?;
+    Ok(())
+}
+
+/// Returns `None` if there is no usable hint at `path`.
+pub fn read_hint(path: &Path) -> Result<Option<Vec<HintEntry>>> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let (body, mut crc) = buf.split_at(buf.len() - 4);
+    if checksum(body) != crc.read_u32::<LE>()? {
+        return Ok(None);
+    }
+    Ok(parse_entries(body).ok())
+}
+
+fn checksum(buf: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(buf);
+    hasher.finalize()
+}
+
+fn parse_entries(mut body: &[u8]) -> io::Result<Vec<HintEntry>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid hint entry");
+    let mut entries = Vec::new();
+    while !body.is_empty() {
+        let (tag, expires_at) = match body.read_u8()? {
+            EXPIRING_TAG => (Tag::Normal, Some(body.read_u64::<LE>()?)),
+            tag => {
+                let tag = Tag::from_u8(tag)
+                    .filter(|&tag| tag != Tag::Batch)
+                    .ok_or_else(invalid)?;
+                (tag, None)
+            }
+        };
+        let offset = body.read_u64::<LE>()?;
+        let len = body.read_u64::<LE>()?;
+        let key_len = body.read_u32::<LE>()?;
+        let mut key = vec![0; key_len as usize];
+        body.read_exact(&mut key)?;
+        entries.push(HintEntry {
+            key,
+            offset,
+            len,
+            tag,
+            expires_at,
+        });
+    }
+    Ok(entries)
+}
@@ -0,0 +1,299 @@
+//! Looking inside a `KvStore` directory record by record, without opening
+//! it: what `kvs-admin verify`, `inspect` and `repair` are built on.
+//!
+//! Nothing here writes to the directory it reads, whatever damage it finds.
+
+use crate::engine::backup;
+use crate::engine::hint::{self, HintEntry};
+use crate::engine::kv::{self, KvStore, KvStoreOptions};
+use crate::engine::manifest::Manifest;
+use crate::engine::marker;
+use crate::engine::record::{self, Entry, Tag};
+use crate::engine::KvsEngine;
+use crate::error::{KvError, Result};
+use std::fs::{self, File};
+use std::io::{BufReader, Seek, SeekFrom};
+use std::path::Path;
+
+/// The segments that make up a store, oldest first.
+#[derive(Debug)]
+pub struct Layout {
+    pub segments: Vec<u32>,
+    /// `log_<id>` files the manifest does not list, left over from a crash.
+    pub orphans: Vec<u32>,
+    /// Why the manifest could not be used, in which case `segments` is every
+    /// `log_<id>` file in the directory.
+    pub manifest_problem: Option<String>,
+}
+
+/// One record as it is on disk. The entries of a batch follow the batch
+/// record itself, with `in_batch` set.
+#[derive(Debug)]
+pub struct RecordInfo {
+    pub offset: u64,
+    /// Encoded length, header included.
+    pub len: u64,
+    /// `set`, `remove` or `batch`.
+    pub kind: &'static str,
+    pub key: Vec<u8>,
+    pub value_len: u64,
+    pub expires_at: Option<u64>,
+    pub in_batch: bool,
+}
+
+/// Bytes of a segment that hold no readable record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Damage {
+    pub segment: u32,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// What `verify` found.
+#[derive(Debug, Default)]
+pub struct Verified {
+    pub segments: usize,
+    pub records: u64,
+    pub damage: Vec<Damage>,
+    /// Problems other than damaged records: with the manifest or a hint file.
+    pub problems: Vec<String>,
+}
+
+impl Verified {
+    pub fn is_ok(&self) -> bool {
+        self.damage.is_empty() && self.problems.is_empty()
+    }
+}
+
+/// What `repair` salvaged.
+#[derive(Debug)]
+pub struct Repaired {
+    pub records: u64,
+    pub keys: u64,
+    pub damage: Vec<Damage>,
+}
+
+pub fn layout(dir: &Path) -> Result<Layout> {
+    let on_disk = kv::list_segments(dir)?;
+    let (listed, manifest_problem) = match Manifest::load(dir) {
+        Ok(Some(manifest)) => (Some(manifest.segment_ids()), None),
+        // Format version 0 had no manifest, only `current`.
+        Ok(None) if dir.join("current").exists() => (None, None),
+        Ok(None) if on_disk.is_empty() => {
+            return Err(KvError::StoreNotFound(dir.display().to_string()))
+        }
+        Ok(None) => (None, Some("missing".to_owned())),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    let layout = match listed {
+        Some(listed) => Layout {
+            segments: listed.iter().cloned().collect(),
+            orphans: on_disk.difference(&listed).cloned().collect(),
+            manifest_problem,
+        },
+        None => Layout {
+            segments: on_disk.into_iter().collect(),
+            orphans: Vec::new(),
+            manifest_problem,
+        },
+    };
+    Ok(layout)
+}
+
+/// Calls `visit` on every readable record of segment `id`, and returns the
+/// damaged ranges skipped.
+pub fn inspect(dir: &Path, id: u32, mut visit: impl FnMut(&RecordInfo)) -> Result<Vec<Damage>> {
+    walk(dir, id, |offset, len, tag, entries| {
+        if tag == Tag::Batch {
+            visit(&RecordInfo {
+                offset,
+                len,
+                kind: "batch",
+                key: Vec::new(),
+                value_len: len - record::HEADER_LEN,
+                expires_at: None,
+                in_batch: false,
+            });
+        }
+        for (offset, entry) in entries {
+            visit(&RecordInfo {
+                offset: *offset,
+                len: entry.encoded_len(),
+                kind: kind(entry.tag),
+                key: entry.key.clone(),
+                value_len: entry.value.len() as u64,
+                expires_at: entry.expires_at,
+                in_batch: tag == Tag::Batch,
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Checks the framing and checksum of every record in every segment, and
+/// that each hint file matches its segment.
+pub fn verify(dir: &Path) -> Result<Verified> {
+    let layout = layout(dir)?;
+    let mut verified = Verified {
+        segments: layout.segments.len(),
+        ..Verified::default()
+    };
+    if let Some(problem) = layout.manifest_problem {
+        verified.problems.push(format!("manifest: {}", problem));
+    }
+    for id in layout.segments {
+        let mut hints = Vec::new();
+        let damage = walk(dir, id, |_, _, _, entries| {
+            for (offset, entry) in entries {
+                hints.push(HintEntry {
+                    key: entry.key.clone(),
+                    offset: *offset,
+                    len: entry.encoded_len(),
+                    tag: entry.tag,
+                    expires_at: entry.expires_at,
+                });
+            }
+            Ok(())
+        })?;
+        verified.records += hints.len() as u64;
+        verified.damage.extend(damage);
+
+        let hint_path = kv::get_hint_path(dir, id);
+        if !hint_path.exists() {
+            continue;
+        }
+        match hint::read_hint(&hint_path)? {
+            None => verified
+                .problems
+                .push(format!("hint_{} is damaged and will be ignored", id)),
+            Some(found) if !same_hints(&found, &hints) => verified
+                .problems
+                .push(format!("hint_{} does not match log_{}", id, id)),
+            Some(_) => {}
+        }
+    }
+    Ok(verified)
+}
+
+/// Copies every readable record of the store in `src`, oldest first, into a
+/// new store in `dest`, which must not exist or be empty. `src` is left as
+/// it is; `dest` is removed again if the repair fails.
+pub fn repair(src: &Path, dest: &Path) -> Result<Repaired> {
+    let layout = layout(src)?;
+    backup::create_empty_dir(dest)?;
+    let salvaged = salvage(src, dest, &layout.segments);
+    if salvaged.is_err() {
+        let _ = fs::remove_dir_all(dest);
+    }
+    salvaged
+}
+
+fn salvage(src: &Path, dest: &Path, segments: &[u32]) -> Result<Repaired> {
+    let store = KvStore::open_with(dest, KvStoreOptions::new())?;
+    marker::record_engine(dest, "kvs")?;
+    let mut records = 0;
+    let mut damage = Vec::new();
+    for &id in segments {
+        damage.extend(walk(src, id, |_, _, _, entries| {
+            records += entries.len() as u64;
+            let entries = entries.iter().map(|(_, entry)| copy(entry)).collect();
+            store.append_entries(entries)
+        })?);
+    }
+    let keys = store.scan(..).try_fold(0, |n, pair| pair.map(|_| n + 1))?;
+    Ok(Repaired {
+        records,
+        keys,
+        damage,
+    })
+}
+
+/// Reads the top-level records of segment `id` in order, passing each to
+/// `visit` with its offset, length, tag and entries: itself, or those of a
+/// batch. Damaged bytes are skipped up to the next intact record, or to the
+/// end of the segment if there is none.
+fn walk(
+    dir: &Path,
+    id: u32,
+    mut visit: impl FnMut(u64, u64, Tag, &[(u64, Entry)]) -> Result<()>,
+) -> Result<Vec<Damage>> {
+    let path = kv::get_log_path(dir, id);
+    let len = fs::metadata(&path)?.len();
+    let mut reader = BufReader::new(File::open(&path)?);
+    let mut damage = Vec::new();
+    let mut offset = 0;
+    loop {
+        let corrupted = match record::read_record(&mut reader, id, offset) {
+            Ok(Some(entry)) => {
+                let record_len = entry.encoded_len();
+                let tag = entry.tag;
+                let entries = if tag == Tag::Batch {
+                    record::decode_batch(entry, id, offset)
+                } else {
+                    Ok(vec![(offset, entry)])
+                };
+                match entries {
+                    Ok(entries) => visit(offset, record_len, tag, &entries)?,
+                    Err(KvError::Corruption { .. }) => damage.push(Damage {
+                        segment: id,
+                        start: offset,
+                        end: offset + record_len,
+                    }),
+                    Err(e) => return Err(e),
+                }
+                offset += record_len;
+                false
+            }
+            Ok(None) => break,
+            Err(KvError::Corruption { .. }) => true,
+            Err(e) => return Err(e),
+        };
+        if corrupted {
+            match record::find_next_record(&mut reader, id, offset + 1)? {
+                Some(next) => {
+                    damage.push(Damage {
+                        segment: id,
+                        start: offset,
+                        end: next,
+                    });
+                    reader.seek(SeekFrom::Start(next))?;
+                    offset = next;
+                }
+                None => {
+                    damage.push(Damage {
+                        segment: id,
+                        start: offset,
+                        end: len,
+                    });
+                    break;
+                }
+            }
+        }
+    }
+    Ok(damage)
+}
+
+fn same_hints(a: &[HintEntry], b: &[HintEntry]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(a, b)| {
+            (&a.key, a.offset, a.len, a.tag, a.expires_at)
+                == (&b.key, b.offset, b.len, b.tag, b.expires_at)
+        })
+}
+
+fn copy(entry: &Entry) -> Entry {
+    Entry {
+        key: entry.key.clone(),
+        value: entry.value.clone(),
+        tag: entry.tag,
+        expires_at: entry.expires_at,
+    }
+}
+
+fn kind(tag: Tag) -> &'static str {
+    match tag {
+        Tag::Normal => "set",
+        Tag::Deleted => "remove",
+        Tag::Batch => "batch",
+    }
+}
@@ -1,215 +1,1878 @@
+use crate::engine::archive::{self, Archive};
+use crate::engine::backup;
+use crate::engine::batch::{BatchOp, WriteBatch};
+use crate::engine::durability::{Durability, GroupCommit, SyncCounter};
+use crate::engine::expiry;
+use crate::engine::hint::{self, HintEntry};
+use crate::engine::lock::DirLock;
+use crate::engine::manifest::{Manifest, FORMAT_VERSION, MANIFEST_FILE, MANIFEST_TEMP_FILE};
+use crate::engine::marker;
+use crate::engine::record::{self, Entry, Tag};
+use crate::engine::scan::{self, KeyRange, ScanSource};
+use crate::engine::snapshot::KvsSnapshot;
+use crate::engine::stats::{SegmentStats, StoreStats, SyncStats};
 use crate::engine::KvsEngine;
 use crate::error::KvError;
 use crate::error::Result;
-use byteorder::{ReadBytesExt, WriteBytesExt, LE};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use byteorder::{ReadBytesExt, LE};
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use std::cell::RefCell;
+use std::collections::btree_map;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{File, OpenOptions};
-use std::io::Seek;
-use std::io::{BufReader, BufWriter, Read, SeekFrom, Write};
+use std::io::{self, Read, Seek};
+use std::io::{BufReader, BufWriter, SeekFrom, Write};
 use std::option::Option;
-use std::path::Path;
-use std::path::PathBuf;
-use std::str;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
+/// Most expired keys removed by a single write.
+const REAP_BATCH: usize = 1000;
+
+/// Named the active segment before the manifest replaced it.
+const LEGACY_CURRENT_FILE: &str = "current";
+
+/// Times a read-only open beside a writer is tried before giving up on
+/// catching the segments between two merges.
+const OPEN_ATTEMPTS: u32 = 10;
+
+/// A log-structured key/value store.
+///
+/// The log is split into segment files, `log_<id>`. Writes go to the active
+/// segment, the one with the highest id; once it passes the configured
+/// segment size it is sealed, and the `CompactionPolicy` decides when a
+/// background thread merges the sealed segments into one.
+/// Sealed segments get a hint file so reopening the store does not have to
+/// read their values. The `MANIFEST` lists the segments that make up the
+/// store; anything else found on open is left over from a crash and deleted.
+/// In archive mode every segment filled by writes is also copied to an
+/// archive as it is sealed; see `archive`.
+///
+/// Expired keys are hidden as soon as their deadline passes. A background
+/// thread writes tombstones for them, and compaction drops the ones it finds
+/// first.
+///
+/// While snapshots are open, the index remembers the records they still see
+/// after those are replaced, and compacted segments are only deleted once
+/// the snapshots that were open at the time are released.
+///
+/// `KvStore` is a cheap handle: clones share the same index and writer, and
+/// every clone keeps its own set of segment readers so gets on different
+/// threads never contend on a file handle.
+///
+/// The directory is locked, through a `LOCK` file, until the last handle and
+/// snapshot are dropped. Opening it again meanwhile, even from the same
+/// process, fails with `KvError::Locked`; read-only opens share the lock
+/// instead, see `KvStoreOptions::read_only`.
+#[derive(Clone)]
 pub struct KvStore {
-    memtable: Box<HashMap<String, u64>>,
-    immutable: Box<HashMap<String, u64>>,
-    dir: PathBuf,
-    log_id: u32,
-    reader: BufReader<File>,
-    writer: BufWriter<File>,
+    // Declared first so its threads are joined before the writer, and with
+    // it the directory lock, can be released.
+    _background: Arc<Vec<Periodic>>,
+    index: Arc<RwLock<Index>>,
+    reader: KvStoreReader,
+    // `None` when the store was opened read-only.
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    durability: Durability,
+    group_commit: Arc<GroupCommit>,
+    // Set while `Durability::None` leaves writes sitting in the writer's
+    // buffer, where readers cannot see them.
+    unflushed: Arc<AtomicBool>,
+    syncs: Arc<SyncCounter>,
+}
+
+/// Where the latest record of every key is, how many bytes of each segment
+/// are still live, and which keys expire when.
+#[derive(Default)]
+struct Index {
+    keys: BTreeMap<Vec<u8>, LogPointer>,
+    segments: BTreeMap<u32, SegmentStats>,
+    expiries: BTreeSet<(u64, Vec<u8>)>,
+    // Number of the last write applied.
+    seq: u64,
+    // Open snapshots, by id, and the write each is pinned to.
+    snapshots: BTreeMap<u64, u64>,
+    next_snapshot_id: u64,
+    // Replaced records some snapshot still sees, oldest first, with the write
+    // that made each one current. `None` marks a removal.
+    history: BTreeMap<Vec<u8>, Vec<(u64, Option<LogPointer>)>>,
+    // Compacted segments kept for snapshots, each with the id of the first
+    // snapshot opened after the compaction.
+    retired: Vec<(u64, u32)>,
+}
+
+impl Index {
+    /// Records that `hint` was written to `segment_id` by write `seq`.
+    fn apply(&mut self, segment_id: u32, hint: &HintEntry, seq: u64) {
+        self.seq = seq;
+        let stats = self.segment(segment_id);
+        let old = match hint.tag {
+            Tag::Normal => {
+                stats.live_bytes += hint.len;
+                let pointer = LogPointer {
+                    segment_id,
+                    offset: hint.offset,
+                    len: hint.len,
+                    expires_at: hint.expires_at,
+                    seq,
+                };
+                self.keys.insert(hint.key.clone(), pointer)
+            }
+            Tag::Batch => unreachable!("batches are indexed entry by entry"),
+            Tag::Deleted => {
+                // A tombstone only has to outlive the values it hides, which
+                // compaction drops together with it.
+                stats.dead_bytes += hint.len;
+                self.keys.remove(&hint.key)
+            }
+        };
+        if let Some(old) = old {
+            self.mark_dead(old);
+            if let Some(deadline) = old.expires_at {
+                self.expiries.remove(&(deadline, hint.key.clone()));
+            }
+            self.keep_version(&hint.key, old, seq, hint.tag == Tag::Deleted);
+        }
+        if let Some(deadline) = hint.expires_at {
+            self.expiries.insert((deadline, hint.key.clone()));
+        }
+    }
+
+    /// The pointer of `key`, unless it is missing or expired.
+    fn live(&self, key: &[u8]) -> Option<LogPointer> {
+        let now = expiry::now();
+        self.keys
+            .get(key)
+            .filter(|pointer| !pointer.is_expired(now))
+            .copied()
+    }
+
+    /// Drops `key` from the index without accounting for its bytes.
+    fn forget(&mut self, key: &[u8]) {
+        if let Some(pointer) = self.keys.remove(key) {
+            if let Some(deadline) = pointer.expires_at {
+                self.expiries.remove(&(deadline, key.to_vec()));
+            }
+            if let Some(versions) = self.history.get_mut(key) {
+                versions.push((pointer.seq, None));
+            }
+        }
+    }
+
+    /// Remembers `old`, replaced or removed by write `seq`, if a snapshot
+    /// still sees it.
+    fn keep_version(&mut self, key: &[u8], old: LogPointer, seq: u64, removed: bool) {
+        let seen = self
+            .snapshots
+            .values()
+            .any(|&pinned| old.seq <= pinned && pinned < seq);
+        if seen {
+            self.history
+                .entry(key.to_vec())
+                .or_default()
+                .push((old.seq, Some(old)));
+        }
+        // Older versions must not show through once the key is gone.
+        if removed {
+            if let Some(versions) = self.history.get_mut(key) {
+                versions.push((seq, None));
+            }
+        }
+    }
+
+    /// The record of `key` a snapshot pinned to write `seq` sees.
+    fn version_at(&self, key: &[u8], seq: u64) -> Option<LogPointer> {
+        match self.keys.get(key) {
+            Some(pointer) if pointer.seq <= seq => Some(*pointer),
+            _ => self
+                .history
+                .get(key)?
+                .iter()
+                .rev()
+                .find(|(version_seq, _)| *version_seq <= seq)
+                .and_then(|(_, pointer)| *pointer),
+        }
+    }
+
+    /// Up to `limit` keys in `range` and the records a snapshot pinned to
+    /// write `seq` sees, skipping the ones that have expired.
+    fn versions_in(&self, range: KeyRange, seq: u64, limit: usize) -> Vec<(Vec<u8>, LogPointer)> {
+        let now = expiry::now();
+        let mut keys = self
+            .keys
+            .range(range.clone())
+            .map(|(key, _)| key)
+            .peekable();
+        let mut replaced = self.history.range(range).map(|(key, _)| key).peekable();
+        let mut found = Vec::new();
+        while found.len() < limit {
+            let key = match (keys.peek(), replaced.peek()) {
+                (Some(&a), Some(&b)) => a.min(b),
+                (Some(&a), None) => a,
+                (None, Some(&b)) => b,
+                (None, None) => break,
+            };
+            if keys.peek() == Some(&key) {
+                keys.next();
+            }
+            if replaced.peek() == Some(&key) {
+                replaced.next();
+            }
+            match self.version_at(key, seq) {
+                Some(pointer) if !pointer.is_expired(now) => found.push((key.clone(), pointer)),
+                _ => {}
+            }
+        }
+        found
+    }
+
+    /// Forgets a released snapshot, along with the records only it saw.
+    /// Returns the compacted segments no open snapshot needs anymore.
+    fn release(&mut self, id: u64) -> Vec<u32> {
+        self.snapshots.remove(&id);
+        let pinned: Vec<u64> = self.snapshots.values().copied().collect();
+        let keys = &self.keys;
+        self.history.retain(|key, versions| {
+            let current = keys.get(key).map(|pointer| pointer.seq);
+            let mut kept: Vec<(u64, Option<LogPointer>)> = Vec::new();
+            for (i, &(seq, pointer)) in versions.iter().enumerate() {
+                let next = versions
+                    .get(i + 1)
+                    .map(|&(next, _)| next)
+                    .or(current)
+                    .unwrap_or(u64::MAX);
+                let seen = pinned.iter().any(|&pinned| seq <= pinned && pinned < next);
+                // A removal with nothing kept before it hides nothing.
+                if seen && (pointer.is_some() || !kept.is_empty()) {
+                    kept.push((seq, pointer));
+                }
+            }
+            *versions = kept;
+            !versions.is_empty()
+        });
+
+        let oldest = self.snapshots.keys().next().copied().unwrap_or(u64::MAX);
+        let (released, retired): (Vec<_>, Vec<_>) = std::mem::take(&mut self.retired)
+            .into_iter()
+            .partition(|(first_unaffected, _)| *first_unaffected <= oldest);
+        self.retired = retired;
+        let mut released: Vec<u32> = released.into_iter().map(|(_, id)| id).collect();
+        released.sort_unstable();
+        released
+    }
+
+    fn mark_dead(&mut self, pointer: LogPointer) {
+        let stats = self.segment(pointer.segment_id);
+        stats.live_bytes -= pointer.len;
+        stats.dead_bytes += pointer.len;
+    }
+
+    fn segment(&mut self, id: u32) -> &mut SegmentStats {
+        self.segments.entry(id).or_insert(SegmentStats {
+            id,
+            ..SegmentStats::default()
+        })
+    }
+
+    /// Dead bytes over all bytes of the given segments.
+    fn dead_ratio(&self, ids: &BTreeSet<u32>) -> f64 {
+        let stats = StoreStats {
+            keys: 0,
+            segments: ids
+                .iter()
+                .filter_map(|id| self.segments.get(id))
+                .cloned()
+                .collect(),
+        };
+        stats.dead_ratio()
+    }
+}
+
+/// Location of a record: its segment, offset and encoded length, along with
+/// the deadline of its value and the write that made it current, 0 for
+/// records found when opening the store.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct LogPointer {
+    segment_id: u32,
+    offset: u64,
+    len: u64,
+    expires_at: Option<u64>,
+    seq: u64,
+}
+
+impl LogPointer {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|deadline| deadline <= now)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Entry {
-    key: String,
-    value: String,
-    tag: Tag,
+/// When sealed segments are merged. The policy is checked every time the
+/// active segment is sealed, and again when a merge finishes if segments were
+/// sealed while it ran.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionPolicy {
+    /// Every time the active segment is sealed.
+    OnSeal,
+    /// Once at least this many sealed segments have piled up.
+    SealedSegments(usize),
+    /// Once this fraction of the bytes in sealed segments is dead: values
+    /// that were overwritten or removed, and tombstones.
+    GarbageRatio(f64),
+    /// Only when `KvStore::compact` is called.
+    Manual,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-enum Tag {
-    Normal,
-    Deleted,
+impl CompactionPolicy {
+    /// Whether the segments `sealed` are due to be merged.
+    fn wants(&self, index: &Index, sealed: &BTreeSet<u32>) -> bool {
+        match *self {
+            CompactionPolicy::OnSeal => true,
+            CompactionPolicy::SealedSegments(n) => sealed.len() >= n,
+            CompactionPolicy::GarbageRatio(ratio) => index.dead_ratio(sealed) >= ratio,
+            CompactionPolicy::Manual => false,
+        }
+    }
 }
 
-const LOG_THRESHOLD: u64 = 4 * 1024 * 1024;
+/// Parses `on-seal`, `segments:<n>`, `garbage:<ratio>` and `manual`.
+impl FromStr for CompactionPolicy {
+    type Err = KvError;
 
-impl Entry {
-    fn new(k: String, v: String, t: Tag) -> Self {
-        Entry {
-            key: k,
-            value: v,
-            tag: t,
+    fn from_str(s: &str) -> Result<CompactionPolicy> {
+        match s {
+            "on-seal" => Ok(CompactionPolicy::OnSeal),
+            "manual" => Ok(CompactionPolicy::Manual),
+            _ => {
+                let segments = s
+                    .strip_prefix("segments:")
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .filter(|&n| n > 0)
+                    .map(CompactionPolicy::SealedSegments);
+                let garbage = s
+                    .strip_prefix("garbage:")
+                    .and_then(|ratio| ratio.parse::<f64>().ok())
+                    .filter(|&ratio| ratio > 0.0 && ratio <= 1.0)
+                    .map(CompactionPolicy::GarbageRatio);
+                segments.or(garbage).ok_or_else(|| {
+                    KvError::InvalidOption(format!("unknown compaction policy: {}", s))
+                })
+            }
         }
     }
 }
 
+/// Settings used when opening a `KvStore`.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    repair: bool,
+    upgrade: bool,
+    durability: Durability,
+    segment_size: u64,
+    compaction: CompactionPolicy,
+    read_buffer_size: usize,
+    create_if_missing: bool,
+    error_if_exists: bool,
+    read_only: bool,
+    reap_interval: Duration,
+    archive: Option<PathBuf>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            repair: false,
+            upgrade: false,
+            durability: Durability::FlushOnly,
+            segment_size: 4 * 1024 * 1024,
+            compaction: CompactionPolicy::GarbageRatio(0.5),
+            read_buffer_size: 8 * 1024,
+            create_if_missing: true,
+            error_if_exists: false,
+            read_only: false,
+            reap_interval: Duration::from_secs(1),
+            archive: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> Self {
+        KvStoreOptions::default()
+    }
+
+    /// Defaults to `Durability::FlushOnly`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Opens the store even if records in the middle of a segment are
+    /// damaged, dropping them and rewriting the segments without them.
+    ///
+    /// A torn record at the end of a segment is always cut off, with or
+    /// without this option.
+    pub fn repair(mut self, repair: bool) -> Self {
+        self.repair = repair;
+        self
+    }
+
+    /// Opens a store written in an older format, upgrading it. A read-only
+    /// store is only upgraded in memory. Without this option, opening a
+    /// store whose format version is not the current one fails with
+    /// `KvError::FormatVersion`.
+    pub fn upgrade(mut self, upgrade: bool) -> Self {
+        self.upgrade = upgrade;
+        self
+    }
+
+    /// Size in bytes at which the active segment is sealed. Defaults to 4 MiB.
+    pub fn segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size;
+        self
+    }
+
+    /// Defaults to `CompactionPolicy::GarbageRatio(0.5)`.
+    pub fn compaction(mut self, compaction: CompactionPolicy) -> Self {
+        self.compaction = compaction;
+        self
+    }
+
+    /// Buffer size of each segment reader. Defaults to 8 KiB.
+    pub fn read_buffer_size(mut self, read_buffer_size: usize) -> Self {
+        self.read_buffer_size = read_buffer_size;
+        self
+    }
+
+    /// Creates a new store if there is none at the path. Defaults to `true`.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Fails the open if a store already exists at the path. Defaults to
+    /// `false`.
+    pub fn error_if_exists(mut self, error_if_exists: bool) -> Self {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    /// Opens an existing store without writing to its directory: writes fail
+    /// with `KvError::ReadOnly` and a torn tail is skipped, not truncated.
+    ///
+    /// Read-only stores share the directory lock, so any number can be open
+    /// at once, and a writer fails to open with `KvError::Locked` while one
+    /// is. If a writer already has the directory, the store opens anyway and
+    /// sees the data as of the open: it keeps every segment open, so the
+    /// writer's merges cannot take them away.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// How often expired keys are looked for and removed. Defaults to one
+    /// second.
+    pub fn reap_interval(mut self, reap_interval: Duration) -> Self {
+        self.reap_interval = reap_interval;
+        self
+    }
+
+    /// Copies every segment filled by writes into `dir` as it is sealed, so
+    /// `archive::restore` can rebuild the store as of any write since. The
+    /// first open with an archive takes a checkpoint to start it from.
+    pub fn archive(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.archive = Some(dir.into());
+        self
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.segment_size == 0 {
+            return Err(KvError::InvalidOption(
+                "segment size must be positive".to_owned(),
+            ));
+        }
+        if self.reap_interval == Duration::from_secs(0) {
+            return Err(KvError::InvalidOption(
+                "reap interval must be positive".to_owned(),
+            ));
+        }
+        if self.read_buffer_size == 0 {
+            return Err(KvError::InvalidOption(
+                "read buffer size must be positive".to_owned(),
+            ));
+        }
+        if let CompactionPolicy::GarbageRatio(ratio) = self.compaction {
+            if !(ratio > 0.0 && ratio <= 1.0) {
+                return Err(KvError::InvalidOption(
+                    "garbage ratio must be in (0, 1]".to_owned(),
+                ));
+            }
+        }
+        if self.read_only && self.repair {
+            return Err(KvError::InvalidOption(
+                "a read-only store cannot be repaired".to_owned(),
+            ));
+        }
+        if self.read_only && self.archive.is_some() {
+            return Err(KvError::InvalidOption(
+                "a read-only store cannot be archived".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
 impl KvStore {
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        options.validate()?;
         let path = path.into();
-        std::fs::create_dir_all(&path)?;
-        let path = PathBuf::from(path);
-        println!("open kvstore: {:?}", path);
-        let mut current = path.clone();
-        current.push("current");
-        let mut log_id: u32 = 0;
-        match File::open(current.as_path()) {
-            Err(_) => {
-                let mut file = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .read(true)
-                    .open(current.as_path())
-                    .unwrap();
-                file.write_u32::<LE>(log_id)?;
-                file.sync_all()?;
-            }
-            Ok(file) => {
-                let mut buf_reader = BufReader::new(&file);
-                log_id = buf_reader.read_u32::<LE>()?;
-            }
-        };
-
-        //println!("kv opened, log_id: {}", log_id);
-        let reader = BufReader::new(open_log(get_log_path(path.clone(), log_id)?)?);
-        let writer = BufWriter::new(open_log(get_log_path(path.clone(), log_id)?)?);
-        let mut kv_store = KvStore {
-            memtable: Box::new(HashMap::new()),
-            immutable: Box::new(HashMap::new()),
-            dir: path,
-            log_id: log_id,
-            reader: reader,
-            writer: writer,
-        };
-        kv_store.load_log()?;
-        Ok(kv_store)
-    }
-
-    fn append_to_memtable(&mut self, entry: &Entry, pos: u64) -> Result<()> {
-        if entry.tag == Tag::Normal {
-            self.memtable.insert(entry.key.clone(), pos);
-        } else if entry.tag == Tag::Deleted {
-            self.memtable.remove(&entry.key);
+        let exists = path.join(MANIFEST_FILE).exists() || path.join(LEGACY_CURRENT_FILE).exists();
+        if exists && options.error_if_exists {
+            return Err(KvError::StoreExists(path.display().to_string()));
+        }
+        if !exists && (options.read_only || !options.create_if_missing) {
+            return Err(KvError::StoreNotFound(path.display().to_string()));
+        }
+        if !options.read_only {
+            std::fs::create_dir_all(&path)?;
+            let lock = DirLock::acquire(&path)?;
+            return KvStore::load(path, &options, Some(lock));
+        }
+        if let Some(lock) = DirLock::acquire_shared(&path)? {
+            return KvStore::load(path, &options, Some(lock));
+        }
+        // A writer has the directory, and a merge of its may remove a segment
+        // between reading the manifest and opening the segment.
+        let mut attempts = 0;
+        loop {
+            match KvStore::load(path.clone(), &options, None) {
+                Err(KvError::IoError(ref e))
+                    if e.kind() == io::ErrorKind::NotFound && attempts < OPEN_ATTEMPTS =>
+                {
+                    attempts += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Opens the store in `path`, which `lock` is held on. Without a lock,
+    /// every segment is kept open so a writer cannot remove it.
+    fn load(path: PathBuf, options: &KvStoreOptions, lock: Option<DirLock>) -> Result<KvStore> {
+        info!("open kvstore: {:?}", path);
+        let (mut manifest, mut rebuilt) = match Manifest::load(&path) {
+            Ok(Some(manifest)) => (manifest, false),
+            // Format version 0 had no manifest, only `current`.
+            Ok(None) if path.join(LEGACY_CURRENT_FILE).exists() => {
+                let mut manifest = rebuild_manifest(&path)?;
+                manifest.version = 0;
+                (manifest, true)
+            }
+            Ok(None) if list_segments(&path)?.is_empty() => (Manifest::new(0), true),
+            Ok(None) if !options.repair => {
+                return Err(KvError::BadManifest("missing".to_owned()));
+            }
+            Ok(None) => (rebuild_manifest(&path)?, true),
+            Err(e) if options.repair => {
+                warn!("{}, rebuilding it from the segments on disk", e);
+                (rebuild_manifest(&path)?, true)
+            }
+            Err(e) => return Err(e),
+        };
+        if manifest.version > FORMAT_VERSION
+            || (manifest.version < FORMAT_VERSION && !options.upgrade)
+        {
+            return Err(KvError::FormatVersion {
+                found: manifest.version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        if manifest.version < FORMAT_VERSION {
+            // Version 1 added the manifest and version 2 the write count in
+            // it, which starts over from 0; records are unchanged.
+            info!(
+                "upgrading from format version {} to {}",
+                manifest.version, FORMAT_VERSION
+            );
+            manifest.version = FORMAT_VERSION;
+            rebuilt = true;
+        }
+        if !options.read_only {
+            if rebuilt {
+                manifest.save(&path)?;
+                remove_file_if_exists(&path.join(LEGACY_CURRENT_FILE))?;
+            } else {
+                remove_orphans(&path, &manifest)?;
+            }
+        }
+
+        // Segments are replayed oldest first so newer records win.
+        let active_id = manifest.active;
+        let mut segments = manifest.segment_ids();
+
+        let dir = Arc::new(path);
+        let mut index = Index::default();
+        let mut damaged = false;
+        let mut active_hints = Vec::new();
+        let mut active_writes = 0;
+        for &segment_id in &segments {
+            // Repair has to look at every record, so it never trusts hints.
+            let hints = if segment_id != active_id && !options.repair {
+                hint::read_hint(&get_hint_path(&dir, segment_id))?
+            } else {
+                None
+            };
+            let hints = match hints {
+                Some(hints) => hints,
+                None => {
+                    let (hints, segment_damaged, writes) = scan_segment(&dir, segment_id, options)?;
+                    damaged |= segment_damaged;
+                    if segment_id == active_id {
+                        active_writes = writes;
+                    }
+                    hints
+                }
+            };
+            index.segment(segment_id);
+            for hint in &hints {
+                index.apply(segment_id, hint, 0);
+            }
+            if segment_id == active_id {
+                active_hints = hints;
+            }
+        }
+        let pinned = match lock {
+            Some(_) => BTreeMap::new(),
+            None => pin_segments(&dir, &segments, options.read_buffer_size)?,
+        };
+        segments.remove(&active_id);
+        let seq = manifest.seq + active_writes;
+        index.seq = seq;
+
+        let index = Arc::new(RwLock::new(index));
+        let oldest = segments.iter().next().map_or(active_id, |&id| id);
+        let safe_point = Arc::new(AtomicU32::new(oldest));
+        let reader = KvStoreReader {
+            dir: Arc::clone(&dir),
+            safe_point: Arc::clone(&safe_point),
+            buffer_size: options.read_buffer_size,
+            readers: RefCell::new(BTreeMap::new()),
+            pinned: Arc::new(pinned),
+            _lock: Arc::new(lock),
+        };
+        if options.read_only {
+            return Ok(KvStore {
+                _background: Arc::new(Vec::new()),
+                index,
+                reader,
+                writer: None,
+                durability: options.durability,
+                group_commit: Arc::new(GroupCommit::default()),
+                unflushed: Arc::new(AtomicBool::new(false)),
+                syncs: Arc::new(SyncCounter::default()),
+            });
+        }
+        let archive = match options.archive {
+            Some(ref dir) => Some(Archive::open(dir, manifest.seq)?),
+            None => None,
+        };
+        let mut writer = KvStoreWriter {
+            active_len: log_size(get_log_path(&dir, active_id))?,
+            writer: BufWriter::new(open_log(get_log_path(&dir, active_id))?),
+            dir,
+            active_id,
+            active_hints,
+            durability: options.durability,
+            segment_size: options.segment_size,
+            compaction: options.compaction,
+            seq,
+            unflushed: Arc::new(AtomicBool::new(false)),
+            syncs: Arc::new(SyncCounter::default()),
+            reader: reader.clone(),
+            index: Arc::clone(&index),
+            manifest: Arc::new(Mutex::new(manifest)),
+            safe_point,
+            compacting: Arc::new(AtomicBool::new(false)),
+            deferred: Arc::new(Mutex::new(None)),
+            compactor: None,
+            archive,
+        };
+        if damaged {
+            // Leave the damaged segments behind so the next open finds clean
+            // ones.
+            writer.compact()?;
+        }
+        let unflushed = Arc::clone(&writer.unflushed);
+        let syncs = Arc::clone(&writer.syncs);
+        let writer = Arc::new(Mutex::new(writer));
+        let mut background = Vec::new();
+        if let Durability::FsyncEvery(interval) = options.durability {
+            background.push(Periodic::spawn(&writer, interval, |writer| {
+                if let Err(e) = writer.sync() {
+                    error!("periodic fsync failed: {}", e);
+                }
+            }));
+        }
+        background.push(Periodic::spawn(&writer, options.reap_interval, |writer| {
+            if let Err(e) = writer.reap_expired() {
+                error!("removing expired keys failed: {}", e);
+            }
+        }));
+        let store = KvStore {
+            _background: Arc::new(background),
+            index,
+            reader,
+            writer: Some(writer),
+            durability: options.durability,
+            group_commit: Arc::new(GroupCommit::default()),
+            unflushed,
+            syncs,
+        };
+        if let Some(ref dir) = options.archive {
+            archive::start(dir, &store)?;
+        }
+        Ok(store)
+    }
+
+    /// Seals the active segment and merges every sealed segment, returning
+    /// once the merge is done. This is the only way segments get merged under
+    /// `CompactionPolicy::Manual`.
+    pub fn compact(&self) -> Result<()> {
+        self.writer()?.lock().unwrap().compact()
+    }
+
+    /// Live and dead bytes of every segment.
+    pub fn stats(&self) -> StoreStats {
+        let index = self.index.read().unwrap();
+        StoreStats {
+            keys: index.keys.len(),
+            segments: index.segments.values().cloned().collect(),
+        }
+    }
+
+    /// Flushes and fsyncs made since the store was opened, sealing and
+    /// merging segments included.
+    pub fn sync_stats(&self) -> SyncStats {
+        self.syncs.stats()
+    }
+
+    fn flush_if_needed(&self) -> Result<()> {
+        if self.unflushed.load(Ordering::SeqCst) {
+            self.writer()?.lock().unwrap().flush()?;
         }
         Ok(())
     }
 
-    fn start_write(&mut self, key: String, val: String, tag: Tag) -> Result<()> {
-        let entry = Entry::new(key, val, tag);
-        let pos = append_entry(&mut self.writer, &entry)?;
-        self.append_to_memtable(&entry, pos)?;
+    /// Writes a checkpoint to `dest` and returns the number of writes it
+    /// holds.
+    pub(crate) fn checkpoint_seq(&self, dest: &Path) -> Result<u64> {
+        let writer = self.writer()?;
+        backup::create_empty_dir(dest)?;
+        // Compactions keep their inputs on disk while a snapshot is open.
+        let pin = self.snapshot()?;
+        let (manifest, active_len, seq) = {
+            let mut writer = writer.lock().unwrap();
+            writer.flush()?;
+            let manifest = writer.manifest.lock().unwrap().clone();
+            (manifest, writer.active_len, writer.seq)
+        };
+        let dir = &self.reader.dir;
+        for &id in manifest.segments.keys() {
+            backup::link_or_copy(&get_log_path(dir, id), &get_log_path(dest, id))?;
+            let hint_path = get_hint_path(dir, id);
+            if hint_path.exists() {
+                backup::link_or_copy(&hint_path, &get_hint_path(dest, id))?;
+            }
+        }
+        // The active segment keeps growing: only the records written before
+        // the writer was released belong to the checkpoint.
+        backup::copy_prefix(
+            &get_log_path(dir, manifest.active),
+            &get_log_path(dest, manifest.active),
+            active_len,
+        )?;
+        manifest.save(dest)?;
+        marker::record_engine(dest, "kvs")?;
+        drop(pin);
+        Ok(seq)
+    }
+
+    /// Appends `entries`, read from another store's log, as one write.
+    pub(crate) fn append_entries(&self, entries: Vec<Entry>) -> Result<()> {
+        let seq = self.writer()?.lock().unwrap().append(entries)?;
+        self.commit(seq)
+    }
+
+    /// Reads the value of `key` at `pointer`. Returns `None` if the key was
+    /// removed since the index was consulted.
+    fn read_value(&self, key: &[u8], mut pointer: LogPointer) -> Result<Option<Vec<u8>>> {
+        loop {
+            match self.reader.read_entry(pointer) {
+                Ok(entry) => return Ok(Some(entry.value)),
+                // The segment was compacted away between the index lookup and
+                // the read; the index now points into a newer one.
+                Err(KvError::IoError(ref e))
+                    if e.kind() == io::ErrorKind::NotFound
+                        && pointer.segment_id < self.reader.safe_point.load(Ordering::SeqCst) =>
+                {
+                    pointer = match self.index.read().unwrap().live(key) {
+                        Some(pointer) => pointer,
+                        None => return Ok(None),
+                    };
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Writes the value of `key` again with a new deadline.
+    fn rewrite_deadline(&self, key: &[u8], expires_at: Option<u64>) -> Result<()> {
+        let seq = {
+            let mut writer = self.writer()?.lock().unwrap();
+            if self.unflushed.load(Ordering::SeqCst) {
+                writer.flush()?;
+            }
+            let pointer = self
+                .index
+                .read()
+                .unwrap()
+                .live(key)
+                .ok_or(KvError::KeyNotExit)?;
+            if pointer.expires_at == expires_at {
+                return Ok(());
+            }
+            let value = self.read_value(key, pointer)?.ok_or(KvError::KeyNotExit)?;
+            writer.append(vec![Entry::expiring(key.to_vec(), value, expires_at)])?
+        };
+        self.commit(seq)
+    }
+
+    /// The value of `key` for a caller holding the writer, which keeps it
+    /// from changing until the writer is released.
+    fn current_value(&self, writer: &mut KvStoreWriter, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.unflushed.load(Ordering::SeqCst) {
+            writer.flush()?;
+        }
+        let pointer = self.index.read().unwrap().live(key);
+        match pointer {
+            Some(pointer) => self.read_value(key, pointer),
+            None => Ok(None),
+        }
+    }
+
+    fn writer(&self) -> Result<&Mutex<KvStoreWriter>> {
+        self.writer.as_deref().ok_or(KvError::ReadOnly)
+    }
+
+    /// Makes write `seq` as durable as the store's `Durability` asks for.
+    /// Only group commit has anything left to do once the writer returns.
+    fn commit(&self, seq: u64) -> Result<()> {
+        if self.durability != Durability::GroupCommit {
+            return Ok(());
+        }
+        self.group_commit.wait(seq, || {
+            // Sync a handle of our own so writers can keep appending while
+            // the fsync runs.
+            let (file, covered) = {
+                let writer = self.writer()?.lock().unwrap();
+                (writer.writer.get_ref().try_clone()?, writer.seq)
+            };
+            file.sync_data()?;
+            self.syncs.synced();
+            Ok(covered)
+        })
+    }
+}
+
+impl KvsEngine for KvStore {
+    type Snapshot = KvSnapshot;
+
+    fn snapshot(&self) -> Result<KvSnapshot> {
+        let (id, seq) = {
+            let mut index = self.index.write().unwrap();
+            let id = index.next_snapshot_id;
+            index.next_snapshot_id += 1;
+            let seq = index.seq;
+            index.snapshots.insert(id, seq);
+            (id, seq)
+        };
+        let dir = Arc::clone(&self.reader.dir);
+        Ok(KvSnapshot {
+            store: self.clone(),
+            // Never closes its readers: the segments it reads stay on disk
+            // until the snapshot is released, even once compacted.
+            reader: KvStoreReader {
+                dir: Arc::clone(&dir),
+                safe_point: Arc::new(AtomicU32::new(0)),
+                buffer_size: self.reader.buffer_size,
+                readers: RefCell::new(BTreeMap::new()),
+                pinned: Arc::clone(&self.reader.pinned),
+                _lock: Arc::clone(&self.reader._lock),
+            },
+            pin: Arc::new(SnapshotPin {
+                index: Arc::clone(&self.index),
+                dir,
+                id,
+                seq,
+            }),
+        })
+    }
+
+    /// Sealed segments are hard-linked where possible, so a checkpoint on
+    /// the same filesystem takes little space or time. Fails with
+    /// `KvError::ReadOnly` on a read-only store.
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        self.checkpoint_seq(dest).map(|_| ())
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        let seq = self.writer()?.lock().unwrap().set(key, val)?;
+        self.commit(seq)
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.flush_if_needed()?;
+        let pointer = match self.index.read().unwrap().live(key) {
+            Some(pointer) => pointer,
+            None => return Ok(None),
+        };
+        self.read_value(key, pointer)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let entry = Entry::expiring(key, value, Some(expiry::deadline(ttl)));
+        let seq = self.writer()?.lock().unwrap().append(vec![entry])?;
+        self.commit(seq)
+    }
+
+    fn expire(&self, key: &[u8], ttl: Duration) -> Result<()> {
+        self.rewrite_deadline(key, Some(expiry::deadline(ttl)))
+    }
+
+    fn persist(&self, key: &[u8]) -> Result<()> {
+        self.rewrite_deadline(key, None)
+    }
+
+    fn ttl(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let pointer = self
+            .index
+            .read()
+            .unwrap()
+            .live(key)
+            .ok_or(KvError::KeyNotExit)?;
+        Ok(pointer
+            .expires_at
+            .map(|deadline| expiry::remaining(deadline).unwrap_or_default()))
+    }
+
+    fn deadline(&self, key: &[u8]) -> Result<Option<u64>> {
+        let pointer = self
+            .index
+            .read()
+            .unwrap()
+            .live(key)
+            .ok_or(KvError::KeyNotExit)?;
+        Ok(pointer.expires_at)
+    }
+
+    fn scan_page(&self, range: KeyRange, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.flush_if_needed()?;
+        if scan::is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let now = expiry::now();
+        let pointers: Vec<(Vec<u8>, LogPointer)> = self
+            .index
+            .read()
+            .unwrap()
+            .keys
+            .range(range)
+            .filter(|(_, pointer)| !pointer.is_expired(now))
+            .take(limit)
+            .map(|(key, pointer)| (key.clone(), *pointer))
+            .collect();
+        let mut pairs = Vec::with_capacity(pointers.len());
+        for (key, pointer) in pointers {
+            if let Some(value) = self.read_value(&key, pointer)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let seq = self.writer()?.lock().unwrap().remove(key)?;
+        self.commit(seq)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let seq = self.writer()?.lock().unwrap().write_batch(batch)?;
+        self.commit(seq)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let seq = {
+            // Holding the writer keeps the value from changing between the
+            // comparison and the write.
+            let mut writer = self.writer()?.lock().unwrap();
+            let current = self.current_value(&mut writer, &key)?;
+            if current != expected {
+                return Err(KvError::Conflict);
+            }
+            match (new, current) {
+                (Some(value), _) => writer.set(key, value)?,
+                (None, Some(_)) => writer.remove(&key)?,
+                (None, None) => return Ok(()),
+            }
+        };
+        self.commit(seq)
+    }
+
+    fn compare_and_write_batch(
+        &self,
+        expected: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let seq = {
+            let mut writer = self.writer()?.lock().unwrap();
+            for (key, value) in expected {
+                if self.current_value(&mut writer, &key)? != value {
+                    return Err(KvError::Conflict);
+                }
+            }
+            writer.write_batch(batch)?
+        };
+        self.commit(seq)
+    }
+}
+
+/// A snapshot of a `KvStore`.
+#[derive(Clone)]
+pub struct KvSnapshot {
+    store: KvStore,
+    reader: KvStoreReader,
+    pin: Arc<SnapshotPin>,
+}
+
+/// Keeps a snapshot registered in the index until the last handle is gone.
+struct SnapshotPin {
+    index: Arc<RwLock<Index>>,
+    dir: Arc<PathBuf>,
+    id: u64,
+    seq: u64,
+}
+
+impl Drop for SnapshotPin {
+    fn drop(&mut self) {
+        let released = self.index.write().unwrap().release(self.id);
+        for id in released {
+            if let Err(e) = remove_segment(&self.dir, id) {
+                error!("failed to remove compacted segment {}: {}", id, e);
+            }
+        }
+    }
+}
+
+impl KvsSnapshot for KvSnapshot {
+    fn seq(&self) -> u64 {
+        self.pin.seq
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.store.flush_if_needed()?;
+        let pointer = self
+            .store
+            .index
+            .read()
+            .unwrap()
+            .version_at(key, self.pin.seq);
+        match pointer {
+            Some(pointer) if !pointer.is_expired(expiry::now()) => {
+                Ok(Some(self.reader.read_entry(pointer)?.value))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn scan_page(&self, range: KeyRange, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.store.flush_if_needed()?;
+        if scan::is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let pointers = self
+            .store
+            .index
+            .read()
+            .unwrap()
+            .versions_in(range, self.pin.seq, limit);
+        pointers
+            .into_iter()
+            .map(|(key, pointer)| Ok((key, self.reader.read_entry(pointer)?.value)))
+            .collect()
+    }
+}
+
+impl ScanSource for KvSnapshot {
+    fn fetch_page(&self, range: KeyRange, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_page(range, limit)
+    }
+}
+
+/// Per-handle segment readers.
+///
+/// Readers are opened lazily and dropped once compaction moves the
+/// `safe_point` past their segment. Every segment below the safe point has
+/// been merged away.
+struct KvStoreReader {
+    dir: Arc<PathBuf>,
+    safe_point: Arc<AtomicU32>,
+    buffer_size: usize,
+    readers: RefCell<BTreeMap<u32, BufReader<File>>>,
+    // Every segment of a read-only store opened beside a writer, opened up
+    // front and shared by all clones.
+    pinned: Arc<BTreeMap<u32, Mutex<BufReader<File>>>>,
+    // Only held: the directory stays locked until every reader is gone,
+    // including the writer's, which is dropped after its last sync.
+    _lock: Arc<Option<DirLock>>,
+}
+
+impl KvStoreReader {
+    fn close_stale_readers(&self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        let mut readers = self.readers.borrow_mut();
+        *readers = readers.split_off(&safe_point);
+    }
+
+    fn read_entry(&self, pointer: LogPointer) -> Result<Entry> {
+        if let Some(reader) = self.pinned.get(&pointer.segment_id) {
+            return read_entry(&mut reader.lock().unwrap(), pointer);
+        }
+        self.close_stale_readers();
+        let mut readers = self.readers.borrow_mut();
+        let reader = match readers.entry(pointer.segment_id) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => {
+                let file = File::open(get_log_path(&self.dir, pointer.segment_id))?;
+                entry.insert(BufReader::with_capacity(self.buffer_size, file))
+            }
+        };
+        read_entry(reader, pointer)
+    }
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        KvStoreReader {
+            dir: Arc::clone(&self.dir),
+            safe_point: Arc::clone(&self.safe_point),
+            buffer_size: self.buffer_size,
+            readers: RefCell::new(BTreeMap::new()),
+            pinned: Arc::clone(&self.pinned),
+            _lock: Arc::clone(&self._lock),
+        }
+    }
+}
+
+/// The single writer shared by every clone of a `KvStore`.
+struct KvStoreWriter {
+    dir: Arc<PathBuf>,
+    active_id: u32,
+    active_len: u64,
+    // Every record in the active segment, written out as its hint once sealed.
+    active_hints: Vec<HintEntry>,
+    durability: Durability,
+    segment_size: u64,
+    compaction: CompactionPolicy,
+    // Number of writes appended so far.
+    seq: u64,
+    unflushed: Arc<AtomicBool>,
+    syncs: Arc<SyncCounter>,
+    writer: BufWriter<File>,
+    reader: KvStoreReader,
+    index: Arc<RwLock<Index>>,
+    // Saved whenever the set of segments changes.
+    manifest: Arc<Mutex<Manifest>>,
+    safe_point: Arc<AtomicU32>,
+    compacting: Arc<AtomicBool>,
+    // The id set aside for a merge wanted while another one was running.
+    deferred: Arc<Mutex<Option<u32>>>,
+    compactor: Option<JoinHandle<()>>,
+    // Where sealed segments are copied, in archive mode.
+    archive: Option<Archive>,
+}
+
+impl KvStoreWriter {
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<u64> {
+        self.start_write(key, val, Tag::Normal)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<u64> {
+        if self.index.read().unwrap().live(key).is_none() {
+            return Err(KvError::KeyNotExit);
+        }
+        self.start_write(key.to_vec(), Vec::new(), Tag::Deleted)
+    }
+
+    /// Appends a record and returns its write number.
+    fn start_write(&mut self, key: Vec<u8>, val: Vec<u8>, tag: Tag) -> Result<u64> {
+        self.append(vec![Entry::new(key, val, tag)])
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<u64> {
+        let entries: Vec<Entry> = batch
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(key, value) => Entry::new(key, value, Tag::Normal),
+                BatchOp::Remove(key) => Entry::new(key, Vec::new(), Tag::Deleted),
+            })
+            .collect();
+        if entries.is_empty() {
+            return Ok(self.seq);
+        }
+        self.append(entries)
+    }
+
+    /// Appends `entries` as one unit and returns its write number. Several
+    /// entries are framed as a batch record, which recovery applies whole or
+    /// not at all.
+    fn append(&mut self, entries: Vec<Entry>) -> Result<u64> {
+        let offset = self.active_len;
+        let (buf, mut entry_offset) = if entries.len() == 1 {
+            (entries[0].encode(), offset)
+        } else {
+            (record::encode_batch(&entries), offset + record::HEADER_LEN)
+        };
+        self.writer.write_all(&buf)?;
+        match self.durability {
+            Durability::None => self.unflushed.store(true, Ordering::SeqCst),
+            Durability::FsyncEveryWrite => self.sync()?,
+            _ => self.flush()?,
+        }
+        self.seq += 1;
+        self.active_len = offset + buf.len() as u64;
+        if let Some(ref mut archive) = self.archive {
+            archive.note_write(self.seq)?;
+        }
+
+        let hints: Vec<HintEntry> = entries
+            .into_iter()
+            .map(|entry| {
+                let hint = HintEntry {
+                    len: entry.encoded_len(),
+                    key: entry.key,
+                    offset: entry_offset,
+                    tag: entry.tag,
+                    expires_at: entry.expires_at,
+                };
+                entry_offset += hint.len;
+                hint
+            })
+            .collect();
+        // Readers see the whole batch or none of it.
+        {
+            let mut index = self.index.write().unwrap();
+            for hint in &hints {
+                index.apply(self.active_id, hint, self.seq);
+            }
+        }
+        self.active_hints.extend(hints);
 
-        if log_size(get_log_path(self.dir.clone(), self.log_id)?)? >= LOG_THRESHOLD {
-            self.compaction()?;
+        if self.active_len >= self.segment_size {
+            self.seal_active(false)?;
         }
+        Ok(self.seq)
+    }
+
+    /// Writes tombstones for every key whose deadline has passed.
+    fn reap_expired(&mut self) -> Result<()> {
+        loop {
+            let now = expiry::now();
+            let expired: Vec<Entry> = self
+                .index
+                .read()
+                .unwrap()
+                .expiries
+                .iter()
+                .take_while(|(deadline, _)| *deadline <= now)
+                .take(REAP_BATCH)
+                .map(|(_, key)| Entry::new(key.clone(), Vec::new(), Tag::Deleted))
+                .collect();
+            if expired.is_empty() {
+                return Ok(());
+            }
+            debug!("removing {} expired keys", expired.len());
+            let done = expired.len() < REAP_BATCH;
+            self.append(expired)?;
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.unflushed.store(false, Ordering::SeqCst);
+        self.syncs.flushed();
         Ok(())
     }
 
-    fn compaction(&mut self) -> Result<()> {
-        // println!(
-        //     "start compaction, current log_id: {}, append_num: {}",
-        //     self.log_id, self.append_num
-        // );
-        self.log_id += 1;
-        let mut new_writer =
-            BufWriter::new(open_log(get_log_path(self.dir.clone(), self.log_id)?)?);
-        let mut new_mem: Box<HashMap<String, u64>> = Box::new(HashMap::new());
-
-        for (_, pointer) in self.memtable.iter() {
-            let entry = read_entry(&mut self.reader, *pointer)?;
-            let pos = append_entry(&mut new_writer, &entry)?;
-            if entry.tag == Tag::Normal {
-                new_mem.insert(entry.key.clone(), pos);
-            } else if entry.tag == Tag::Deleted {
-                new_mem.remove(&entry.key);
-            }
-        }
-        self.memtable = new_mem;
-        self.writer = new_writer;
-        self.reader = BufReader::new(open_log(get_log_path(self.dir.clone(), self.log_id)?)?);
-        std::fs::remove_file(get_log_path(self.dir.clone(), self.log_id - 1)?)?;
-        self.update_current()?;
+    fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.syncs.synced();
         Ok(())
     }
 
-    fn load_log(&mut self) -> Result<()> {
-        loop {
-            let pos = self.reader.seek(SeekFrom::Current(0))?;
-            let entry = match read_entry(&mut self.reader, pos) {
-                Ok(entry) => entry,
-                Err(_) => break,
+    /// Seals the active segment and starts a new one. A background
+    /// compaction of every sealed segment is kicked off if `force` is set or
+    /// the compaction policy asks for one, unless one is already running.
+    ///
+    /// The compaction output takes the id right after the sealed segment, so
+    /// it is replayed after its inputs and before anything written later.
+    fn seal_active(&mut self, force: bool) -> Result<()> {
+        self.sync()?;
+        let sealed_id = self.active_id;
+        // Archived before the manifest moves on, so a crash in between
+        // archives it again later, never not at all.
+        let first = self.manifest.lock().unwrap().seq + 1;
+        if let Some(ref archive) = self.archive {
+            if self.seq >= first {
+                archive.add_segment(&get_log_path(&self.dir, sealed_id), first, self.seq)?;
+            }
+        }
+        let mut sealed = self.manifest.lock().unwrap().sealed();
+        sealed.insert(sealed_id);
+        let wanted = force || self.compaction.wants(&self.index.read().unwrap(), &sealed);
+        // Held until the segment is in the manifest, so a running merge can
+        // not finish in between without seeing the one deferred here.
+        let deferred = Arc::clone(&self.deferred);
+        let mut deferred = deferred.lock().unwrap();
+        let compact = wanted && !self.compacting.swap(true, Ordering::SeqCst);
+        let hints = std::mem::take(&mut self.active_hints);
+        if !compact {
+            // A segment about to be merged is not worth a hint of its own.
+            hint::write_hint(&get_hint_path(&self.dir, sealed_id), &hints)?;
+        }
+        // The id after the sealed segment is kept for the merge, whether it
+        // runs now or after the one running.
+        self.active_id = if wanted { sealed_id + 2 } else { sealed_id + 1 };
+        self.writer = BufWriter::new(open_log(get_log_path(&self.dir, self.active_id))?);
+        self.active_len = 0;
+        self.index.write().unwrap().segment(self.active_id);
+        {
+            let mut manifest = self.manifest.lock().unwrap();
+            manifest.segments.insert(sealed_id, 0);
+            manifest.active = self.active_id;
+            manifest.seq = self.seq;
+            manifest.save(&self.dir)?;
+        }
+        if wanted && !compact {
+            *deferred = Some(sealed_id + 1);
+        }
+        drop(deferred);
+
+        if compact {
+            self.wait_for_compaction();
+            let compaction = Compaction {
+                dir: Arc::clone(&self.dir),
+                inputs: self.manifest.lock().unwrap().sealed(),
+                output: sealed_id + 1,
+                reader: self.reader.clone(),
+                index: Arc::clone(&self.index),
+                manifest: Arc::clone(&self.manifest),
+                safe_point: Arc::clone(&self.safe_point),
             };
-            self.append_to_memtable(&entry, pos)?;
+            let policy = self.compaction;
+            let compacting = Arc::clone(&self.compacting);
+            let deferred = Arc::clone(&self.deferred);
+            self.compactor = Some(thread::spawn(move || {
+                let mut next = Some(compaction.clone());
+                loop {
+                    if let Some(merge) = next.take() {
+                        if let Err(e) = merge.run() {
+                            error!("compaction failed: {}", e);
+                        }
+                    }
+                    // Segments sealed meanwhile wait for this merge, then
+                    // get the policy checked again.
+                    let output = match deferred.lock().unwrap().take() {
+                        Some(output) => output,
+                        None => {
+                            compacting.store(false, Ordering::SeqCst);
+                            return;
+                        }
+                    };
+                    next = compaction.follow_up(policy, output);
+                }
+            }));
         }
         Ok(())
     }
 
-    fn update_current(&self) -> Result<()> {
-        // println!("update current, log_id: {}", self.log_id);
-        let mut current = self.dir.clone();
-        current.push("current");
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(current.as_path())
-            .unwrap();
-        file.write_u32::<LE>(self.log_id).expect("write_u32 failed");
-        file.sync_all().expect("sync_all failed");
+    fn compact(&mut self) -> Result<()> {
+        self.wait_for_compaction();
+        self.seal_active(true)?;
+        self.wait_for_compaction();
         Ok(())
     }
+
+    fn wait_for_compaction(&mut self) {
+        if let Some(compactor) = self.compactor.take() {
+            if compactor.join().is_err() {
+                error!("compaction thread panicked");
+            }
+        }
+    }
 }
 
-impl KvsEngine for KvStore {
-    fn set(&mut self, key: String, val: String) -> Result<()> {
-        //println!("set {}, {}", key, val);
-        self.start_write(key, val, Tag::Normal)?;
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        info!("kv dropped");
+        if let Err(e) = self.sync() {
+            error!("failed to sync the active segment: {}", e);
+        }
+        self.wait_for_compaction();
+    }
+}
+
+/// A thread running a task on the writer at a fixed interval. It stops, and
+/// is joined, when the last store handle is dropped, so the writer is never
+/// dropped, and the directory unlocked, from the thread itself.
+struct Periodic {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Periodic {
+    fn spawn(
+        writer: &Arc<Mutex<KvStoreWriter>>,
+        interval: Duration,
+        task: impl Fn(&mut KvStoreWriter) + Send + 'static,
+    ) -> Periodic {
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let writer = Arc::clone(writer);
+        let handle = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => task(&mut writer.lock().unwrap()),
+                _ => return,
+            }
+        });
+        Periodic {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("background thread panicked");
+            }
+        }
+    }
+}
+
+/// A merge of sealed segments into a single new one.
+#[derive(Clone)]
+struct Compaction {
+    dir: Arc<PathBuf>,
+    inputs: BTreeSet<u32>,
+    output: u32,
+    reader: KvStoreReader,
+    index: Arc<RwLock<Index>>,
+    manifest: Arc<Mutex<Manifest>>,
+    safe_point: Arc<AtomicU32>,
+}
+
+impl Compaction {
+    /// A merge into `output` of every sealed segment before it, if `policy`
+    /// still wants one.
+    fn follow_up(&self, policy: CompactionPolicy, output: u32) -> Option<Compaction> {
+        let inputs: BTreeSet<u32> = self
+            .manifest
+            .lock()
+            .unwrap()
+            .sealed()
+            .range(..output)
+            .cloned()
+            .collect();
+        if inputs.is_empty() || !policy.wants(&self.index.read().unwrap(), &inputs) {
+            return None;
+        }
+        Some(Compaction {
+            inputs,
+            output,
+            ..self.clone()
+        })
+    }
+
+    fn run(self) -> Result<()> {
+        let output_path = get_log_path(&self.dir, self.output);
+        let mut retired = false;
+        // The output holds every live record of the inputs, so once the
+        // manifest lists it in their place the inputs are no longer needed.
+        let installed = self.copy_live_entries(&output_path).and_then(|copied| {
+            let mut manifest = self.manifest.lock().unwrap();
+            let mut next = manifest.clone();
+            next.generation += 1;
+            for id in &self.inputs {
+                next.segments.remove(id);
+            }
+            next.segments.insert(self.output, next.generation);
+            next.save(&self.dir)?;
+            *manifest = next;
+            Ok(copied)
+        });
+        let (moved, expired) = match installed {
+            Ok(copied) => copied,
+            Err(e) => {
+                let _ = std::fs::remove_file(&output_path);
+                let _ = remove_hint(&self.dir, self.output);
+                return Err(e);
+            }
+        };
+
+        // Keys written or removed since the copy began keep their newer
+        // state; only pointers still into the inputs are moved, and the
+        // copies of the others are dead on arrival.
+        {
+            let mut index = self.index.write().unwrap();
+            index.segment(self.output);
+            for (key, old, new) in moved {
+                let installed = match index.keys.get_mut(&key) {
+                    Some(pointer) if *pointer == old => {
+                        *pointer = new;
+                        true
+                    }
+                    _ => false,
+                };
+                let stats = index.segment(self.output);
+                if installed {
+                    stats.live_bytes += new.len;
+                } else {
+                    stats.dead_bytes += new.len;
+                }
+            }
+            // Every record of an expired key is in the inputs, so it can go
+            // without a tombstone.
+            for (key, old) in expired {
+                if index.keys.get(&key) == Some(&old) {
+                    index.forget(&key);
+                }
+            }
+            for id in &self.inputs {
+                index.segments.remove(id);
+            }
+            if !index.snapshots.is_empty() {
+                let first_unaffected = index.next_snapshot_id;
+                index
+                    .retired
+                    .extend(self.inputs.iter().map(|&id| (first_unaffected, id)));
+                retired = true;
+            }
+        }
+        self.safe_point.store(self.output, Ordering::SeqCst);
+        self.reader.close_stale_readers();
+
+        if retired {
+            info!(
+                "keeping segments {:?} until open snapshots are released",
+                self.inputs
+            );
+            return Ok(());
+        }
+        // Oldest first, so a crash part way never leaves a tombstone removed
+        // while the value it hides survives.
+        for id in &self.inputs {
+            remove_segment(&self.dir, *id)?;
+        }
+        info!(
+            "compacted segments {:?} into log_{}",
+            self.inputs, self.output
+        );
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        println!("get {}", key);
-        match self.memtable.get(&key) {
-            Some(pos) => Ok(Some(read_entry(&mut self.reader, pos.clone())?.value)),
-            None => Ok(None),
+    /// Copies the live records of the inputs into the output. Returns the
+    /// records moved and the expired ones left behind.
+    #[allow(clippy::type_complexity)]
+    fn copy_live_entries(
+        &self,
+        output_path: &Path,
+    ) -> Result<(
+        Vec<(Vec<u8>, LogPointer, LogPointer)>,
+        Vec<(Vec<u8>, LogPointer)>,
+    )> {
+        let now = expiry::now();
+        let (expired, live): (Vec<_>, Vec<_>) = self
+            .index
+            .read()
+            .unwrap()
+            .keys
+            .iter()
+            .filter(|(_, pointer)| self.inputs.contains(&pointer.segment_id))
+            .map(|(key, pointer)| (key.clone(), *pointer))
+            .partition(|(_, pointer)| pointer.is_expired(now));
+
+        let mut writer = BufWriter::new(open_log(output_path.to_path_buf())?);
+        let mut moved = Vec::with_capacity(live.len());
+        let mut offset = 0;
+        for (key, old) in live {
+            let entry = self.reader.read_entry(old)?;
+            writer.write_all(&entry.encode())?;
+            let new = LogPointer {
+                segment_id: self.output,
+                offset,
+                ..old
+            };
+            offset += old.len;
+            moved.push((key, old, new));
         }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        let hints: Vec<HintEntry> = moved
+            .iter()
+            .map(|(key, _, new)| HintEntry {
+                key: key.clone(),
+                offset: new.offset,
+                len: new.len,
+                tag: Tag::Normal,
+                expires_at: new.expires_at,
+            })
+            .collect();
+        hint::write_hint(&get_hint_path(&self.dir, self.output), &hints)?;
+        Ok((moved, expired))
     }
+}
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        match self.memtable.get(&key) {
-            Some(_) => {
-                self.start_write(key.to_owned(), "".to_owned(), Tag::Deleted)?;
-                Ok(())
+/// Reads every record of segment `segment_id`, returning them as hints along
+/// with whether any damage was skipped and how many writes they came from.
+///
+/// A damaged record with nothing intact after it is a torn write from a
+/// crash, and the segment is truncated back to the last valid record. Damage
+/// followed by intact records fails the open unless `repair` is set, in which
+/// case the damaged ranges are skipped. A read-only store leaves the segment
+/// untouched and stops at the damage.
+fn scan_segment(
+    dir: &Path,
+    segment_id: u32,
+    options: &KvStoreOptions,
+) -> Result<(Vec<HintEntry>, bool, u64)> {
+    let path = get_log_path(dir, segment_id);
+    let file = if options.read_only {
+        match File::open(&path) {
+            Ok(file) => file,
+            // The active segment may not have been written to yet.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), false, 0)),
+            Err(e) => return Err(e.into()),
+        }
+    } else {
+        open_log(path.clone())?
+    };
+    let mut reader = BufReader::with_capacity(options.read_buffer_size, file);
+    let mut hints = Vec::new();
+    let mut damaged = false;
+    let mut writes = 0;
+    let mut offset = 0;
+    loop {
+        match record::read_record(&mut reader, segment_id, offset) {
+            Ok(Some(entry)) => {
+                let len = entry.encoded_len();
+                let entries = if entry.tag == Tag::Batch {
+                    record::decode_batch(entry, segment_id, offset)?
+                } else {
+                    vec![(offset, entry)]
+                };
+                for (entry_offset, entry) in entries {
+                    hints.push(HintEntry {
+                        len: entry.encoded_len(),
+                        key: entry.key,
+                        offset: entry_offset,
+                        tag: entry.tag,
+                        expires_at: entry.expires_at,
+                    });
+                }
+                writes += 1;
+                offset += len;
             }
-            None => Err(KvError::KeyNotExit),
+            Ok(None) => break,
+            Err(err @ KvError::Corruption { .. }) => {
+                match record::find_next_record(&mut reader, segment_id, offset + 1)? {
+                    None if options.read_only => {
+                        warn!(
+                            "log_{}: ignoring torn record at offset {}",
+                            segment_id, offset
+                        );
+                        break;
+                    }
+                    None => {
+                        let len = log_size(path.clone())?;
+                        warn!(
+                            "log_{}: dropping torn record at offset {}, truncating {} bytes",
+                            segment_id,
+                            offset,
+                            len - offset
+                        );
+                        let file = OpenOptions::new().write(true).open(&path)?;
+                        file.set_len(offset)?;
+                        file.sync_all()?;
+                        break;
+                    }
+                    Some(next) if options.repair => {
+                        warn!(
+                            "log_{}: dropping damaged bytes {}..{} ({} bytes)",
+                            segment_id,
+                            offset,
+                            next,
+                            next - offset
+                        );
+                        damaged = true;
+                        reader.seek(SeekFrom::Start(next))?;
+                        offset = next;
+                    }
+                    Some(next) => {
+                        error!(
+                            "log_{}: damaged bytes {}..{} followed by valid records",
+                            segment_id, offset, next
+                        );
+                        return Err(err);
+                    }
+                }
+            }
+            Err(e) => return Err(e),
         }
     }
+    Ok((hints, damaged, writes))
 }
 
-impl Drop for KvStore {
-    fn drop(&mut self) {
-        println!("kv droped");
-        self.update_current().expect("update current failed");
+/// The manifest of a store that has none: every segment on disk, the newest
+/// one active. A store from before the manifest named its active segment in
+/// `current`, which may be newer than any segment written.
+fn rebuild_manifest(dir: &Path) -> Result<Manifest> {
+    let mut segments = list_segments(dir)?;
+    let named = match File::open(dir.join(LEGACY_CURRENT_FILE)) {
+        Ok(mut file) => Some(file.read_u32::<LE>()?),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let active = segments
+        .iter()
+        .next_back()
+        .cloned()
+        .into_iter()
+        .chain(named)
+        .max()
+        .unwrap_or(0);
+    segments.remove(&active);
+    let mut manifest = Manifest::new(active);
+    manifest.segments = segments.into_iter().map(|id| (id, 0)).collect();
+    Ok(manifest)
+}
+
+/// Deletes the segment and hint files the manifest does not list: the
+/// output of a compaction that never finished, the inputs of one that did,
+/// or a segment opened just before a crash.
+fn remove_orphans(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let live = manifest.segment_ids();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        let orphan = name == MANIFEST_TEMP_FILE
+            || ["log_", "hint_"].iter().any(|prefix| {
+                name.strip_prefix(prefix)
+                    .and_then(|id| id.parse::<u32>().ok())
+                    .is_some_and(|id| !live.contains(&id))
+            });
+        if orphan {
+            warn!("removing leftover {}", name);
+            std::fs::remove_file(dir.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Ids of every `log_<id>` file in `dir`.
+pub(crate) fn list_segments(dir: &Path) -> Result<BTreeSet<u32>> {
+    let mut segments = BTreeSet::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(id) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("log_"))
+            .and_then(|id| id.parse::<u32>().ok())
+        {
+            segments.insert(id);
+        }
     }
+    Ok(segments)
+}
+
+/// Opens every segment in `segments`, which then stay readable however the
+/// directory changes.
+fn pin_segments(
+    dir: &Path,
+    segments: &BTreeSet<u32>,
+    buffer_size: usize,
+) -> Result<BTreeMap<u32, Mutex<BufReader<File>>>> {
+    let mut pinned = BTreeMap::new();
+    for &id in segments {
+        let file = File::open(get_log_path(dir, id))?;
+        pinned.insert(id, Mutex::new(BufReader::with_capacity(buffer_size, file)));
+    }
+    Ok(pinned)
+}
+
+pub(crate) fn get_log_path(dir: &Path, log_id: u32) -> PathBuf {
+    dir.join(format!("log_{}", log_id))
 }
 
-fn get_log_path(dir: PathBuf, log_id: u32) -> Result<(PathBuf)> {
-    let log_file_name = format!("log_{}", log_id);
-    let mut log_path = dir.clone();
-    log_path.push(log_file_name);
-    Ok(log_path)
+pub(crate) fn get_hint_path(dir: &Path, log_id: u32) -> PathBuf {
+    dir.join(format!("hint_{}", log_id))
+}
+
+fn remove_segment(dir: &Path, log_id: u32) -> Result<()> {
+    std::fs::remove_file(get_log_path(dir, log_id))?;
+    remove_hint(dir, log_id)
+}
+
+fn remove_hint(dir: &Path, log_id: u32) -> Result<()> {
+    remove_file_if_exists(&get_hint_path(dir, log_id))
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
 }
 
 fn open_log(path: PathBuf) -> Result<File> {
-    //println!("open log: {:?}", path);
     let file = OpenOptions::new()
         .create(true)
         .read(true)
         .append(true)
-        .open(path.as_path())
-        .expect("open log file failed");
+        .open(path.as_path())?;
     Ok(file)
 }
 
@@ -218,24 +1881,13 @@ fn log_size(path: PathBuf) -> Result<u64> {
     Ok(metadata.len())
 }
 
-fn read_entry(reader: &mut BufReader<File>, pos: u64) -> Result<Entry> {
-    reader.seek(SeekFrom::Start(pos))?;
-    let entry_len = match reader.read_u32::<LE>() {
-        Ok(len) => len,
-        Err(_) => return Err(KvError::KeyNotExit),
-    };
-
-    let mut buf = vec![0; entry_len as usize];
-    reader.read_exact(&mut buf)?;
-    let entry: Entry = serde_json::from_str(str::from_utf8(&buf).unwrap()).unwrap();
-    Ok(entry)
-}
-
-fn append_entry(writer: &mut BufWriter<File>, entry: &Entry) -> Result<(u64)> {
-    let pos = writer.seek(SeekFrom::End(0))?;
-    let serialized = serde_json::to_string(&entry).unwrap();
-    writer.write_u32::<LE>(serialized.len() as u32)?;
-    writer.write(serialized.as_bytes())?;
-    writer.flush()?;
-    Ok(pos)
+fn read_entry(reader: &mut BufReader<File>, pointer: LogPointer) -> Result<Entry> {
+    reader.seek(SeekFrom::Start(pointer.offset))?;
+    let mut record = reader.take(pointer.len);
+    record::read_record(&mut record, pointer.segment_id, pointer.offset)?.ok_or(
+        KvError::Corruption {
+            log_id: pointer.segment_id,
+            offset: pointer.offset,
+        },
+    )
 }
@@ -0,0 +1,103 @@
+use crate::error::{KvError, Result};
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+const LOCK_FILE: &str = "LOCK";
+
+/// How often a lock this process seems to hold is tried again.
+const OWN_LOCK_RETRIES: u32 = 20;
+
+/// An advisory lock on a data directory, released when dropped.
+///
+/// A writer holds it exclusively and writes its PID into the `LOCK` file so a
+/// second process that finds the directory taken can say who has it.
+/// Read-only stores hold it shared and leave the file alone. The file itself
+/// is left behind: only the OS lock on it means anything.
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Fails with `KvError::Locked` if another handle, in this process or
+    /// another, holds the lock. `pid` is 0 if only read-only stores do.
+    pub(crate) fn acquire(dir: &Path) -> Result<DirLock> {
+        let path = dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // The holder's PID, if any, is read back on failure.
+            .truncate(false)
+            .open(&path)?;
+        let mut attempts = 0;
+        while let Err(e) = file.try_lock_exclusive() {
+            if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() {
+                return Err(e.into());
+            }
+            // The PID in the file is a former writer's if only readers
+            // hold the lock.
+            let pid = match FileExt::try_lock_shared(&file) {
+                Ok(()) => {
+                    FileExt::unlock(&file)?;
+                    0
+                }
+                Err(_) => holder_pid(&mut file)?,
+            };
+            // A child forked while a store of this process was open shares
+            // its lock until it execs, so a store just closed here can look
+            // held for a moment.
+            if pid == process::id() && attempts < OWN_LOCK_RETRIES {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            return Err(KvError::Locked {
+                path: dir.display().to_string(),
+                pid,
+            });
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", process::id())?;
+        file.sync_data()?;
+        Ok(DirLock { _file: file })
+    }
+
+    /// Takes the lock shared, for a read-only store, without creating or
+    /// writing `LOCK`. Any number of shared locks can be held at once, and a
+    /// writer cannot take the directory while one is. Returns `None` if a
+    /// writer already holds it, or no writer ever has.
+    pub(crate) fn acquire_shared(dir: &Path) -> Result<Option<DirLock>> {
+        let file = match File::open(dir.join(LOCK_FILE)) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match FileExt::try_lock_shared(&file) {
+            Ok(()) => Ok(Some(DirLock { _file: file })),
+            Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The PID written by the holder, or 0 if it never gets to write one.
+fn holder_pid(file: &mut File) -> Result<u32> {
+    // The holder takes the lock before it writes its PID.
+    for _ in 0..10 {
+        let mut content = String::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_string(&mut content)?;
+        if let Ok(pid) = content.trim().parse() {
+            return Ok(pid);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(0)
+}
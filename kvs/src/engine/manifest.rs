@@ -0,0 +1,164 @@
+//! The manifest: which segments make up a `KvStore`.
+//!
+//! `MANIFEST` names the active segment and every sealed one, along with the
+//! compaction generation that wrote it. Segment files it does not list are
+//! leftovers from a crash and are deleted on open. The file is
+//!
+//! | bytes | field                                   |
+//! |-------|-----------------------------------------|
+//! | 8     | magic, `KVSMANIF`                       |
+//! | 4     | format version                          |
+//! | 8     | compactions completed                   |
+//! | 8     | writes before the active segment        |
+//! | 4     | active segment id                       |
+//! | 4     | sealed segment count                    |
+//! | 12 n  | sealed segment id and its generation    |
+//!
+//! followed by a CRC32 of everything before it. Version 1 had no write
+//! count. It is only ever replaced
+//! whole: written to `MANIFEST.tmp`, synced, then renamed over the old one.
+
+use crate::error::{KvError, Result};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+pub const MANIFEST_FILE: &str = "MANIFEST";
+pub const MANIFEST_TEMP_FILE: &str = "MANIFEST.tmp";
+
+/// Version of the on-disk format written by this build.
+pub const FORMAT_VERSION: u32 = 2;
+
+const MAGIC: &[u8; 8] = b"KVSMANIF";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub version: u32,
+    /// Number of compactions completed.
+    pub generation: u64,
+    /// Number of writes appended before the active segment was started.
+    /// The writes in the active segment itself are counted on open.
+    pub seq: u64,
+    pub active: u32,
+    /// Every sealed segment, with the generation of the compaction that
+    /// wrote it, 0 for a segment filled by writes.
+    pub segments: BTreeMap<u32, u64>,
+}
+
+impl Manifest {
+    pub fn new(active: u32) -> Manifest {
+        Manifest {
+            version: FORMAT_VERSION,
+            generation: 0,
+            seq: 0,
+            active,
+            segments: BTreeMap::new(),
+        }
+    }
+
+    pub fn sealed(&self) -> BTreeSet<u32> {
+        self.segments.keys().cloned().collect()
+    }
+
+    /// Every segment, the active one included.
+    pub fn segment_ids(&self) -> BTreeSet<u32> {
+        let mut ids = self.sealed();
+        ids.insert(self.active);
+        ids
+    }
+
+    /// Returns `None` if `dir` has no manifest, and fails with
+    /// `KvError::BadManifest` if it has a damaged one.
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        let buf = match fs::read(dir.join(MANIFEST_FILE)) {
+            Ok(buf) => buf,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if buf.len() < MAGIC.len() + 4 || &buf[..MAGIC.len()] != MAGIC {
+            return Err(KvError::BadManifest("not a manifest".to_owned()));
+        }
+        let (body, mut crc) = buf.split_at(buf.len() - 4);
+        if checksum(body) != crc.read_u32::<LE>()? {
+            return Err(KvError::BadManifest("checksum mismatch".to_owned()));
+        }
+        parse(&body[MAGIC.len()..])
+            .map(Some)
+            .map_err(|e| KvError::BadManifest(e.to_string()))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.write_u32::<LE>(self.version)?;
+        buf.write_u64::<LE>(self.generation)?;
+        if self.version >= 2 {
+            buf.write_u64::<LE>(self.seq)?;
+        }
+        buf.write_u32::<LE>(self.active)?;
+        buf.write_u32::<LE>(self.segments.len() as u32)?;
+        for (&id, &generation) in &self.segments {
+            buf.write_u32::<LE>(id)?;
+            buf.write_u64::<LE>(generation)?;
+        }
+        let crc = checksum(&buf);
+        buf.write_u32::<LE>(crc)?;
+
+        let temp = dir.join(MANIFEST_TEMP_FILE);
+        let mut file = File::create(&temp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&temp, dir.join(MANIFEST_FILE))?;
+        sync_dir(dir)
+    }
+}
+
+fn parse(mut body: &[u8]) -> io::Result<Manifest> {
+    let version = body.read_u32::<LE>()?;
+    let generation = body.read_u64::<LE>()?;
+    let seq = if version >= 2 {
+        body.read_u64::<LE>()?
+    } else {
+        0
+    };
+    let active = body.read_u32::<LE>()?;
+    let count = body.read_u32::<LE>()?;
+    let mut segments = BTreeMap::new();
+    for _ in 0..count {
+        let id = body.read_u32::<LE>()?;
+        segments.insert(id, body.read_u64::<LE>()?);
+    }
+    if !body.is_empty() || segments.len() != count as usize || segments.contains_key(&active) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "inconsistent segment list",
+        ));
+    }
+    Ok(Manifest {
+        version,
+        generation,
+        seq,
+        active,
+        segments,
+    })
+}
+
+fn checksum(buf: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(buf);
+    hasher.finalize()
+}
+
+/// Makes a rename in `dir` durable.
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! The `engine` file, naming the engine a data directory belongs to.
+
+use crate::error::{KvError, Result};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+pub const ENGINE_FILE: &str = "engine";
+
+pub const ENGINES: [&str; 2] = ["kvs", "sled"];
+
+/// The engine that owns `dir`, or `None` for a directory no engine has used.
+///
+/// Directories written before the `engine` file existed are recognised by
+/// the files each engine leaves: `MANIFEST`, or `current` before that, for
+/// kvs and `conf` for sled.
+pub fn recorded_engine(dir: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(dir.join(ENGINE_FILE)) {
+        Ok(content) => {
+            let engine = content.trim();
+            check_engine(engine)?;
+            Ok(Some(engine.to_owned()))
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            if dir.join("MANIFEST").exists() || dir.join("current").exists() {
+                Ok(Some("kvs".to_owned()))
+            } else if dir.join("conf").exists() {
+                Ok(Some("sled".to_owned()))
+            } else {
+                Ok(None)
+            }
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes the `engine` file, replacing any previous one atomically.
+pub fn record_engine(dir: &Path, engine: &str) -> Result<()> {
+    check_engine(engine)?;
+    let temp = dir.join(format!("{}.tmp", ENGINE_FILE));
+    let mut file = File::create(&temp)?;
+    writeln!(file, "{}", engine)?;
+    file.sync_all()?;
+    fs::rename(&temp, dir.join(ENGINE_FILE))?;
+    Ok(())
+}
+
+fn check_engine(engine: &str) -> Result<()> {
+    if ENGINES.contains(&engine) {
+        Ok(())
+    } else {
+        Err(KvError::InvalidOption(format!(
+            "unknown engine: {}",
+            engine
+        )))
+    }
+}
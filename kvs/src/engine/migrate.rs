@@ -0,0 +1,113 @@
+//! Upgrading `KvStore` directories written in an older format, and
+//! `SledKvsEngine` directories written by sled 0.24.
+
+use crate::engine::backup;
+use crate::engine::batch::WriteBatch;
+use crate::engine::kv::{KvStore, KvStoreOptions};
+use crate::engine::manifest::Manifest;
+use crate::engine::marker;
+use crate::engine::sled::{self, SledKvsEngine};
+use crate::engine::KvsEngine;
+use crate::error::{KvError, Result};
+use std::fs;
+use std::path::Path;
+
+/// Most keys `migrate_sled` writes in one batch.
+const SLED_BATCH: usize = 1000;
+
+pub use crate::engine::manifest::FORMAT_VERSION;
+
+/// The format version of the store in `dir`.
+pub fn format_version(dir: &Path) -> Result<u32> {
+    match Manifest::load(dir)? {
+        Some(manifest) => Ok(manifest.version),
+        // Format version 0 had no manifest, only `current`.
+        None if dir.join("current").exists() => Ok(0),
+        None => Err(KvError::StoreNotFound(dir.display().to_string())),
+    }
+}
+
+/// Upgrades the store in `dir` in place, returning the version it had.
+pub fn migrate(dir: &Path) -> Result<u32> {
+    let version = format_version(dir)?;
+    if version != FORMAT_VERSION {
+        let options = KvStoreOptions::new().create_if_missing(false).upgrade(true);
+        drop(KvStore::open_with(dir, options)?);
+    }
+    Ok(version)
+}
+
+/// Copies the store in `src` into a new store at `dest` in the current
+/// format, returning the version `src` has. `src` is left as it was.
+///
+/// Only live keys are copied, with their deadlines; the history kept for
+/// snapshots is not.
+pub fn migrate_to(src: &Path, dest: &Path) -> Result<u32> {
+    let version = format_version(src)?;
+    let options = KvStoreOptions::new().read_only(true).upgrade(true);
+    let source = KvStore::open_with(src, options)?;
+    let target = KvStore::open_with(dest, KvStoreOptions::new().error_if_exists(true))?;
+    for pair in source.scan(..) {
+        let (key, value) = pair?;
+        match source.ttl(&key) {
+            Ok(Some(ttl)) => target.set_with_ttl(key, value, ttl)?,
+            Ok(None) => target.set_bytes(key, value)?,
+            // Expired since the scan read it.
+            Err(KvError::KeyNotExit) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(version)
+}
+
+/// Copies the store sled 0.24 wrote in `src` into a new store at `dest`,
+/// which must not exist or be empty, and returns the number of keys. `src`
+/// is opened read-only and left as it was; `dest` is removed again if the
+/// copy fails.
+///
+/// Stores of that age had no deadlines, only the default tree.
+pub fn migrate_sled(src: &Path, dest: &Path) -> Result<u64> {
+    match sled::old_format(src)? {
+        Some(_) => {}
+        None if src.join("conf").exists() => {
+            return Err(KvError::InvalidOption(format!(
+                "{} is already in the current sled format",
+                src.display()
+            )))
+        }
+        None => return Err(KvError::StoreNotFound(src.display().to_string())),
+    }
+    backup::create_empty_dir(dest)?;
+    let copied = copy_old_sled(src, dest).and_then(|keys| {
+        marker::record_engine(dest, "sled")?;
+        Ok(keys)
+    });
+    if copied.is_err() {
+        let _ = fs::remove_dir_all(dest);
+    }
+    copied
+}
+
+fn copy_old_sled(src: &Path, dest: &Path) -> Result<u64> {
+    let old_sled = |e: sled_legacy::Error| KvError::OldSled(e.to_string());
+    let config = sled_legacy::ConfigBuilder::new()
+        .path(src)
+        .read_only(true)
+        .build();
+    let source = sled_legacy::Db::start(config).map_err(old_sled)?;
+    let target = SledKvsEngine::open(dest)?;
+    let mut batch = WriteBatch::new();
+    let mut keys = 0;
+    for pair in source.iter() {
+        let (key, value) = pair.map_err(old_sled)?;
+        batch.set(key.to_vec(), value.to_vec());
+        keys += 1;
+        if batch.len() == SLED_BATCH {
+            target.write_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    if !batch.is_empty() {
+        target.write_batch(batch)?;
+    }
+    Ok(keys)
+}
@@ -1,10 +1,178 @@
-use super::error::Result;
+use super::error::{KvError, Result};
+use batch::WriteBatch;
+use scan::{KeyRange, Scan};
+use snapshot::KvsSnapshot;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::time::Duration;
+use transaction::Transaction;
 
-pub trait KvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    fn remove(&mut self, key: String) -> Result<()>;
+/// How many times `KvsEngine::transaction` runs a transaction that keeps
+/// conflicting before giving up.
+const TRANSACTION_ATTEMPTS: usize = 100;
+
+/// A storage engine shared between threads.
+///
+/// Handles are cloned into every thread that needs one; all clones see the
+/// same data.
+///
+/// Keys and values are arbitrary bytes. The `String` methods are a
+/// convenience on top; `get` fails with `KvError::Utf8` on a value that is
+/// not UTF-8.
+///
+/// A key may be given a time to live, after which it reads as absent. Any
+/// write that replaces the value of a key also clears its deadline.
+pub trait KvsEngine: Clone + Send + 'static {
+    type Snapshot: KvsSnapshot;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+
+    /// Sets `key` to `value` and expires it `ttl` from now.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Expires an existing key `ttl` from now. Fails with
+    /// `KvError::KeyNotExit` if there is no such key.
+    fn expire(&self, key: &[u8], ttl: Duration) -> Result<()>;
+
+    /// Removes the deadline of an existing key.
+    fn persist(&self, key: &[u8]) -> Result<()>;
+
+    /// Time left before `key` expires, or `None` if it has no deadline.
+    /// Fails with `KvError::KeyNotExit` if there is no such key.
+    fn ttl(&self, key: &[u8]) -> Result<Option<Duration>>;
+
+    /// The deadline of `key` as stored, in milliseconds since the Unix
+    /// epoch, or `None` if it has none. Fails with `KvError::KeyNotExit` if
+    /// there is no such key.
+    fn deadline(&self, key: &[u8]) -> Result<Option<u64>>;
+
+    /// Applies every write in `batch`, or none of them.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Sets `key` to `new`, or removes it if `new` is `None`, provided its
+    /// value is currently `expected`, where `None` means absent. Fails with
+    /// `KvError::Conflict` and writes nothing otherwise.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()>;
+
+    /// Applies `batch` provided every key in `expected` currently has the
+    /// given value, where `None` means absent. Fails with
+    /// `KvError::Conflict` and writes nothing otherwise.
+    fn compare_and_write_batch(
+        &self,
+        expected: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<()>;
+
+    /// Writes a copy of the store as of the call into `dest`, which must
+    /// not exist or be empty. Writes carry on meanwhile. The copy is a
+    /// store of its own, engine file included, and can be opened directly or
+    /// restored with `backup::restore`.
+    fn checkpoint(&self, dest: &Path) -> Result<()>;
+
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+
+    /// Runs `f` in a transaction and commits it, running `f` again on a
+    /// conflict. An error returned by `f` aborts the transaction.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvStore, KvsEngine, Result};
+    /// # fn main() -> Result<()> {
+    /// let store = KvStore::open("db")?;
+    /// store.transaction(|txn| {
+    ///     let from: u64 = txn.get("alice".to_owned())?.map_or(0, |v| v.parse().unwrap());
+    ///     let to: u64 = txn.get("bob".to_owned())?.map_or(0, |v| v.parse().unwrap());
+    ///     txn.set("alice".to_owned(), (from - 10).to_string());
+    ///     txn.set("bob".to_owned(), (to + 10).to_string());
+    ///     Ok(())
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn transaction<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Transaction<Self>) -> Result<T>,
+    {
+        for _ in 0..TRANSACTION_ATTEMPTS {
+            let mut txn = self.begin();
+            let value = f(&mut txn)?;
+            match txn.commit() {
+                Ok(()) => return Ok(value),
+                Err(KvError::Conflict) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(KvError::Conflict)
+    }
+
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    fn remove_if_equals(&self, key: Vec<u8>, expected: Vec<u8>) -> Result<()> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    /// A read-only view of the engine as it is now.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// Up to `limit` pairs with keys in `range`, in key order. `scan` and
+    /// `scan_prefix` are built on this.
+    fn scan_page(&self, range: KeyRange, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan<Self> {
+        let range = (
+            range.start_bound().map(Clone::clone),
+            range.end_bound().map(Clone::clone),
+        );
+        Scan::new(self.clone(), range)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Scan<Self> {
+        Scan::new(self.clone(), scan::prefix_range(prefix))
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
 }
 
+pub mod archive;
+pub mod backup;
+pub mod batch;
+pub mod convert;
+pub mod dump;
+pub mod durability;
+mod expiry;
+mod hint;
+pub mod inspect;
 pub mod kv;
+mod lock;
+mod manifest;
+pub mod marker;
+pub mod migrate;
+mod record;
+pub mod scan;
 pub mod sled;
+pub mod snapshot;
+pub mod stats;
+pub mod transaction;
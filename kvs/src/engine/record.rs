@@ -0,0 +1,248 @@
+//! On-disk layout of a single log record.
+//!
+//! Every record starts with a fixed 16 byte little-endian header:
+//!
+//! | bytes | field                                  |
+//! |-------|----------------------------------------|
+//! | 2     | magic, `0x4b76`                        |
+//! | 1     | format version                         |
+//! | 1     | tag                                    |
+//! | 4     | CRC32 of the tag, key and value        |
+//! | 4     | key length                             |
+//! | 4     | value length                           |
+//!
+//! followed by the key and value bytes.
+//!
+//! A value that expires is stored under tag `3` instead of `Normal`, with its
+//! deadline as the first 8 bytes of the value (little-endian milliseconds
+//! since the Unix epoch).
+//!
+//! A write batch is a single record tagged `Batch` with an empty key, whose
+//! value is the batch's records laid end to end. Those use their own magic,
+//! `0x4b62`, so recovery never mistakes one for the start of a top-level
+//! record and applies a batch in part.
+
+use crate::error::{KvError, Result};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use crc32fast::Hasher;
+use std::io::{self, Read, Seek, SeekFrom};
+
+const MAGIC: u16 = 0x4b76;
+const BATCH_MAGIC: u16 = 0x4b62;
+const FORMAT_VERSION: u8 = 1;
+pub const HEADER_LEN: u64 = 16;
+/// Tag byte of a `Normal` entry with a deadline.
+pub const EXPIRING_TAG: u8 = 3;
+
+#[derive(Debug)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub tag: Tag,
+    /// Deadline of a `Normal` entry, see `engine::expiry`.
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Tag {
+    Normal,
+    Deleted,
+    Batch,
+}
+
+impl Tag {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Tag::Normal => 0,
+            Tag::Deleted => 1,
+            Tag::Batch => 2,
+        }
+    }
+
+    pub fn from_u8(tag: u8) -> Option<Tag> {
+        match tag {
+            0 => Some(Tag::Normal),
+            1 => Some(Tag::Deleted),
+            2 => Some(Tag::Batch),
+            _ => None,
+        }
+    }
+}
+
+impl Entry {
+    pub fn new(k: Vec<u8>, v: Vec<u8>, t: Tag) -> Self {
+        Entry {
+            key: k,
+            value: v,
+            tag: t,
+            expires_at: None,
+        }
+    }
+
+    pub fn expiring(k: Vec<u8>, v: Vec<u8>, expires_at: Option<u64>) -> Self {
+        Entry {
+            expires_at,
+            ..Entry::new(k, v, Tag::Normal)
+        }
+    }
+
+    /// The tag byte written to disk.
+    pub fn tag_byte(&self) -> u8 {
+        match self.expires_at {
+            Some(_) => EXPIRING_TAG,
+            None => self.tag.to_u8(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len() as usize);
+        self.encode_into(MAGIC, &mut buf);
+        buf
+    }
+
+    fn encode_into(&self, magic: u16, buf: &mut Vec<u8>) {
+        let deadline = self.expires_at.map(u64::to_le_bytes);
+        let deadline = deadline.as_ref().map_or(&[][..], |bytes| &bytes[..]);
+        let crc = checksum(self.tag_byte(), &self.key, &[deadline, &self.value]);
+        buf.write_u16::<LE>(magic).unwrap();
+        buf.write_u8(FORMAT_VERSION).unwrap();
+        buf.write_u8(self.tag_byte()).unwrap();
+        buf.write_u32::<LE>(crc).unwrap();
+        buf.write_u32::<LE>(self.key.len() as u32).unwrap();
+        buf.write_u32::<LE>((deadline.len() + self.value.len()) as u32)
+            .unwrap();
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(deadline);
+        buf.extend_from_slice(&self.value);
+    }
+
+    pub fn encoded_len(&self) -> u64 {
+        let deadline_len = if self.expires_at.is_some() { 8 } else { 0 };
+        HEADER_LEN + self.key.len() as u64 + deadline_len + self.value.len() as u64
+    }
+}
+
+/// Encodes `entries` as one batch record. The first entry starts
+/// `HEADER_LEN` bytes into it.
+pub fn encode_batch(entries: &[Entry]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for entry in entries {
+        entry.encode_into(BATCH_MAGIC, &mut body);
+    }
+    Entry::new(Vec::new(), body, Tag::Batch).encode()
+}
+
+/// Splits the value of a batch record read at `offset` into its entries,
+/// each with its own offset in the log.
+pub fn decode_batch(batch: Entry, log_id: u32, offset: u64) -> Result<Vec<(u64, Entry)>> {
+    let mut entries = Vec::new();
+    let mut body = &batch.value[..];
+    let mut entry_offset = offset + HEADER_LEN + batch.key.len() as u64;
+    while !body.is_empty() {
+        let entry = match read_record(&mut body, log_id, entry_offset)? {
+            Some(entry) if entry.tag != Tag::Batch => entry,
+            _ => return Err(KvError::Corruption { log_id, offset }),
+        };
+        let len = entry.encoded_len();
+        entries.push((entry_offset, entry));
+        entry_offset += len;
+    }
+    Ok(entries)
+}
+
+/// Reads the record at the reader's current position, which is `offset` in
+/// log `log_id`.
+///
+/// Returns `Ok(None)` when the log ends exactly at `offset`.
+pub fn read_record<R: Read>(reader: &mut R, log_id: u32, offset: u64) -> Result<Option<Entry>> {
+    let corruption = || KvError::Corruption { log_id, offset };
+
+    let mut header = [0u8; HEADER_LEN as usize];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        n if n < header.len() => return Err(corruption()),
+        _ => {}
+    }
+    let mut header = &header[..];
+    let magic = header.read_u16::<LE>()?;
+    let version = header.read_u8()?;
+    let tag = header.read_u8()?;
+    let crc = header.read_u32::<LE>()?;
+    let key_len = header.read_u32::<LE>()?;
+    let value_len = header.read_u32::<LE>()?;
+    if (magic != MAGIC && magic != BATCH_MAGIC) || version != FORMAT_VERSION {
+        return Err(corruption());
+    }
+    let tag_byte = tag;
+    let tag = match tag {
+        EXPIRING_TAG => Tag::Normal,
+        _ => Tag::from_u8(tag).ok_or_else(corruption)?,
+    };
+
+    // `take` keeps a corrupted length from allocating more than the log holds.
+    let body_len = u64::from(key_len) + u64::from(value_len);
+    let mut body = Vec::new();
+    reader.take(body_len).read_to_end(&mut body)?;
+    if body.len() as u64 != body_len {
+        return Err(corruption());
+    }
+    let mut value = body.split_off(key_len as usize);
+    if checksum(tag_byte, &body, &[&value]) != crc {
+        return Err(corruption());
+    }
+    if tag_byte != EXPIRING_TAG {
+        return Ok(Some(Entry::new(body, value, tag)));
+    }
+    if value.len() < 8 {
+        return Err(corruption());
+    }
+    let deadline = (&value[..8]).read_u64::<LE>()?;
+    let value = value.split_off(8);
+    Ok(Some(Entry::expiring(body, value, Some(deadline))))
+}
+
+/// Finds the offset of the first intact record at or after `from`.
+pub fn find_next_record<R: Read + Seek>(
+    reader: &mut R,
+    log_id: u32,
+    from: u64,
+) -> Result<Option<u64>> {
+    reader.seek(SeekFrom::Start(from))?;
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest)?;
+    let magic = MAGIC.to_le_bytes();
+    for i in 0..rest.len() {
+        if rest[i..].starts_with(&magic) {
+            let offset = from + i as u64;
+            if let Ok(Some(_)) = read_record(&mut &rest[i..], log_id, offset) {
+                return Ok(Some(offset));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// CRC32 of a record's tag, key and value, the value given in parts.
+fn checksum(tag: u8, key: &[u8], value: &[&[u8]]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(&[tag]);
+    hasher.update(key);
+    for part in value {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+/// Like `read_exact`, but reports how much was read instead of failing at EOF.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
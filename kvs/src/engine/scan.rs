@@ -0,0 +1,92 @@
+use crate::engine::KvsEngine;
+use crate::error::Result;
+use std::collections::VecDeque;
+use std::ops::Bound;
+
+/// A range of keys, as taken by `KvsEngine::scan_page`.
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+const PAGE_SIZE: usize = 128;
+
+/// Something a `Scan` can fetch pages from: every engine, and snapshots.
+pub trait ScanSource: Clone {
+    fn fetch_page(&self, range: KeyRange, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+}
+
+impl<E: KvsEngine> ScanSource for E {
+    fn fetch_page(&self, range: KeyRange, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_page(range, limit)
+    }
+}
+
+/// Key/value pairs in key order, returned by `KvsEngine::scan` and
+/// `KvsEngine::scan_prefix` and their `KvsSnapshot` counterparts.
+///
+/// Pairs are fetched a page at a time, so writes made to an engine while
+/// iterating may or may not show up, but every key is returned at most once.
+pub struct Scan<E: ScanSource> {
+    engine: E,
+    range: KeyRange,
+    page: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl<E: ScanSource> Scan<E> {
+    pub(crate) fn new(engine: E, range: KeyRange) -> Self {
+        Scan {
+            engine,
+            range,
+            page: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+impl<E: ScanSource> Iterator for Scan<E> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            match self.engine.fetch_page(self.range.clone(), PAGE_SIZE) {
+                Ok(page) => {
+                    self.done = page.len() < PAGE_SIZE;
+                    if let Some((key, _)) = page.last() {
+                        self.range.0 = Bound::Excluded(key.clone());
+                    }
+                    self.page = page.into();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.page.pop_front().map(Ok)
+    }
+}
+
+/// The range of keys starting with `prefix`.
+pub fn prefix_range(prefix: &[u8]) -> KeyRange {
+    // The first key past the prefix: drop trailing 0xff bytes and bump the
+    // last one left. A prefix of only 0xff bytes runs to the end.
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix.to_vec()), Bound::Unbounded)
+}
+
+/// Whether `range` holds no key at all. `BTreeMap::range` panics on some of
+/// these, such as a start past the end.
+pub(crate) fn is_empty_range(range: &KeyRange) -> bool {
+    match range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
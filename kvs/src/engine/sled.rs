@@ -1,38 +1,773 @@
+use crate::engine::backup;
+use crate::engine::batch::{BatchOp, WriteBatch};
+use crate::engine::durability::{Durability, GroupCommit, SyncCounter};
+use crate::engine::expiry;
+use crate::engine::lock::DirLock;
+use crate::engine::marker;
+use crate::engine::scan::{self, KeyRange, ScanSource};
+use crate::engine::snapshot::KvsSnapshot;
+use crate::engine::stats::SyncStats;
 use crate::engine::KvsEngine;
 use crate::error::KvError;
 use crate::error::Result;
-use sled::{Db, Tree};
+use byteorder::{BigEndian, ByteOrder};
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError, Transactional,
+    TransactionalTree,
+};
+use sled::{Config, Db, IVec, Tree};
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The oldest sled whose directories this build can open.
+const SLED_FORMAT: (u32, u32) = (0, 34);
+
+/// Most expired keys removed in one pass of the reaper.
+const REAP_BATCH: usize = 1000;
+
+/// Times sled's own lock is tried before giving up on a previous handle
+/// letting go of it.
+const OPEN_ATTEMPTS: u32 = 100;
+
+/// Most keys copied into a checkpoint at a time.
+const CHECKPOINT_PAGE: usize = 1000;
+
+/// A `KvsEngine` on top of sled.
+///
+/// Values live in the default tree. Deadlines are kept beside them in two
+/// more trees, updated in the same transaction as the value: `kvs_expiry`
+/// maps a key to its deadline, and `kvs_deadlines` orders keys by deadline
+/// for the background thread that removes expired ones.
+///
+/// sled has no snapshots of its own. A snapshot reads the live trees, and
+/// while it is held every write first saves the value and deadline it is
+/// about to replace into it. Its memory grows with the keys written since it
+/// was taken, and each write does an extra read per key and open snapshot.
+///
+/// Like `KvStore`, the engine locks its directory while open. A directory
+/// written by sled 0.24 fails to open with `KvError::OldSledFormat`, and is
+/// upgraded with `migrate::migrate_sled`.
+#[derive(Clone)]
 pub struct SledKvsEngine {
-    tree: sled::Db,
+    trees: Trees,
+    durability: Durability,
+    group_commit: Arc<GroupCommit>,
+    syncs: Arc<SyncCounter>,
+    // Only held so the reaper stops with the last handle. Read-only engines
+    // have none.
+    _reaper: Arc<Option<Reaper>>,
+    // Only held; released after the reaper is joined.
+    _lock: Arc<DirLock>,
+}
+
+/// The trees of a `SledKvsEngine`, shared with its reaper.
+#[derive(Clone)]
+struct Trees {
+    tree: Db,
+    expiry: Tree,
+    deadlines: Tree,
+    // Held shared by every write and exclusively while taking a snapshot.
+    gate: Arc<RwLock<()>>,
+    // Number of writes applied so far.
+    seq: Arc<AtomicU64>,
+    // The saved state of every open snapshot.
+    snapshots: Arc<Mutex<Vec<Weak<Saved>>>>,
+    read_only: bool,
+}
+
+/// What a snapshot saw of the keys written since it was taken: the value and
+/// deadline of each, or `None` if it did not exist.
+type Saved = Mutex<BTreeMap<Vec<u8>, Option<State>>>;
+
+type State = (Vec<u8>, Option<u64>);
+
+/// Settings used when opening a `SledKvsEngine`.
+#[derive(Clone, Debug)]
+pub struct SledOptions {
+    durability: Durability,
+    reap_interval: Duration,
+    read_only: bool,
+}
+
+impl Default for SledOptions {
+    fn default() -> Self {
+        SledOptions {
+            durability: Durability::FsyncEveryWrite,
+            reap_interval: Duration::from_secs(1),
+            read_only: false,
+        }
+    }
+}
+
+impl SledOptions {
+    pub fn new() -> Self {
+        SledOptions::default()
+    }
+
+    /// Defaults to `Durability::FsyncEveryWrite`.
+    ///
+    /// sled cannot hand a write to the OS without also syncing it, so
+    /// `Durability::FlushOnly` syncs every write as well.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// How often expired keys are looked for and removed. Defaults to one
+    /// second.
+    pub fn reap_interval(mut self, reap_interval: Duration) -> Self {
+        self.reap_interval = reap_interval;
+        self
+    }
+
+    /// Opens an existing database for reading only: writes fail with
+    /// `KvError::ReadOnly`, expired keys are hidden but not removed, and the
+    /// open fails with `KvError::StoreNotFound` if there is no database.
+    ///
+    /// sled has no read-only mode of its own, so the directory is still
+    /// locked as it is for a writer, and sled may write its own metadata.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
 }
 
 impl SledKvsEngine {
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        SledKvsEngine::open_with(path, SledOptions::default())
+    }
+
+    pub fn open_with(path: impl Into<PathBuf>, options: SledOptions) -> Result<SledKvsEngine> {
+        if options.reap_interval == Duration::from_secs(0) {
+            return Err(KvError::InvalidOption(
+                "reap interval must be positive".to_owned(),
+            ));
+        }
         let path = path.into();
+        if options.read_only {
+            if !path.join("conf").exists() {
+                return Err(KvError::StoreNotFound(path.display().to_string()));
+            }
+        } else {
+            fs::create_dir_all(&path)?;
+        }
+        if let Some(version) = old_format(&path)? {
+            return Err(KvError::OldSledFormat {
+                path: path.display().to_string(),
+                version,
+            });
+        }
+        let lock = Arc::new(DirLock::acquire(&path)?);
+        let mut config = Config::new().path(path);
+        if let Durability::FsyncEvery(interval) = options.durability {
+            config = config.flush_every_ms(Some(interval.as_millis() as u64));
+        }
+        let tree = open_db(&config)?;
+        let trees = Trees {
+            expiry: tree.open_tree("kvs_expiry")?,
+            deadlines: tree.open_tree("kvs_deadlines")?,
+            tree,
+            gate: Arc::new(RwLock::new(())),
+            seq: Arc::new(AtomicU64::new(0)),
+            snapshots: Arc::new(Mutex::new(Vec::new())),
+            read_only: options.read_only,
+        };
+        let reaper = if options.read_only {
+            None
+        } else {
+            Some(Reaper::spawn(trees.clone(), options.reap_interval))
+        };
         Ok(SledKvsEngine {
-            tree: Db::start_default(path)?,
+            trees,
+            durability: options.durability,
+            group_commit: Arc::new(GroupCommit::default()),
+            syncs: Arc::new(SyncCounter::default()),
+            _reaper: Arc::new(reaper),
+            _lock: lock,
         })
     }
+
+    /// Flushes of the database made since the engine was opened to make
+    /// writes durable. Those sled makes on its own, as under
+    /// `Durability::FsyncEvery`, are not counted.
+    pub fn sync_stats(&self) -> SyncStats {
+        self.syncs.stats()
+    }
+
+    fn commit(&self) -> Result<()> {
+        match self.durability {
+            Durability::FlushOnly | Durability::FsyncEveryWrite => {
+                self.trees.tree.flush()?;
+                self.syncs.synced();
+            }
+            Durability::GroupCommit => {
+                let seq = self.trees.seq.load(Ordering::SeqCst);
+                self.group_commit.wait(seq, || {
+                    let covered = self.trees.seq.load(Ordering::SeqCst);
+                    self.trees.tree.flush()?;
+                    self.syncs.synced();
+                    Ok(covered)
+                })?;
+            }
+            Durability::None | Durability::FsyncEvery(_) => {}
+        }
+        Ok(())
+    }
 }
-impl KvsEngine for SledKvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.tree.set(key, value.into_bytes())?;
-        self.tree.flush()?;
+
+impl Trees {
+    /// Runs `f` on the value, expiry and deadline trees in one transaction,
+    /// counted as one write. `keys` are those `f` may change. Fails with
+    /// `KvError::ReadOnly` on a read-only engine.
+    fn transaction<'k, F, A>(&self, keys: impl IntoIterator<Item = &'k [u8]>, f: F) -> Result<A>
+    where
+        F: Fn(&View) -> ConflictableTransactionResult<A, KvError>,
+    {
+        if self.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        let _gate = self.gate.read().unwrap();
+        self.save_for_snapshots(keys)?;
+        let result = (&*self.tree, &self.expiry, &self.deadlines)
+            .transaction(f)
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => KvError::from(e),
+            })?;
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        Ok(result)
+    }
+
+    /// Saves the current state of `keys` into every open snapshot that has
+    /// not saved them yet.
+    ///
+    /// A snapshot's lock is held from looking a key up to saving it, and reads
+    /// of the snapshot take it too. A key it has not saved has therefore not
+    /// been written since it was taken, and the live trees still hold what it
+    /// saw.
+    fn save_for_snapshots<'k>(&self, keys: impl IntoIterator<Item = &'k [u8]>) -> Result<()> {
+        let open: Vec<Arc<Saved>> = {
+            let mut snapshots = self.snapshots.lock().unwrap();
+            snapshots.retain(|saved| saved.strong_count() > 0);
+            snapshots.iter().filter_map(Weak::upgrade).collect()
+        };
+        if open.is_empty() {
+            return Ok(());
+        }
+        for key in keys {
+            for saved in &open {
+                let mut saved = saved.lock().unwrap();
+                if !saved.contains_key(key) {
+                    let state = self.state(key)?;
+                    saved.insert(key.to_vec(), state);
+                }
+            }
+        }
         Ok(())
     }
-    fn get(&mut self, key: String) -> Result<Option<String>> {
+
+    /// The value and deadline of `key`, expired or not.
+    fn state(&self, key: &[u8]) -> Result<Option<State>> {
+        match self.tree.get(key)? {
+            Some(value) => Ok(Some((value.to_vec(), self.deadline(key)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn deadline(&self, key: &[u8]) -> Result<Option<u64>> {
         Ok(self
-            .tree
+            .expiry
             .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+            .map(|deadline| decode_deadline(&deadline)))
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        self.tree.del(key)?.ok_or(KvError::KeyNotExit)?;
-        self.tree.flush()?;
-        Ok(())
+    fn is_expired(&self, key: &[u8]) -> Result<bool> {
+        Ok(self
+            .expiry
+            .get(key)?
+            .is_some_and(|deadline| expiry::is_expired(decode_deadline(&deadline))))
+    }
+
+    /// Removes expired keys, at most `REAP_BATCH` per transaction.
+    fn reap_expired(&self) -> Result<()> {
+        loop {
+            let now = expiry::now();
+            let mut expired = Vec::new();
+            for entry in self.deadlines.iter().keys().take(REAP_BATCH) {
+                let queue_key = entry?;
+                if decode_deadline(&queue_key) > now {
+                    break;
+                }
+                expired.push(queue_key[8..].to_vec());
+            }
+            if expired.is_empty() {
+                return Ok(());
+            }
+            debug!("removing {} expired keys", expired.len());
+            let done = expired.len() < REAP_BATCH;
+            self.transaction(expired.iter().map(Vec::as_slice), |view| {
+                for key in &expired {
+                    // A key written again since the lookup keeps its new value.
+                    if live_value(view, key)?.is_none() {
+                        view.0.remove(&key[..])?;
+                        set_deadline(view, key, None)?;
+                    }
+                }
+                Ok(())
+            })?;
+            if done {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The trees as seen by a transaction: values, deadlines by key and keys by
+/// deadline.
+type View = (TransactionalTree, TransactionalTree, TransactionalTree);
+
+fn abort<A>(e: KvError) -> ConflictableTransactionResult<A, KvError> {
+    Err(ConflictableTransactionError::Abort(e))
+}
+
+/// The value of `key`, unless it has expired.
+fn live_value(
+    (tree, expiry, _): &View,
+    key: &[u8],
+) -> ConflictableTransactionResult<Option<IVec>, KvError> {
+    if let Some(deadline) = expiry.get(key)? {
+        if expiry::is_expired(decode_deadline(&deadline)) {
+            return Ok(None);
+        }
+    }
+    Ok(tree.get(key)?)
+}
+
+fn set_deadline(
+    (_, expiry, deadlines): &View,
+    key: &[u8],
+    deadline: Option<u64>,
+) -> ConflictableTransactionResult<(), KvError> {
+    if let Some(old) = expiry.remove(key)? {
+        deadlines.remove(queue_key(decode_deadline(&old), key))?;
+    }
+    if let Some(deadline) = deadline {
+        expiry.insert(key, &encode_deadline(deadline)[..])?;
+        deadlines.insert(queue_key(deadline, key), &[][..])?;
+    }
+    Ok(())
+}
+
+/// Key of `key` in `kvs_deadlines`: the deadline, big-endian so keys sort by
+/// it, followed by the key.
+fn queue_key(deadline: u64, key: &[u8]) -> Vec<u8> {
+    let mut queue_key = vec![0; 8];
+    BigEndian::write_u64(&mut queue_key, deadline);
+    queue_key.extend_from_slice(key);
+    queue_key
+}
+
+fn encode_deadline(deadline: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    BigEndian::write_u64(&mut bytes, deadline);
+    bytes
+}
+
+fn decode_deadline(bytes: &[u8]) -> u64 {
+    BigEndian::read_u64(bytes)
+}
+
+/// The thread removing expired keys. It stops when the last engine handle is
+/// dropped.
+struct Reaper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Reaper {
+    fn spawn(trees: Trees, interval: Duration) -> Reaper {
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let handle = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(e) = trees.reap_expired() {
+                        error!("removing expired keys failed: {}", e);
+                    }
+                }
+                _ => return,
+            }
+        });
+        Reaper {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("reaper thread panicked");
+            }
+        }
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    type Snapshot = SledSnapshot;
+
+    /// Writes are held off only while the snapshot is registered; see the
+    /// type for what an open snapshot costs them.
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let saved = Arc::new(Saved::default());
+        let _gate = self.trees.gate.write().unwrap();
+        self.trees
+            .snapshots
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&saved));
+        Ok(SledSnapshot {
+            seq: self.trees.seq.load(Ordering::SeqCst),
+            trees: self.trees.clone(),
+            saved,
+            _lock: self._lock.clone(),
+        })
+    }
+
+    /// Copies a snapshot into a new sled database at `dest`, so writes are
+    /// only held off while the snapshot is taken.
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        backup::create_empty_dir(dest)?;
+        let snapshot = self.snapshot()?;
+        let copy = sled::open(dest)?;
+        let expiry = copy.open_tree("kvs_expiry")?;
+        let deadlines = copy.open_tree("kvs_deadlines")?;
+        let mut range: KeyRange = (Bound::Unbounded, Bound::Unbounded);
+        loop {
+            let page = snapshot.states_page(range.clone(), CHECKPOINT_PAGE)?;
+            let mut values = sled::Batch::default();
+            let mut expiry_batch = sled::Batch::default();
+            let mut deadlines_batch = sled::Batch::default();
+            for (key, (value, deadline)) in &page {
+                values.insert(&key[..], &value[..]);
+                if let Some(deadline) = *deadline {
+                    expiry_batch.insert(&key[..], &encode_deadline(deadline)[..]);
+                    deadlines_batch.insert(queue_key(deadline, key), &[][..]);
+                }
+            }
+            copy.apply_batch(values)?;
+            expiry.apply_batch(expiry_batch)?;
+            deadlines.apply_batch(deadlines_batch)?;
+            match page.last() {
+                Some((key, _)) if page.len() == CHECKPOINT_PAGE => {
+                    range.0 = Bound::Excluded(key.clone());
+                }
+                _ => break,
+            }
+        }
+        copy.flush()?;
+        drop(copy);
+        marker::record_engine(dest, "sled")
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.trees.transaction(Some(&key[..]), |view| {
+            view.0.insert(&key[..], &value[..])?;
+            set_deadline(view, &key, None)
+        })?;
+        self.commit()
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.trees.is_expired(key)? {
+            return Ok(None);
+        }
+        Ok(self.trees.tree.get(key)?.map(|i_vec| i_vec.to_vec()))
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let deadline = expiry::deadline(ttl);
+        self.trees.transaction(Some(&key[..]), |view| {
+            view.0.insert(&key[..], &value[..])?;
+            set_deadline(view, &key, Some(deadline))
+        })?;
+        self.commit()
+    }
+
+    fn expire(&self, key: &[u8], ttl: Duration) -> Result<()> {
+        let deadline = expiry::deadline(ttl);
+        self.trees.transaction(Some(key), |view| {
+            if live_value(view, key)?.is_none() {
+                return abort(KvError::KeyNotExit);
+            }
+            set_deadline(view, key, Some(deadline))
+        })?;
+        self.commit()
+    }
+
+    fn persist(&self, key: &[u8]) -> Result<()> {
+        self.trees.transaction(Some(key), |view| {
+            if live_value(view, key)?.is_none() {
+                return abort(KvError::KeyNotExit);
+            }
+            set_deadline(view, key, None)
+        })?;
+        self.commit()
+    }
+
+    fn ttl(&self, key: &[u8]) -> Result<Option<Duration>> {
+        match self.trees.expiry.get(key)? {
+            Some(deadline) => expiry::remaining(decode_deadline(&deadline))
+                .map(Some)
+                .ok_or(KvError::KeyNotExit),
+            None if self.trees.tree.contains_key(key)? => Ok(None),
+            None => Err(KvError::KeyNotExit),
+        }
+    }
+
+    fn deadline(&self, key: &[u8]) -> Result<Option<u64>> {
+        match self.trees.deadline(key)? {
+            Some(deadline) if expiry::is_expired(deadline) => Err(KvError::KeyNotExit),
+            Some(deadline) => Ok(Some(deadline)),
+            None if self.trees.tree.contains_key(key)? => Ok(None),
+            None => Err(KvError::KeyNotExit),
+        }
+    }
+
+    fn scan_page(&self, range: KeyRange, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        for pair in self.trees.tree.range(range) {
+            if pairs.len() == limit {
+                break;
+            }
+            let (key, value) = pair?;
+            if !self.trees.is_expired(&key)? {
+                pairs.push((key.to_vec(), value.to_vec()));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.trees.transaction(Some(key), |view| {
+            if live_value(view, key)?.is_none() {
+                return abort(KvError::KeyNotExit);
+            }
+            view.0.remove(key)?;
+            set_deadline(view, key, None)
+        })?;
+        self.commit()
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        self.trees.transaction(Some(&key[..]), |view| {
+            let current = live_value(view, &key)?;
+            if current.as_deref() != expected.as_deref() {
+                return abort(KvError::Conflict);
+            }
+            match new {
+                Some(ref value) => {
+                    view.0.insert(&key[..], &value[..])?;
+                }
+                None if current.is_some() => {
+                    view.0.remove(&key[..])?;
+                }
+                None => return Ok(()),
+            }
+            set_deadline(view, &key, None)
+        })?;
+        self.commit()
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.compare_and_write_batch(Vec::new(), batch)
+    }
+
+    fn compare_and_write_batch(
+        &self,
+        expected: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let mut sled_batch = sled::Batch::default();
+        let mut keys = Vec::with_capacity(batch.len());
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set(key, value) => {
+                    keys.push(key.clone());
+                    sled_batch.insert(key, value);
+                }
+                BatchOp::Remove(key) => {
+                    keys.push(key.clone());
+                    sled_batch.remove(key);
+                }
+            }
+        }
+        self.trees
+            .transaction(keys.iter().map(Vec::as_slice), |view| {
+                for (key, value) in &expected {
+                    if live_value(view, key)?.as_deref() != value.as_deref() {
+                        return abort(KvError::Conflict);
+                    }
+                }
+                view.0.apply_batch(&sled_batch)?;
+                for key in &keys {
+                    set_deadline(view, key, None)?;
+                }
+                Ok(())
+            })?;
+        self.commit()
+    }
+}
+
+/// A snapshot of a `SledKvsEngine`. It keeps the directory locked while
+/// open, like the engine.
+#[derive(Clone)]
+pub struct SledSnapshot {
+    seq: u64,
+    trees: Trees,
+    saved: Arc<Saved>,
+    _lock: Arc<DirLock>,
+}
+
+impl SledSnapshot {
+    /// Up to `limit` keys in `range` that have not expired, in key order,
+    /// with their values and deadlines.
+    fn states_page(&self, range: KeyRange, limit: usize) -> Result<Vec<(Vec<u8>, State)>> {
+        if scan::is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let now = expiry::now();
+        // Keys come from the live tree unless the snapshot saved them, in
+        // which case the saved state replaces the live one.
+        let saved = self.saved.lock().unwrap();
+        let mut saved_keys = saved.range(range.clone()).peekable();
+        let mut live_keys = self.trees.tree.range(range);
+        let mut next_live = live_keys.next().transpose()?;
+        let mut states = Vec::new();
+        while states.len() < limit {
+            let take_saved = match (saved_keys.peek(), &next_live) {
+                (None, None) => break,
+                (Some((key, _)), Some((live_key, _))) => key[..] <= live_key[..],
+                (saved, _) => saved.is_some(),
+            };
+            let (key, state) = if take_saved {
+                let (key, state) = saved_keys.next().unwrap();
+                if next_live
+                    .as_ref()
+                    .is_some_and(|(live_key, _)| live_key == key)
+                {
+                    next_live = live_keys.next().transpose()?;
+                }
+                (key.clone(), state.clone())
+            } else {
+                let (key, value) = next_live.take().unwrap();
+                next_live = live_keys.next().transpose()?;
+                let deadline = self.trees.deadline(&key)?;
+                (key.to_vec(), Some((value.to_vec(), deadline)))
+            };
+            if let Some(state) = state.filter(|state| is_live(state, now)) {
+                states.push((key, state));
+            }
+        }
+        Ok(states)
+    }
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let saved = self.saved.lock().unwrap();
+        let state = match saved.get(key) {
+            Some(state) => state.clone(),
+            None => self.trees.state(key)?,
+        };
+        Ok(state
+            .filter(|state| is_live(state, expiry::now()))
+            .map(|(value, _)| value))
+    }
+
+    fn scan_page(&self, range: KeyRange, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .states_page(range, limit)?
+            .into_iter()
+            .map(|(key, (value, _))| (key, value))
+            .collect())
+    }
+}
+
+/// Whether `state` has not expired by `now`.
+fn is_live((_, expires_at): &State, now: u64) -> bool {
+    expires_at.is_none_or(|deadline| deadline > now)
+}
+
+impl ScanSource for SledSnapshot {
+    fn fetch_page(&self, range: KeyRange, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_page(range, limit)
+    }
+}
+
+/// Opens the database `config` describes, whose directory is locked.
+///
+/// sled finishes its writes in the background after the last handle is
+/// dropped, and keeps its own lock on the database until then, so a store
+/// closed just before may still hold it for a moment.
+fn open_db(config: &Config) -> Result<Db> {
+    let mut attempts = 0;
+    loop {
+        match config.open() {
+            Err(sled::Error::Io(ref e))
+                if e.to_string().starts_with("could not acquire lock")
+                    && attempts < OPEN_ATTEMPTS =>
+            {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(10));
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// The version of sled that wrote the store in `dir`, if it is older than
+/// this build can open. sled records its version in `conf`, which it has
+/// written as text since 0.29.
+pub fn old_format(dir: &Path) -> Result<Option<String>> {
+    let conf = match fs::read(dir.join("conf")) {
+        Ok(conf) => conf,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    // sled ignores a `conf` this short and writes a new one; it ends in a
+    // CRC32 otherwise.
+    if conf.len() <= 8 {
+        return Ok(None);
+    }
+    let text = String::from_utf8_lossy(&conf[..conf.len() - 4]);
+    let version = text
+        .lines()
+        .find_map(|line| line.strip_prefix("version: "))
+        .and_then(|version| version.split_once('.'))
+        .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)));
+    match version {
+        Some(version) if version >= SLED_FORMAT => Ok(None),
+        Some((major, minor)) => Ok(Some(format!("{}.{}", major, minor))),
+        None => Ok(Some("0.28 or older".to_owned())),
     }
 }
@@ -0,0 +1,41 @@
+use crate::engine::scan::{self, KeyRange, Scan, ScanSource};
+use crate::error::Result;
+use std::ops::RangeBounds;
+
+/// A read-only view of an engine as it was when `KvsEngine::snapshot` was
+/// called.
+///
+/// Writes made after that do not show up in the snapshot. Deadlines are
+/// still checked at read time, so a key that expires since disappears from
+/// it as well. Handles are cheap to clone; whatever the engine keeps around
+/// for the snapshot is released along with the last one.
+pub trait KvsSnapshot: ScanSource + Send + 'static {
+    /// Number of writes made to the engine, all of which the snapshot sees.
+    /// `KvStore` counts every write since the store was created or last
+    /// upgraded; sled only those since it was opened.
+    fn seq(&self) -> u64;
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Up to `limit` pairs with keys in `range`, in key order.
+    fn scan_page(&self, range: KeyRange, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan<Self> {
+        let range = (
+            range.start_bound().map(Clone::clone),
+            range.end_bound().map(Clone::clone),
+        );
+        Scan::new(self.clone(), range)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Scan<Self> {
+        Scan::new(self.clone(), scan::prefix_range(prefix))
+    }
+}
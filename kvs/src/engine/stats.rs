@@ -0,0 +1,56 @@
+/// Space usage of one segment.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SegmentStats {
+    pub id: u32,
+    /// Bytes of records that hold the latest value of a key.
+    pub live_bytes: u64,
+    /// Bytes of overwritten and removed values, and of tombstones.
+    pub dead_bytes: u64,
+}
+
+impl SegmentStats {
+    pub fn dead_ratio(&self) -> f64 {
+        ratio(self.dead_bytes, self.live_bytes + self.dead_bytes)
+    }
+}
+
+/// A store's space usage, as returned by `KvStore::stats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoreStats {
+    /// Number of live keys.
+    pub keys: usize,
+    /// Every segment on disk, oldest first. The last one is the active segment.
+    pub segments: Vec<SegmentStats>,
+}
+
+impl StoreStats {
+    pub fn live_bytes(&self) -> u64 {
+        self.segments.iter().map(|s| s.live_bytes).sum()
+    }
+
+    pub fn dead_bytes(&self) -> u64 {
+        self.segments.iter().map(|s| s.dead_bytes).sum()
+    }
+
+    pub fn dead_ratio(&self) -> f64 {
+        ratio(self.dead_bytes(), self.live_bytes() + self.dead_bytes())
+    }
+}
+
+/// How often an engine has pushed writes towards disk since it was opened,
+/// as returned by `KvStore::sync_stats` and `SledKvsEngine::sync_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SyncStats {
+    /// Buffered writes handed to the OS. sled has no such step.
+    pub flushes: u64,
+    /// fsyncs of the active segment, or flushes of the whole sled database.
+    pub fsyncs: u64,
+}
+
+fn ratio(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
@@ -0,0 +1,78 @@
+use crate::engine::batch::WriteBatch;
+use crate::engine::KvsEngine;
+use crate::error::Result;
+use std::collections::BTreeMap;
+
+/// An optimistic multi-key transaction, started by `KvsEngine::begin`.
+///
+/// Reads go to the engine and are remembered; writes are buffered, and
+/// later reads of the same key see them. Nothing is locked: `commit` applies
+/// the writes all at once, provided every key read still has the value it
+/// was read with.
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    // The value of each key when it was first read, `None` if absent.
+    reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // The buffered writes, `None` for a removal.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(crate) fn new(engine: E) -> Self {
+        Transaction {
+            engine,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.reads.get(key) {
+            return Ok(value.clone());
+        }
+        let value = self.engine.get_bytes(key)?;
+        self.reads.insert(key.to_vec(), value.clone());
+        Ok(value)
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removing a key that does not exist is not an error.
+    pub fn remove_bytes(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Applies the buffered writes. Fails with `KvError::Conflict`, writing
+    /// nothing, if a key read by the transaction has changed since.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        self.engine
+            .compare_and_write_batch(self.reads.into_iter().collect(), batch)
+    }
+}
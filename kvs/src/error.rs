@@ -1,3 +1,6 @@
+// `failure_derive` expands `Fail` impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
 use std::io;
 use std::string::FromUtf8Error;
 #[derive(Debug, Fail)]
@@ -13,6 +16,72 @@ pub enum KvError {
 
     #[fail(display = "key not exit")]
     KeyNotExit,
+
+    #[fail(display = "value does not match the expected one")]
+    Conflict,
+
+    #[fail(display = "corrupted record in log {} at offset {}", log_id, offset)]
+    Corruption { log_id: u32, offset: u64 },
+
+    /// Older stores can be upgraded with `KvStoreOptions::upgrade` or
+    /// `kvs-admin migrate`.
+    #[fail(
+        display = "store has format version {}, but this build supports version {}",
+        found, supported
+    )]
+    FormatVersion { found: u32, supported: u32 },
+
+    #[fail(display = "damaged manifest: {}", _0)]
+    BadManifest(String),
+
+    /// `record` counts from 1, blank lines and a CSV header included.
+    #[fail(display = "malformed dump record {}: {}", record, reason)]
+    BadDump { record: u64, reason: String },
+
+    #[fail(display = "archive: {}", _0)]
+    Archive(String),
+
+    #[fail(display = "convert: {}", _0)]
+    Convert(String),
+
+    #[fail(display = "invalid option: {}", _0)]
+    InvalidOption(String),
+
+    #[fail(display = "no store found at {}", _0)]
+    StoreNotFound(String),
+
+    #[fail(display = "a store already exists at {}", _0)]
+    StoreExists(String),
+
+    /// `pid` is 0 if the holder is not known.
+    #[fail(display = "{} is locked by process {}", path, pid)]
+    Locked { path: String, pid: u32 },
+
+    #[fail(
+        display = "the directory belongs to the {} engine, not {}",
+        recorded, requested
+    )]
+    EngineMismatch { recorded: String, requested: String },
+
+    #[fail(display = "store is read-only")]
+    ReadOnly,
+
+    #[fail(display = "protocol error: {}", _0)]
+    Protocol(String),
+
+    #[fail(display = "thread pool error: {}", _0)]
+    ThreadPool(String),
+
+    /// sled changed its on-disk format between 0.24, which this crate used
+    /// to build on, and 0.34.
+    #[fail(
+        display = "{} was written by sled {}, which this build cannot open; copy it into the current format with `kvs-admin migrate {} --to NEW_DIR`",
+        path, version, path
+    )]
+    OldSledFormat { path: String, version: String },
+
+    #[fail(display = "sled 0.24 error: {}", _0)]
+    OldSled(String),
 }
 
 impl From<io::Error> for KvError {
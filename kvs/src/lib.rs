@@ -1,8 +1,13 @@
 pub use common::Command;
-pub use engine::kv::KvStore;
+pub use engine::batch::WriteBatch;
+pub use engine::durability::Durability;
+pub use engine::kv::{CompactionPolicy, KvSnapshot, KvStore, KvStoreOptions};
+pub use engine::snapshot::KvsSnapshot;
+pub use engine::stats::{SegmentStats, StoreStats, SyncStats};
 pub use engine::KvsEngine;
 pub use error::KvError;
 pub use error::Result;
+pub use thread_pool::ThreadPool;
 
 #[macro_use]
 extern crate failure;
@@ -14,6 +19,8 @@ extern crate serde_json;
 
 pub mod client;
 pub mod common;
+pub mod config;
 pub mod engine;
 mod error;
 pub mod server;
+pub mod thread_pool;
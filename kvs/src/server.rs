@@ -1,37 +1,71 @@
-use crate::common::{Action, Command, Response};
-use crate::engine::sled::SledKvsEngine;
+use crate::common::{self, Action, Command, Response};
+use crate::engine::transaction::Transaction;
 use crate::engine::KvsEngine;
-use crate::KvStore;
+use crate::thread_pool::ThreadPool;
+use crate::KvError;
 use crate::Result;
-use byteorder::{ReadBytesExt, WriteBytesExt, LE};
-use log::LevelFilter;
-use std::env::current_dir;
-use std::io::prelude::*;
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{self, BufReader, BufWriter};
 use std::net::{TcpListener, TcpStream};
-use std::str;
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 
-pub struct KvsServer<T: KvsEngine> {
+/// Most pairs a single `SCAN` returns.
+const MAX_SCAN_PAGE: usize = 1000;
+
+/// How long a connection may go without sending a command before it is
+/// closed, unless set with `KvsServer::idle_timeout`.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct KvsServer<T: KvsEngine, P: ThreadPool> {
     engine: T,
+    pool: P,
     address: String,
+    idle_timeout: Duration,
+    backup_dir: Option<PathBuf>,
 }
-impl<T: KvsEngine> KvsServer<T> {
-    pub fn new(address_: String, engine_: T) -> Self {
+impl<T: KvsEngine, P: ThreadPool> KvsServer<T, P> {
+    pub fn new(address_: String, engine_: T, pool_: P) -> Self {
         KvsServer {
             engine: engine_,
+            pool: pool_,
             address: address_,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            backup_dir: None,
         }
     }
 
-    pub fn run(&mut self) -> Result<()> {
+    /// Closes connections that send nothing for `timeout`, handing their
+    /// pool worker to the next client. A transaction left open by `MULTI`
+    /// is discarded with the connection. Must be positive.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Lets clients write checkpoints with `CHECKPOINT`, into directories
+    /// under `dir`. Without it the command is refused.
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    pub fn run(&self) -> Result<()> {
         let listener = TcpListener::bind(&self.address).expect("could not start server");
-        // accept connections and get a TcpStream
+        // accept connections and hand each one to the pool, which serves it
+        // until the client hangs up or goes idle
         for connection in listener.incoming() {
             match connection {
                 Ok(stream) => {
-                    if let Err(e) = self.handle_connection(stream) {
-                        println!("error {:?}", e);
-                    }
+                    let engine = self.engine.clone();
+                    let idle_timeout = self.idle_timeout;
+                    let backup_dir = self.backup_dir.clone();
+                    self.pool.spawn(move || {
+                        if let Err(e) = handle_connection(engine, stream, idle_timeout, backup_dir)
+                        {
+                            println!("error {:?}", e);
+                        }
+                    })
                 }
                 Err(e) => {
                     println!("connection failed {}", e);
@@ -40,41 +74,179 @@ impl<T: KvsEngine> KvsServer<T> {
         }
         Ok(())
     }
+}
 
-    fn handle_connection(&mut self, stream: TcpStream) -> Result<()> {
-        let mut reader = BufReader::new(&stream);
-        let mut writer = BufWriter::new(&stream);
-        let command_len = reader.read_u32::<LE>()?;
-        info!("server recv: {:?}", command_len);
-        let mut buf = vec![0; command_len as usize];
-        reader.read_exact(&mut buf)?;
-        let command: Command = serde_json::from_str(str::from_utf8(&buf).unwrap()).unwrap();
-        info!("server recv: {:?}", command);
-        let res = self.exec(command);
-        let serialized = serde_json::to_string(&res).unwrap();
-        writer.write_u32::<LE>(serialized.len() as u32)?;
-        writer.flush()?;
-        writer.write(serialized.as_bytes())?;
-        writer.flush()?;
-        Ok(())
+fn handle_connection<T: KvsEngine>(
+    engine: T,
+    stream: TcpStream,
+    idle_timeout: Duration,
+    backup_dir: Option<PathBuf>,
+) -> Result<()> {
+    // Each connection holds a pool worker for as long as it is open.
+    stream.set_read_timeout(Some(idle_timeout))?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let mut session = Session {
+        engine,
+        backup_dir,
+        txn: None,
+    };
+    loop {
+        let command: Command = match common::read_message(&mut reader) {
+            Ok(command) => command,
+            // The client hung up between commands.
+            Err(KvError::IoError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(())
+            }
+            Err(KvError::IoError(ref e))
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                debug!("closing a connection idle for {:?}", idle_timeout);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        debug!("server recv: {:?}", command);
+        let res = session.exec(command);
+        common::write_message(&mut writer, &res)?;
     }
+}
+
+/// A client connection, and the transaction it opened with `MULTI`.
+struct Session<T: KvsEngine> {
+    engine: T,
+    backup_dir: Option<PathBuf>,
+    txn: Option<Transaction<T>>,
+}
 
+impl<T: KvsEngine> Session<T> {
     fn exec(&mut self, command: Command) -> Response {
         match command.action {
-            Action::GET => match self.engine.get(command.key).unwrap() {
-                Some(value) => Response::Ok(Some(value)),
-                None => Response::Err("Key not found".to_owned()),
-            },
-            Action::SET => {
-                self.engine
-                    .set(command.key.to_owned(), command.value.to_owned())
-                    .unwrap();
+            Action::MULTI if self.txn.is_some() => {
+                Response::Err("a transaction is already in progress".to_owned())
+            }
+            Action::MULTI => {
+                self.txn = Some(self.engine.begin());
                 Response::Ok(None)
             }
-            Action::RM => match self.engine.remove(command.key.to_owned()) {
-                Ok(_) => Response::Ok(None),
-                Err(_) => Response::Err("Key not found".to_owned()),
+            Action::EXEC => match self.txn.take() {
+                Some(txn) => write_response(txn.commit()),
+                None => Response::Err("no transaction in progress".to_owned()),
+            },
+            Action::DISCARD => match self.txn.take() {
+                Some(_) => Response::Ok(None),
+                None => Response::Err("no transaction in progress".to_owned()),
+            },
+            _ => match self.txn {
+                Some(ref mut txn) => exec_in_transaction(txn, command),
+                None => exec(&self.engine, self.backup_dir.as_deref(), command),
             },
         }
     }
 }
+
+fn exec_in_transaction<T: KvsEngine>(txn: &mut Transaction<T>, command: Command) -> Response {
+    match command.action {
+        Action::GET => match txn.get_bytes(&command.key) {
+            Ok(Some(value)) => Response::Ok(Some(value)),
+            Ok(None) => Response::Err("Key not found".to_owned()),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Action::SET => {
+            txn.set_bytes(command.key, command.value);
+            Response::Ok(None)
+        }
+        Action::RM => {
+            txn.remove_bytes(&command.key);
+            Response::Ok(None)
+        }
+        _ => Response::Err("only GET, SET and RM can be used in a transaction".to_owned()),
+    }
+}
+
+fn exec<T: KvsEngine>(engine: &T, backup_dir: Option<&Path>, command: Command) -> Response {
+    match command.action {
+        Action::GET => match engine.get_bytes(&command.key) {
+            Ok(Some(value)) => Response::Ok(Some(value)),
+            Ok(None) => Response::Err("Key not found".to_owned()),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Action::SET => write_response(engine.set_bytes(command.key, command.value)),
+        Action::RM => write_response(engine.remove_bytes(&command.key)),
+        Action::BATCH(batch) => write_response(engine.write_batch(batch)),
+        Action::CAS { expected, new } => {
+            write_response(engine.compare_and_swap(command.key, expected, new))
+        }
+        Action::SETNX => write_response(engine.set_if_absent(command.key, command.value)),
+        Action::RMEQ => write_response(engine.remove_if_equals(command.key, command.value)),
+        Action::SETEX { ttl_ms } => write_response(engine.set_with_ttl(
+            command.key,
+            command.value,
+            Duration::from_millis(ttl_ms),
+        )),
+        Action::EXPIRE { ttl_ms } => {
+            write_response(engine.expire(&command.key, Duration::from_millis(ttl_ms)))
+        }
+        Action::PERSIST => write_response(engine.persist(&command.key)),
+        Action::CHECKPOINT { dest } => match checkpoint_dest(backup_dir, &dest) {
+            Ok(dest) => write_response(engine.checkpoint(&dest)),
+            Err(err) => Response::Err(err.to_owned()),
+        },
+        Action::TTL => match engine.ttl(&command.key) {
+            Ok(ttl) => Response::Ttl(ttl.map(|ttl| ttl.as_millis() as u64)),
+            Err(KvError::KeyNotExit) => Response::Err("Key not found".to_owned()),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Action::SCAN { end, limit } => {
+            let limit = (limit as usize).min(MAX_SCAN_PAGE);
+            let range = (
+                Bound::Included(command.key),
+                end.map_or(Bound::Unbounded, Bound::Excluded),
+            );
+            match engine.scan_page(range, limit) {
+                Ok(pairs) => {
+                    // The smallest key after the last one returned.
+                    let cursor = match pairs.last() {
+                        Some((key, _)) if pairs.len() == limit => {
+                            let mut cursor = key.clone();
+                            cursor.push(0);
+                            Some(cursor)
+                        }
+                        _ => None,
+                    };
+                    Response::Scan { pairs, cursor }
+                }
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Action::MULTI | Action::EXEC | Action::DISCARD => {
+            unreachable!("transactions are handled by the session")
+        }
+    }
+}
+
+/// Where `CHECKPOINT` writes `dest`: under the backup directory, which a
+/// client cannot leave.
+fn checkpoint_dest(
+    backup_dir: Option<&Path>,
+    dest: &str,
+) -> std::result::Result<PathBuf, &'static str> {
+    let backup_dir = backup_dir.ok_or("the server has no backup directory for checkpoints")?;
+    let dest = Path::new(dest);
+    let inside = dest
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if dest.as_os_str().is_empty() || !inside {
+        return Err("the checkpoint destination must be a relative path without `..`");
+    }
+    Ok(backup_dir.join(dest))
+}
+
+fn write_response(result: Result<()>) -> Response {
+    match result {
+        Ok(_) => Response::Ok(None),
+        Err(KvError::Conflict) => Response::Conflict,
+        Err(KvError::KeyNotExit) => Response::Err("Key not found".to_owned()),
+        Err(e) => Response::Err(e.to_string()),
+    }
+}
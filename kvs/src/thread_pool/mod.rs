@@ -0,0 +1,24 @@
+use crate::error::Result;
+
+/// A pool of threads that runs jobs handed to it.
+pub trait ThreadPool {
+    /// Creates a pool with `threads` worker threads.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs `job` on one of the pool's threads.
+    ///
+    /// A job that panics must not take the pool down with it.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
@@ -0,0 +1,36 @@
+use super::ThreadPool;
+use crate::error::{KvError, Result};
+use std::any::Any;
+
+/// A thin wrapper over a `rayon` pool. A job that panics is logged and the
+/// worker carries on.
+pub struct RayonThreadPool(rayon::ThreadPool);
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // Without a handler, rayon aborts the process on a panicking job.
+            .panic_handler(|panic| error!("a pool job panicked: {}", panic_message(&*panic)))
+            .build()
+            .map_err(|e| KvError::ThreadPool(e.to_string()))?;
+        Ok(RayonThreadPool(pool))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.spawn(job)
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
@@ -0,0 +1,58 @@
+use super::ThreadPool;
+use crate::error::Result;
+use crossbeam_channel::{Receiver, Sender};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of workers pulling jobs off one shared queue.
+///
+/// A worker whose job panics is replaced by a new one, so the pool keeps its
+/// size no matter what the jobs do.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = crossbeam_channel::unbounded::<Job>();
+        for _ in 0..threads {
+            spawn_worker(Worker(receiver.clone()))?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("no worker left in the thread pool");
+    }
+}
+
+struct Worker(Receiver<Job>);
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let worker = Worker(self.0.clone());
+            if let Err(e) = spawn_worker(worker) {
+                error!("failed to replace a panicked worker: {}", e);
+            }
+        }
+    }
+}
+
+fn spawn_worker(worker: Worker) -> Result<()> {
+    thread::Builder::new().spawn(move || run_jobs(worker))?;
+    Ok(())
+}
+
+fn run_jobs(worker: Worker) {
+    // Ends once the pool, and with it every sender, is dropped.
+    while let Ok(job) = worker.0.recv() {
+        job();
+    }
+}
@@ -1,4 +1,8 @@
 use assert_cmd::prelude::*;
+use kvs::client::KvsClient;
+use kvs::common::{Action, Response};
+use kvs::engine::sled::SledKvsEngine;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, WriteBatch};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -6,9 +10,6 @@ use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
-#[macro_use]
-extern crate log;
-use log::LevelFilter;
 
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
@@ -23,28 +24,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -55,35 +56,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -94,28 +95,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -126,7 +127,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -137,7 +138,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -148,7 +149,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -160,13 +161,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -174,6 +176,608 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// `kvs-admin migrate` upgrades an old store in place, or into a new
+// directory.
+#[test]
+fn cli_admin_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let old = temp_dir.path().join("old");
+    let store = KvStore::open(&old).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+    fs::remove_file(old.join("MANIFEST")).unwrap();
+    fs::write(old.join("current"), 0u32.to_le_bytes()).unwrap();
+    fs::write(old.join("engine"), "kvs\n").unwrap();
+
+    let admin = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-admin").unwrap();
+        command.args(args).current_dir(&temp_dir);
+        command
+    };
+    admin(&["migrate", "old", "--to", "new"])
+        .assert()
+        .success()
+        .stdout(contains("format version 0"));
+    assert!(old.join("current").exists());
+    let store = KvStore::open(temp_dir.path().join("new")).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    drop(store);
+    admin(&["migrate", "old", "--to", "new"]).assert().failure();
+
+    admin(&["migrate", "old"])
+        .assert()
+        .success()
+        .stdout(contains("upgraded"));
+    admin(&["migrate", "old"])
+        .assert()
+        .success()
+        .stdout(contains("already"));
+    let store = KvStore::open(&old).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// `kvs-admin verify`, `inspect`, `stats` and `repair` work on a store that
+// is not open, damaged or not.
+#[test]
+fn cli_admin_verify_inspect_stats_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    let data = temp_dir.path().join("data");
+    let store = KvStore::open_with(&data, KvStoreOptions::new().segment_size(4096)).unwrap();
+    for i in 0..200 {
+        store.set(format!("key{}", i), "value".repeat(10)).unwrap();
+    }
+    store.remove("key0".to_owned()).unwrap();
+    drop(store);
+    fs::write(data.join("engine"), "kvs\n").unwrap();
+
+    let admin = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-admin").unwrap();
+        command.args(args).current_dir(&temp_dir);
+        command
+    };
+    admin(&["verify", "data"])
+        .assert()
+        .success()
+        .stdout(contains("201 records"))
+        .stdout(contains("0 damaged ranges"));
+    admin(&["inspect", "data"])
+        .assert()
+        .success()
+        .stdout(contains("set    \"key1\" value 50"))
+        .stdout(contains("remove \"key0\""));
+    admin(&["stats", "data"])
+        .assert()
+        .success()
+        .stdout(contains("total"))
+        .stdout(contains("199 keys"));
+
+    let first = fs::read_dir(&data)
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            name.strip_prefix("log_")?.parse::<u32>().ok()
+        })
+        .min()
+        .unwrap();
+    let log_path = data.join(format!("log_{}", first));
+    let mut bytes = fs::read(&log_path).unwrap();
+    bytes[21] ^= 0x01;
+    fs::write(&log_path, bytes).unwrap();
+
+    admin(&["verify", "data"])
+        .assert()
+        .failure()
+        .stdout(contains(format!("damaged: log_{} bytes 0..", first)));
+    admin(&["inspect", "data", "--segment", &first.to_string()])
+        .assert()
+        .success()
+        .stdout(contains(format!("damaged: log_{} bytes 0..", first)));
+    admin(&["repair", "data", "fixed"])
+        .assert()
+        .success()
+        .stdout(contains("lost damaged"))
+        .stdout(contains("salvaged 200 records, 199 keys"));
+    admin(&["verify", "fixed"]).assert().success();
+    admin(&["repair", "data", "fixed"]).assert().failure();
+}
+
+// A server refuses a directory sled 0.24 wrote, and `kvs-admin migrate`
+// copies it into the current format.
+#[test]
+fn cli_admin_migrate_old_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    let old = temp_dir.path().join("old");
+    {
+        let db = sled_legacy::Db::start_default(&old).unwrap();
+        db.set(b"key1", b"value1".to_vec()).unwrap();
+        db.flush().unwrap();
+    }
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4028"])
+        .current_dir(&old)
+        .assert()
+        .failure()
+        .stderr(contains("kvs-admin migrate"));
+
+    let admin = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-admin").unwrap();
+        command.args(args).current_dir(&temp_dir);
+        command
+    };
+    admin(&["migrate", "old"]).assert().failure();
+    admin(&["migrate", "old", "--to", "new"])
+        .assert()
+        .success()
+        .stdout(contains("copied 1 keys"));
+    admin(&["migrate", "new"])
+        .assert()
+        .success()
+        .stdout(contains("already"));
+    let engine = SledKvsEngine::open(temp_dir.path().join("new")).unwrap();
+    assert_eq!(
+        engine.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// A running server writes a checkpoint on request, into its backup
+// directory only, which `kvs-admin restore` turns back into a store.
+#[test]
+fn cli_checkpoint_and_restore() {
+    let addr = "127.0.0.1:4024";
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    let backups = temp_dir.path().join("backups");
+    fs::write(&config, format!("backup_dir = {:?}\n", backups)).unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str], addr: &str| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(["--addr", addr]);
+        command
+    };
+    client(&["set", "key1", "value1"], addr).assert().success();
+    let checkpointed = client(&["checkpoint", "nightly"], addr).output().unwrap();
+    let outside = temp_dir.path().join("outside");
+    let absolute = client(&["checkpoint", outside.to_str().unwrap()], addr)
+        .output()
+        .unwrap();
+    let escaping = client(&["checkpoint", "nightly/../../outside"], addr)
+        .output()
+        .unwrap();
+    client(&["set", "key2", "value2"], addr).assert().success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    assert!(checkpointed.status.success());
+    for refused in [absolute, escaping] {
+        assert!(!refused.status.success());
+        assert!(String::from_utf8_lossy(&refused.stderr).contains("relative path without `..`"));
+    }
+    assert!(!outside.exists());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["restore", "backups/nightly", "restored"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("restored 1 keys"));
+    let store = KvStore::open(temp_dir.path().join("restored")).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+    drop(store);
+
+    // Without a backup directory there is nowhere to write to.
+    let addr = "127.0.0.1:4031";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let refused = client(&["checkpoint", "weekly"], addr).output().unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("no backup directory"));
+}
+
+// A server dual-writing to the other engine leaves a copy with the same
+// checksum, which a server can then be started on; `kvs-admin convert`
+// makes the same copy offline, and swaps it in with `--cut-over`.
+#[test]
+fn cli_dual_write_and_convert() {
+    let addr = "127.0.0.1:4026";
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let store = KvStore::open(&data_dir).unwrap();
+    for i in 0..100 {
+        store.set(format!("key{}", i), "old".to_owned()).unwrap();
+    }
+    drop(store);
+    fs::write(data_dir.join("engine"), "kvs\n").unwrap();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--dual-write", "../mirror"])
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(["--addr", addr]);
+        command
+    };
+    client(&["set", "key1", "new"]).assert().success();
+    client(&["rm", "key2"]).assert().success();
+    client(&["set", "key100", "added"]).assert().success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    let admin = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-admin").unwrap();
+        command.args(args).current_dir(&temp_dir);
+        command
+    };
+    let checksum = |dir: &str| {
+        let output = admin(&["checksum", dir]).output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert!(checksum("data").starts_with("100 keys"));
+    assert_eq!(checksum("data"), checksum("mirror"));
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("mirror/engine")).unwrap(),
+        "sled\n"
+    );
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(temp_dir.path().join("mirror"))
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    client(&["get", "key1"]).assert().success().stdout("new\n");
+    client(&["get", "key100"])
+        .assert()
+        .success()
+        .stdout("added\n");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    admin(&["convert", "mirror", "back", "--engine", "kvs"])
+        .assert()
+        .success()
+        .stdout(contains("100 keys"));
+    assert_eq!(checksum("back"), checksum("data"));
+    admin(&["convert", "mirror", "back", "--engine", "kvs"])
+        .assert()
+        .failure();
+
+    admin(&[
+        "convert",
+        "data",
+        "converted",
+        "--engine",
+        "sled",
+        "--cut-over",
+    ])
+    .assert()
+    .success()
+    .stdout(contains("data is now the sled store"));
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("data/engine")).unwrap(),
+        "sled\n"
+    );
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("converted/engine")).unwrap(),
+        "kvs\n"
+    );
+    assert_eq!(checksum("data"), checksum("converted"));
+}
+
+// The kvs store a sled server mirrors to should get the `[kvs]` tuning of the
+// config file, but not its settings for how a served store is used.
+#[test]
+fn cli_dual_write_uses_kvs_config() {
+    let addr = "127.0.0.1:4030";
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config,
+        "[kvs]\nsegment_size = 1\nread_only = true\nerror_if_exists = true\narchive_dir = \"../archive\"\n",
+    )
+    .unwrap();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "--engine",
+            "sled",
+            "--dual-write",
+            "../mirror",
+        ])
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let set = |key: &str| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, "value", "--addr", addr])
+            .output()
+            .unwrap()
+    };
+    let outputs: Vec<_> = ["key1", "key2", "key3"]
+        .iter()
+        .map(|key| set(key))
+        .collect();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    assert!(outputs.iter().all(|output| output.status.success()));
+
+    // Every write sealed the segment it went to.
+    let segments = fs::read_dir(temp_dir.path().join("mirror"))
+        .unwrap()
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_string_lossy().starts_with("log_")
+        })
+        .count();
+    assert!(segments >= 3, "{} segments", segments);
+    assert!(!temp_dir.path().join("archive").exists());
+}
+
+// A server archiving its store, with every write sealed into a segment of
+// its own, can be restored to any write.
+#[test]
+fn cli_archive_and_restore_until() {
+    let addr = "127.0.0.1:4025";
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config,
+        format!(
+            "[kvs]\nsegment_size = 1\narchive_dir = {:?}\n",
+            temp_dir.path().join("archive")
+        ),
+    )
+    .unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--config"])
+        .arg(&config)
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(["--addr", addr]);
+        command
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["set", "key1", "value2"]).assert().success();
+    client(&["set", "key2", "value2"]).assert().success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    let admin = || {
+        let mut command = Command::cargo_bin("kvs-admin").unwrap();
+        command.current_dir(&temp_dir);
+        command
+    };
+    admin()
+        .args(["restore", "archive", "restored", "--until", "1"])
+        .assert()
+        .success()
+        .stdout(contains("restored 1 keys to restored as of write 1"));
+    let store = KvStore::open(temp_dir.path().join("restored")).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+    admin()
+        .args(["restore", "archive", "later", "--until", "4"])
+        .assert()
+        .failure()
+        .stderr(contains("only reaches write 3"));
+}
+
+// A kvs store dumped with kvs-admin loads into a new sled store, and an
+// interrupted dump picks up after the last key it reported.
+#[test]
+fn cli_admin_dump_and_load() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path().join("kvs")).unwrap();
+    let mut batch = WriteBatch::new();
+    for i in 0..10_001 {
+        batch.set(
+            format!("key{:05}", i).into_bytes(),
+            i.to_string().into_bytes(),
+        );
+    }
+    store.write_batch(batch).unwrap();
+    drop(store);
+
+    let admin = || {
+        let mut command = Command::cargo_bin("kvs-admin").unwrap();
+        command.current_dir(&temp_dir);
+        command
+    };
+    admin()
+        .args(["dump", "kvs", "--format", "csv", "--output", "dump.csv"])
+        .assert()
+        .success()
+        .stderr(contains(
+            "dumped 10000 keys, resume with --after \"key09999\"",
+        ))
+        .stderr(contains("dumped 10001 keys"));
+    admin()
+        .args([
+            "load", "sled", "dump.csv", "--format", "csv", "--engine", "sled",
+        ])
+        .assert()
+        .success()
+        .stderr(contains("loaded 10001 keys"));
+    let engine = SledKvsEngine::open(temp_dir.path().join("sled")).unwrap();
+    assert_eq!(
+        engine.get("key10000".to_owned()).unwrap(),
+        Some("10000".to_owned())
+    );
+    drop(engine);
+    admin()
+        .args([
+            "load", "sled", "dump.csv", "--format", "csv", "--engine", "kvs",
+        ])
+        .assert()
+        .failure()
+        .stderr(contains("belongs to the sled engine"));
+
+    admin()
+        .args([
+            "dump",
+            "kvs",
+            "--output",
+            "dump.jsonl",
+            "--after",
+            "key09998",
+        ])
+        .assert()
+        .success();
+    let dumped = fs::read_to_string(temp_dir.path().join("dump.jsonl")).unwrap();
+    assert_eq!(
+        dumped.lines().collect::<Vec<_>>(),
+        [
+            r#"{"key":"key09999","value":"9999"}"#,
+            r#"{"key":"key10000","value":"10000"}"#
+        ]
+    );
+}
+
+// A dump cut off mid-record, even inside a quoted CSV value, resumes into
+// the same file without torn or repeated records.
+#[test]
+fn cli_admin_dump_resumes_after_partial_dump() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path().join("kvs")).unwrap();
+    for i in 0..20 {
+        store
+            .set(format!("key{:02}", i), format!("line one\nline {}", i))
+            .unwrap();
+    }
+    drop(store);
+
+    let admin = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-admin").unwrap();
+        command.args(args).current_dir(&temp_dir);
+        command
+    };
+    for (format, cut_after) in [("jsonl", "line 12"), ("csv", "\"line one\n")] {
+        let full = format!("full.{}", format);
+        let partial = format!("partial.{}", format);
+        admin(&["dump", "kvs", "--format", format, "--output", &full])
+            .assert()
+            .success();
+        let dumped = fs::read_to_string(temp_dir.path().join(&full)).unwrap();
+        // Past the record of key11, the last one reported.
+        let key12 = dumped.find("key12").unwrap();
+        let cut = key12 + dumped[key12..].find(cut_after).unwrap() + cut_after.len();
+        fs::write(temp_dir.path().join(&partial), &dumped[..cut]).unwrap();
+
+        admin(&[
+            "dump", "kvs", "--format", format, "--output", &partial, "--after", "key11",
+        ])
+        .assert()
+        .success();
+        let resumed = fs::read_to_string(temp_dir.path().join(&partial)).unwrap();
+        assert_eq!(resumed, dumped);
+    }
+}
+
+// A CSV row with too few or too many fields is reported, not a panic.
+#[test]
+fn cli_admin_load_malformed_csv() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("short.csv"), "a,b\n").unwrap();
+    fs::write(
+        temp_dir.path().join("long.csv"),
+        "key,value,encoding,expires_at\nc,1,,\nd,2,,,extra\n",
+    )
+    .unwrap();
+    let load = |file: &str| {
+        let mut command = Command::cargo_bin("kvs-admin").unwrap();
+        command
+            .args(["load", "store", file, "--format", "csv"])
+            .current_dir(&temp_dir);
+        command
+    };
+    load("short.csv").assert().code(1).stderr(contains(
+        "malformed dump record 1: expected 4 fields, found 2",
+    ));
+    load("long.csv").assert().code(1).stderr(contains(
+        "malformed dump record 3: expected 4 fields, found 5",
+    ));
+}
+
+// A second server refuses a directory that is already being served.
+#[test]
+fn cli_locked_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4021"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4022"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(format!("locked by process {}", child.id())));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -181,17 +785,19 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
-            .failure();
+            .failure()
+            .stderr(contains("belongs to the sled engine"));
     }
 
     // kvs first, sled second
@@ -199,39 +805,86 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
-            .failure();
+            .failure()
+            .stderr(contains("belongs to the kvs engine"));
     }
 }
 
+// Without `--engine`, the server opens the directory with the engine that
+// created it.
+#[test]
+fn cli_recorded_engine() {
+    let addr = "127.0.0.1:4023";
+    let temp_dir = TempDir::new().unwrap();
+    let server = |args: &[&str]| {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir);
+        command
+    };
+
+    let mut child = server(&["--engine", "sled"]);
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "key1", "value1"]).assert().success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "sled\n"
+    );
+
+    let mut child = server(&[]);
+    thread::sleep(Duration::from_secs(1));
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -239,7 +892,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -247,7 +900,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -255,7 +908,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -263,7 +916,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -271,7 +924,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -279,7 +932,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -287,7 +940,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -300,26 +953,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -337,3 +991,493 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn server_cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config_path,
+        "addr = \"127.0.0.1:4006\"\nengine = \"kvs\"\ndurability = \"fsync\"\n\n[kvs]\nsegment_size = 1024\ncompaction = \"manual\"\n",
+    )
+    .unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", config_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for i in 0..20 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", &format!("key{}", i), &"x".repeat(100)])
+            .args(["--addr", "127.0.0.1:4006"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    // Small segments and no compaction leave many segments behind.
+    let segments = fs::read_dir(&temp_dir)
+        .unwrap()
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_str().unwrap().starts_with("log_")
+        })
+        .count();
+    assert!(segments > 1, "only {} segments", segments);
+}
+
+#[test]
+fn server_cli_invalid_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config_path,
+        "engine = \"kvs\"\n[kvs]\nsegment_sise = 1024\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", config_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("segment_sise"));
+}
+
+#[test]
+fn cli_binary_values() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "ff00", "00fffe", "--encoding", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "/wA=", "--encoding", "base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("AP/+\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "ff00", "--encoding", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("00fffe\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "74657874",
+            "c328",
+            "--encoding",
+            "hex",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "text", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("UTF-8"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "zz", "--encoding", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_scan() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for key in ["a1", "a2", "a3", "b1"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, &format!("v{}", key), "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a1\tva1\na2\tva2\na3\tva3\nb1\tvb1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "scan",
+            "--prefix",
+            "61",
+            "--limit",
+            "2",
+            "--encoding",
+            "hex",
+        ])
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("6131\t766131\n6132\t766132\n")
+        .stderr(contains("cursor: 613200"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "scan",
+            "--prefix",
+            "61",
+            "--cursor",
+            "613200",
+            "--encoding",
+            "hex",
+        ])
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("6133\t766133\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--start", "a2", "--end", "b", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a2\tva2\na3\tva3\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn server_batch_request() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new();
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1", "value1")
+        .set("key2", "value2")
+        .remove("key1");
+    let command = kvs::Command::new(Action::BATCH(batch), Vec::new(), Vec::new());
+    match client.send_command(&command, addr).unwrap() {
+        Response::Ok(None) => {}
+        other => panic!("unexpected response {:?}", other),
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue2\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_compare_and_swap() {
+    let addr = "127.0.0.1:4018";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir);
+        command
+    };
+    client(&["set-if-absent", "key1", "value1"])
+        .assert()
+        .success();
+    client(&["set-if-absent", "key1", "value2"])
+        .assert()
+        .code(2);
+    client(&["cas", "key1", "--expected", "value2", "--new", "value3"])
+        .assert()
+        .code(2);
+    client(&["cas", "key1", "--expected", "value1", "--new", "value3"])
+        .assert()
+        .success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value3\n");
+    client(&["rm-if-equals", "key1", "value1"]).assert().code(2);
+    client(&["cas", "key1", "--expected", "value3"])
+        .assert()
+        .success();
+    client(&["cas", "key1", "--new", "value4"])
+        .assert()
+        .success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value4\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_ttl() {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir);
+        command
+    };
+    client(&["set", "key1", "value1", "--ttl", "1h"])
+        .assert()
+        .success();
+    client(&["ttl", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("ms\n"));
+    client(&["set", "key2", "value2"]).assert().success();
+    client(&["ttl", "key2"])
+        .assert()
+        .success()
+        .stdout("no expiry\n");
+    client(&["persist", "key1"]).assert().success();
+    client(&["ttl", "key1"])
+        .assert()
+        .success()
+        .stdout("no expiry\n");
+    client(&["expire", "key2", "100ms"]).assert().success();
+    client(&["set", "key3", "value3", "--ttl", "bogus"])
+        .assert()
+        .failure();
+
+    thread::sleep(Duration::from_millis(300));
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    client(&["ttl", "key2"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_transaction_session() {
+    let addr = "127.0.0.1:4020";
+    let temp_dir = TempDir::new().unwrap();
+    // The open session holds one pool thread; the other clients need more.
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "4"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new();
+    let command = |action, key: &str, value: &str| {
+        kvs::Command::new(action, key.as_bytes().to_vec(), value.as_bytes().to_vec())
+    };
+    client
+        .send_command(&command(Action::SET, "key1", "value1"), addr)
+        .unwrap();
+
+    let mut session = client.connect(addr).unwrap();
+    let mut send = |action, key, value| session.send(&command(action, key, value)).unwrap();
+    assert!(matches!(send(Action::EXEC, "", ""), Response::Err(_)));
+    assert!(matches!(send(Action::MULTI, "", ""), Response::Ok(None)));
+    assert!(matches!(send(Action::MULTI, "", ""), Response::Err(_)));
+    match send(Action::GET, "key1", "") {
+        Response::Ok(Some(value)) => assert_eq!(value, b"value1"),
+        other => panic!("unexpected response {:?}", other),
+    }
+    send(Action::SET, "key2", "value2");
+    send(Action::RM, "key1", "");
+    assert!(matches!(send(Action::GET, "key1", ""), Response::Err(_)));
+    assert!(matches!(send(Action::EXEC, "", ""), Response::Ok(None)));
+
+    let get = |key| {
+        client
+            .send_command(&command(Action::GET, key, ""), addr)
+            .unwrap()
+    };
+    assert!(matches!(get("key1"), Response::Err(_)));
+    match get("key2") {
+        Response::Ok(Some(value)) => assert_eq!(value, b"value2"),
+        other => panic!("unexpected response {:?}", other),
+    }
+
+    // Another client changes a key the transaction read.
+    send(Action::MULTI, "", "");
+    send(Action::GET, "key2", "");
+    send(Action::SET, "key3", "value3");
+    client
+        .send_command(&command(Action::SET, "key2", "value4"), addr)
+        .unwrap();
+    assert!(matches!(send(Action::EXEC, "", ""), Response::Conflict));
+    assert!(matches!(get("key3"), Response::Err(_)));
+
+    // Discarded writes are never applied.
+    send(Action::MULTI, "", "");
+    send(Action::SET, "key3", "value3");
+    assert!(matches!(send(Action::DISCARD, "", ""), Response::Ok(None)));
+    assert!(matches!(get("key3"), Response::Err(_)));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// With one pool thread, an idle session must not keep other clients out for
+// longer than the idle timeout.
+// kvs-admin reads a directory a server has open without getting in its way.
+#[test]
+fn cli_admin_reads_beside_server() {
+    let addr = "127.0.0.1:4029";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = KvsClient::new();
+    let set = |key: &str, value: &str| {
+        let command = kvs::Command::new(
+            Action::SET,
+            key.as_bytes().to_vec(),
+            value.as_bytes().to_vec(),
+        );
+        matches!(client.send_command(&command, addr), Ok(Response::Ok(None)))
+    };
+    assert!(set("key1", "value1"));
+
+    let admin = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-admin").unwrap();
+        command.args(args).arg(temp_dir.path()).output().unwrap()
+    };
+    let stats = admin(&["stats"]);
+    let dump = admin(&["dump", "--format", "csv"]);
+    // The server still writes once they are done.
+    let served = set("key2", "value2");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    stats.assert().success().stdout(contains("1 keys"));
+    dump.assert().success().stdout(contains("key1"));
+    assert!(served);
+}
+
+#[test]
+fn cli_idle_session_is_closed() {
+    let addr = "127.0.0.1:4027";
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(&config, "idle_timeout_ms = 500\n").unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--threads",
+            "1",
+            "--config",
+        ])
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new();
+    let command = |action, key: &str, value: &str| {
+        kvs::Command::new(action, key.as_bytes().to_vec(), value.as_bytes().to_vec())
+    };
+    let mut session = client.connect(addr).unwrap();
+    assert!(matches!(
+        session.send(&command(Action::MULTI, "", "")).unwrap(),
+        Response::Ok(None)
+    ));
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let response = KvsClient::new().send_command(&command(Action::SET, "key1", "value1"), addr);
+        let _ = sender.send(response.map(|response| matches!(response, Response::Ok(None))));
+    });
+    let served = receiver.recv_timeout(Duration::from_secs(10));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    assert!(matches!(served, Ok(Ok(true))), "{:?}", served);
+    // The idle session was closed, its transaction with it.
+    assert!(session.send(&command(Action::EXEC, "", "")).is_err());
+}
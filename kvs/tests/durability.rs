@@ -0,0 +1,277 @@
+use assert_cmd::prelude::*;
+use kvs::engine::sled::{SledKvsEngine, SledOptions};
+use kvs::{Durability, KvStore, KvStoreOptions, KvsEngine, Result, SyncStats};
+use predicates::str::contains;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const KEYS: usize = 20;
+
+fn start_server(temp_dir: &TempDir, engine: &str, durability: &str, addr: &str) -> Child {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr])
+        .args(["--durability", durability])
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child
+}
+
+// Kills the server with SIGKILL right after the writes are acknowledged, so
+// nothing is flushed on the way out.
+fn crash_after_writes(engine: &str, durability: &str, addr: &str) -> TempDir {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = start_server(&temp_dir, engine, durability, addr);
+    for i in 0..KEYS {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args([
+                "set",
+                &format!("key{}", i),
+                &format!("value{}", i),
+                "--addr",
+                addr,
+            ])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait on server");
+    temp_dir
+}
+
+// Every acknowledged write must be there after the crash. A process crash
+// leaves what reached the OS, so this holds for every mode but `none`; the
+// `syncs_per_mode` tests check how far each one goes beyond that.
+fn acknowledged_writes_survive(engine: &str, durability: &str, addr: &str) {
+    let temp_dir = crash_after_writes(engine, durability, addr);
+    let mut server = start_server(&temp_dir, engine, durability, addr);
+    for i in 0..KEYS {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", &format!("key{}", i), "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(format!("value{}\n", i));
+    }
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait on server");
+}
+
+// Writes may be lost, but the store must still open and serve requests.
+fn store_reopens(engine: &str, addr: &str) {
+    let temp_dir = crash_after_writes(engine, "none", addr);
+    let mut server = start_server(&temp_dir, engine, "none", addr);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "after", "crash", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "after", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("crash"));
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn kvs_durability_none() {
+    store_reopens("kvs", "127.0.0.1:4010");
+}
+
+#[test]
+fn kvs_durability_flush() {
+    acknowledged_writes_survive("kvs", "flush", "127.0.0.1:4011");
+}
+
+#[test]
+fn kvs_durability_fsync() {
+    acknowledged_writes_survive("kvs", "fsync", "127.0.0.1:4012");
+}
+
+#[test]
+fn kvs_durability_fsync_every() {
+    acknowledged_writes_survive("kvs", "fsync-every:50", "127.0.0.1:4013");
+}
+
+#[test]
+fn kvs_durability_group_commit() {
+    acknowledged_writes_survive("kvs", "group-commit", "127.0.0.1:4014");
+}
+
+#[test]
+fn sled_durability_none() {
+    store_reopens("sled", "127.0.0.1:4015");
+}
+
+#[test]
+fn sled_durability_fsync() {
+    acknowledged_writes_survive("sled", "fsync", "127.0.0.1:4016");
+}
+
+#[test]
+fn sled_durability_group_commit() {
+    acknowledged_writes_survive("sled", "group-commit", "127.0.0.1:4017");
+}
+
+// The flushes and fsyncs made by `KEYS` writes from one thread.
+fn syncs_for_writes<E: KvsEngine>(
+    engine: &E,
+    stats: impl Fn(&E) -> SyncStats,
+) -> Result<SyncStats> {
+    let before = stats(engine);
+    for i in 0..KEYS {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let after = stats(engine);
+    Ok(SyncStats {
+        flushes: after.flushes - before.flushes,
+        fsyncs: after.fsyncs - before.fsyncs,
+    })
+}
+
+// Each mode takes every write exactly as far as it promises, and a lone
+// writer under group commit has nobody to share an fsync with.
+#[test]
+fn kvs_syncs_per_mode() -> Result<()> {
+    let syncs = |durability| -> Result<SyncStats> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().durability(durability);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        syncs_for_writes(&store, KvStore::sync_stats)
+    };
+    let writes = KEYS as u64;
+    let hour = Duration::from_secs(3600);
+    let expected = [
+        (Durability::None, 0, 0),
+        (Durability::FlushOnly, writes, 0),
+        (Durability::FsyncEveryWrite, writes, writes),
+        (Durability::FsyncEvery(hour), writes, 0),
+        (Durability::GroupCommit, writes, writes),
+    ];
+    for (durability, flushes, fsyncs) in expected {
+        assert_eq!(
+            syncs(durability)?,
+            SyncStats { flushes, fsyncs },
+            "{:?}",
+            durability
+        );
+    }
+
+    // The interval fsyncs come from the background.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options =
+        KvStoreOptions::new().durability(Durability::FsyncEvery(Duration::from_millis(20)));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    assert!(store.sync_stats().fsyncs > 0);
+    Ok(())
+}
+
+// sled syncs the whole database where `KvStore` would flush, and runs its
+// interval fsyncs itself.
+#[test]
+fn sled_syncs_per_mode() -> Result<()> {
+    let syncs = |durability| -> Result<SyncStats> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = SledOptions::new().durability(durability);
+        let engine = SledKvsEngine::open_with(temp_dir.path(), options)?;
+        syncs_for_writes(&engine, SledKvsEngine::sync_stats)
+    };
+    let writes = KEYS as u64;
+    let expected = [
+        (Durability::None, 0),
+        (Durability::FlushOnly, writes),
+        (Durability::FsyncEveryWrite, writes),
+        (Durability::FsyncEvery(Duration::from_millis(50)), 0),
+        (Durability::GroupCommit, writes),
+    ];
+    for (durability, fsyncs) in expected {
+        assert_eq!(
+            syncs(durability)?,
+            SyncStats { flushes: 0, fsyncs },
+            "{:?}",
+            durability
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn server_cli_invalid_durability() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--durability", "sometimes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+fn concurrent_writes<E: KvsEngine>(engine: E) -> Result<()> {
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    engine
+                        .set(format!("key{}-{}", t, i), format!("{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    for t in 0..8 {
+        for i in 0..50 {
+            assert_eq!(
+                engine.get(format!("key{}-{}", t, i))?,
+                Some(format!("{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn kvs_group_commit_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().durability(Durability::GroupCommit);
+    concurrent_writes(KvStore::open_with(temp_dir.path(), options)?)
+}
+
+#[test]
+fn sled_group_commit_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions::new().durability(Durability::GroupCommit);
+    concurrent_writes(SledKvsEngine::open_with(temp_dir.path(), options)?)
+}
+
+// Buffered writes must still be visible to reads on the same store.
+#[test]
+fn kvs_durability_none_reads_own_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().durability(Durability::None);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -1,5 +1,16 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::engine::archive::{self, RestorePoint};
+use kvs::engine::convert::{self, DualWrite};
+use kvs::engine::dump::{self, DumpFormat};
+use kvs::engine::sled::{SledKvsEngine, SledOptions};
+use kvs::engine::{backup, inspect, marker, migrate};
+use kvs::{
+    CompactionPolicy, Durability, KvError, KvStore, KvStoreOptions, KvsEngine, KvsSnapshot, Result,
+    WriteBatch,
+};
+use std::fs;
 use std::path::Path;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -7,7 +18,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -17,7 +28,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -28,7 +39,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -37,7 +48,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -49,14 +60,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -65,7 +76,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -73,7 +84,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -85,7 +96,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -115,7 +126,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -125,10 +136,436 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+// Clones of one store written from many threads should all land.
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    let key = format!("key{}", t * 1000 + i);
+                    store.set(key, format!("value{}", i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for t in 0..8 {
+        for i in 0..1000 {
+            let key = format!("key{}", t * 1000 + i);
+            assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+        }
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..8000 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", i % 1000))
+        );
+    }
+
+    Ok(())
+}
+
+// Readers on many threads should see the data while a writer keeps going.
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 100..5000 {
+                store
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .unwrap();
+            }
+        })
+    };
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    for i in 0..100 {
+                        let value = store.get(format!("key{}", i)).unwrap();
+                        assert_eq!(value, Some(format!("value{}", i)));
+                    }
+                }
+            })
+        })
+        .collect();
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    for i in 0..5000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Writes spanning many segments should survive background compaction and a
+// reopen, and merged segments should not pile up on disk.
+#[test]
+fn segments_are_merged() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "x".repeat(1024);
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}{}", value, iter))?;
+        }
+        if iter % 50 == 0 {
+            store.remove("key0".to_owned())?;
+        }
+    }
+
+    drop(store);
+    let segments = fs::read_dir(temp_dir.path())?
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_str().unwrap().starts_with("log_")
+        })
+        .count();
+    assert!(segments <= 3, "{} segments left on disk", segments);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key)?, Some(format!("{}{}", value, 199)));
+    }
+    Ok(())
+}
+
+// Sealed segments should be loaded from their hint files, falling back to a
+// full scan only when the hint is unusable.
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionPolicy::OnSeal);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let value = "x".repeat(1024);
+    for key_id in 0..5000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    drop(store);
+
+    // The first segment has been sealed and merged into `log_1`.
+    let log_path = temp_dir.path().join("log_1");
+    let hint_path = temp_dir.path().join("hint_1");
+    assert!(hint_path.exists());
+
+    // Damage a value: opening with the hint never reads it.
+    let mut bytes = fs::read(&log_path)?;
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0x01;
+    fs::write(&log_path, bytes)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4999".to_owned())?, Some(value));
+    drop(store);
+
+    // Without a valid hint the segment is scanned and the damage found.
+    let mut bytes = fs::read(&hint_path)?;
+    bytes[0] ^= 0x01;
+    fs::write(&hint_path, bytes)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// A flipped bit in a stored value should surface as a corruption error.
+#[test]
+fn corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // The first record starts at offset 0: a 16 byte header, then "key1".
+    let log_path = temp_dir.path().join("log_0");
+    let mut bytes = fs::read(&log_path)?;
+    bytes[21] ^= 0x01;
+    fs::write(&log_path, bytes)?;
+
+    match store.get("key1".to_owned()) {
+        Err(KvError::Corruption { log_id, offset }) => {
+            assert_eq!(log_id, 0);
+            assert_eq!(offset, 0);
+        }
+        other => panic!("expected a corruption error, got {:?}", other),
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A partially written record at the end of the log should be cut off on open.
+#[test]
+fn torn_tail_is_truncated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("log_0");
+    let valid_len = fs::metadata(&log_path)?.len();
+    let mut bytes = fs::read(&log_path)?;
+    let torn = bytes[..20].to_vec();
+    bytes.extend_from_slice(&torn);
+    fs::write(&log_path, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log_path)?.len(), valid_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Damage in the middle of the log should fail the open unless repair is set.
+#[test]
+fn mid_log_corruption_needs_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("log_0");
+    let mut bytes = fs::read(&log_path)?;
+    bytes[21] ^= 0x01;
+    fs::write(&log_path, bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Corruption { log_id, offset }) => {
+            assert_eq!(log_id, 0);
+            assert_eq!(offset, 0);
+        }
+        Err(e) => panic!("expected a corruption error, got {:?}", e),
+        Ok(_) => panic!("expected a corruption error"),
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().repair(true))?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // The repaired store opens cleanly from then on.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Verify should report damage without touching the store, and repair should
+// copy everything readable around it into a new store.
+#[test]
+fn verify_and_repair_offline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key4".to_vec(), b"value4".to_vec());
+    batch.remove(b"key3".to_vec());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let verified = inspect::verify(temp_dir.path())?;
+    assert!(verified.is_ok(), "{:?}", verified);
+    assert_eq!(verified.records, 5);
+
+    let mut kinds = Vec::new();
+    inspect::inspect(temp_dir.path(), 0, |record| {
+        kinds.push((record.kind, record.key.clone(), record.in_batch))
+    })?;
+    assert_eq!(kinds.len(), 6);
+    assert_eq!(kinds[0], ("set", b"key1".to_vec(), false));
+    assert_eq!(kinds[3], ("batch", Vec::new(), false));
+    assert_eq!(kinds[5], ("remove", b"key3".to_vec(), true));
+
+    let log_path = temp_dir.path().join("log_0");
+    let mut bytes = fs::read(&log_path)?;
+    bytes[21] ^= 0x01;
+    fs::write(&log_path, &bytes)?;
+
+    let verified = inspect::verify(temp_dir.path())?;
+    assert!(!verified.is_ok());
+    assert_eq!(verified.records, 4);
+    assert_eq!(verified.damage.len(), 1);
+    assert_eq!(verified.damage[0].segment, 0);
+    assert_eq!(verified.damage[0].start, 0);
+    assert_eq!(fs::read(&log_path)?, bytes);
+
+    let repaired_dir = temp_dir.path().join("repaired");
+    let repaired = inspect::repair(temp_dir.path(), &repaired_dir)?;
+    assert_eq!(repaired.records, 4);
+    assert_eq!(repaired.keys, 2);
+    assert_eq!(repaired.damage, verified.damage);
+    assert_eq!(fs::read(&log_path)?, bytes);
+
+    let store = KvStore::open(&repaired_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+    assert!(inspect::verify(&repaired_dir)?.is_ok());
+
+    // The destination must be new.
+    assert!(inspect::repair(temp_dir.path(), &repaired_dir).is_err());
+    Ok(())
+}
+
+// A store from before the manifest, with only `current` naming its active
+// segment, has format version 0 and is only opened to be upgraded.
+#[test]
+fn old_format_needs_upgrade() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+    fs::write(temp_dir.path().join("current"), 0u32.to_le_bytes())?;
+
+    match KvStore::open(temp_dir.path()).err() {
+        Some(KvError::FormatVersion { found, supported }) => {
+            assert_eq!(found, 0);
+            assert_eq!(supported, migrate::FORMAT_VERSION);
+        }
+        other => panic!("expected a format version error, got {:?}", other),
+    }
+    assert_eq!(migrate::format_version(temp_dir.path())?, 0);
+
+    // Read-only upgrades leave the directory alone.
+    let read_only = KvStoreOptions::new().read_only(true).upgrade(true);
+    let store = KvStore::open_with(temp_dir.path(), read_only)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(migrate::format_version(temp_dir.path())?, 0);
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().upgrade(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(
+        migrate::format_version(temp_dir.path())?,
+        migrate::FORMAT_VERSION
+    );
+    assert!(!temp_dir.path().join("current").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A store written by a newer version is never opened, upgrade or not.
+#[test]
+fn newer_format_is_refused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+
+    // The version follows the 8 byte magic; the CRC32 is the last 4 bytes.
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let mut bytes = fs::read(&manifest_path)?;
+    let body_len = bytes.len() - 4;
+    bytes[8..12].copy_from_slice(&(migrate::FORMAT_VERSION + 1).to_le_bytes());
+    let crc = crc32fast::hash(&bytes[..body_len]);
+    bytes[body_len..].copy_from_slice(&crc.to_le_bytes());
+    fs::write(&manifest_path, bytes)?;
+
+    let options = KvStoreOptions::new().upgrade(true);
+    match KvStore::open_with(temp_dir.path(), options).err() {
+        Some(KvError::FormatVersion { found, .. }) => {
+            assert_eq!(found, migrate::FORMAT_VERSION + 1)
+        }
+        other => panic!("expected a format version error, got {:?}", other),
+    }
+    Ok(())
+}
+
+// Segment files the manifest does not list are crash leftovers, and must not
+// bring back data compacted away.
+#[test]
+fn orphan_segments_are_removed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(4096)
+        .compaction(CompactionPolicy::Manual);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let first_segment = fs::read(temp_dir.path().join("log_0"))?;
+    store.remove("key0".to_owned())?;
+    for i in 1..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    drop(store);
+
+    // The inputs of a finished compaction, the output of an unfinished one
+    // and a half-written manifest.
+    fs::write(temp_dir.path().join("log_0"), first_segment)?;
+    fs::write(temp_dir.path().join("log_1000"), b"garbage")?;
+    fs::write(temp_dir.path().join("hint_1000"), b"garbage")?;
+    fs::write(temp_dir.path().join("MANIFEST.tmp"), b"garbage")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    for leftover in &["log_0", "log_1000", "hint_1000", "MANIFEST.tmp"] {
+        assert!(!temp_dir.path().join(leftover).exists(), "{}", leftover);
+    }
+    Ok(())
+}
+
+// A damaged manifest fails the open; repair rebuilds it from the segments.
+#[test]
+fn damaged_manifest_needs_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let mut bytes = fs::read(&manifest_path)?;
+    bytes[12] ^= 0x01;
+    fs::write(&manifest_path, bytes)?;
+    match KvStore::open(temp_dir.path()).err() {
+        Some(KvError::BadManifest(_)) => {}
+        other => panic!("expected a damaged manifest, got {:?}", other),
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().repair(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
 //#[test]
 //fn compact() -> Result<()> {
 //    let temp_dir = Path::new("/tmp/db");
-//    let mut store = KvStore::open(temp_dir.to_path_buf()).expect("unable to open kvstore");
+//    let store = KvStore::open(temp_dir.to_path_buf()).expect("unable to open kvstore");
 //    for j in 1..1000 {
 //        for i in 1..(1 << 12) {
 //            store.set(format!("key{}", i), "value".to_string()).unwrap();
@@ -136,3 +573,1457 @@ fn compaction() -> Result<()> {
 //    }
 //    Ok(())
 //}
+
+fn count_segments(dir: &std::path::Path) -> Result<usize> {
+    Ok(fs::read_dir(dir)?
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_str().unwrap().starts_with("log_")
+        })
+        .count())
+}
+
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(4096)
+        .compaction(CompactionPolicy::Manual);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..50 {
+        for key_id in 0..10 {
+            store.set(
+                format!("key{}", key_id),
+                format!("{}{}", "x".repeat(100), iter),
+            )?;
+        }
+    }
+    let before = count_segments(temp_dir.path())?;
+    assert!(before > 10, "only {} segments before compaction", before);
+
+    store.compact()?;
+    assert!(count_segments(temp_dir.path())? <= 2);
+    for key_id in 0..10 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key)?, Some(format!("{}{}", "x".repeat(100), 49)));
+    }
+    Ok(())
+}
+
+#[test]
+fn sealed_segments_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(4096)
+        .compaction(CompactionPolicy::SealedSegments(8));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..400 {
+        store.set("key".to_owned(), format!("{}{}", "x".repeat(100), iter))?;
+    }
+    drop(store);
+    let segments = count_segments(temp_dir.path())?;
+    assert!(segments <= 10, "{} segments left on disk", segments);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key".to_owned())?,
+        Some(format!("{}{}", "x".repeat(100), 399))
+    );
+    Ok(())
+}
+
+#[test]
+fn create_if_missing_and_error_if_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().create_if_missing(false);
+    match KvStore::open_with(temp_dir.path(), options.clone()) {
+        Err(KvError::StoreNotFound(_)) => {}
+        other => panic!("expected StoreNotFound, got {:?}", other.err()),
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().error_if_exists(true))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    match KvStore::open_with(temp_dir.path(), KvStoreOptions::new().error_if_exists(true)) {
+        Err(KvError::StoreExists(_)) => {}
+        other => panic!("expected StoreExists, got {:?}", other.err()),
+    }
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().read_only(true);
+    match KvStore::open_with(temp_dir.path().join("missing"), options.clone()) {
+        Err(KvError::StoreNotFound(_)) => {}
+        other => panic!("expected StoreNotFound, got {:?}", other.err()),
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // A torn tail is left alone.
+    let log_path = temp_dir.path().join("log_0");
+    let mut bytes = fs::read(&log_path)?;
+    let torn = bytes[..20].to_vec();
+    bytes.extend_from_slice(&torn);
+    fs::write(&log_path, &bytes)?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    match store.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvError::ReadOnly) => {}
+        other => panic!("expected ReadOnly, got {:?}", other.err()),
+    }
+    match store.remove("key1".to_owned()) {
+        Err(KvError::ReadOnly) => {}
+        other => panic!("expected ReadOnly, got {:?}", other.err()),
+    }
+    assert_eq!(fs::read(&log_path)?, bytes);
+    Ok(())
+}
+
+// A read-only sled engine should refuse writes and leave expired keys where
+// they are.
+#[test]
+fn sled_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let missing = temp_dir.path().join("missing");
+    let options = SledOptions::new()
+        .read_only(true)
+        .reap_interval(Duration::from_millis(20));
+    match SledKvsEngine::open_with(&missing, options.clone()) {
+        Err(KvError::StoreNotFound(_)) => {}
+        other => panic!("expected StoreNotFound, got {:?}", other.err()),
+    }
+    assert!(!missing.exists());
+
+    let dir = temp_dir.path().join("sled");
+    let engine = SledKvsEngine::open(&dir)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_with_ttl(
+        b"expiring".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(50),
+    )?;
+    drop(engine);
+
+    let engine = SledKvsEngine::open_with(&dir, options)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    match engine.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvError::ReadOnly) => {}
+        other => panic!("expected ReadOnly, got {:?}", other.err()),
+    }
+    match engine.remove("key1".to_owned()) {
+        Err(KvError::ReadOnly) => {}
+        other => panic!("expected ReadOnly, got {:?}", other.err()),
+    }
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(engine.get_bytes(b"expiring")?, None);
+    drop(engine);
+
+    let db = sled::open(&dir)?;
+    assert_eq!(db.len(), 2);
+    assert_eq!(db.open_tree("kvs_expiry")?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn invalid_options() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for options in [
+        KvStoreOptions::new().segment_size(0),
+        KvStoreOptions::new().read_buffer_size(0),
+        KvStoreOptions::new().read_only(true).repair(true),
+    ] {
+        match KvStore::open_with(temp_dir.path(), options) {
+            Err(KvError::InvalidOption(_)) => {}
+            other => panic!("expected InvalidOption, got {:?}", other.err()),
+        }
+    }
+    assert!("segments:0".parse::<CompactionPolicy>().is_err());
+    assert_eq!(
+        "segments:4".parse::<CompactionPolicy>().unwrap(),
+        CompactionPolicy::SealedSegments(4)
+    );
+}
+
+#[test]
+fn stats_track_dead_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.dead_bytes(), 0);
+    let live = stats.live_bytes();
+    assert_eq!(live, fs::metadata(temp_dir.path().join("log_0"))?.len());
+
+    // The overwritten value and the removed value plus its tombstone are dead.
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.keys, 1);
+    assert_eq!(stats.live_bytes(), live / 2);
+    let total = fs::metadata(temp_dir.path().join("log_0"))?.len();
+    assert_eq!(stats.live_bytes() + stats.dead_bytes(), total);
+
+    // The counters are rebuilt on open.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats(), stats);
+
+    store.compact()?;
+    let stats = store.stats();
+    assert_eq!(stats.dead_bytes(), 0);
+    assert_eq!(stats.live_bytes(), live / 2);
+    Ok(())
+}
+
+#[test]
+fn garbage_ratio_policy() -> Result<()> {
+    let value = "x".repeat(100);
+    let options = KvStoreOptions::new()
+        .segment_size(4096)
+        .compaction(CompactionPolicy::GarbageRatio(0.5));
+
+    // Nothing is dead, so nothing is merged.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..400 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    let written = store.stats().live_bytes();
+    drop(store);
+    assert_eq!(dir_size(temp_dir.path())?, written);
+    assert!(count_segments(temp_dir.path())? > 10);
+
+    // Overwrites keep the dead ratio high and the segments merged. How far
+    // the background merges got depends on timing, so a last merge is waited
+    // for before counting: only the active segment and its output are left.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..800 {
+        store.set(format!("key{}", iter % 4), format!("{}{}", value, iter))?;
+    }
+    assert_eq!(store.stats().keys, 4);
+    store.compact()?;
+    drop(store);
+    let segments = count_segments(temp_dir.path())?;
+    assert!(segments <= 2, "{} segments left on disk", segments);
+    assert!(dir_size(temp_dir.path())? < 2 * 4096);
+    Ok(())
+}
+
+fn dir_size(dir: &std::path::Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_str().unwrap().starts_with("log_") {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+fn binary_keys_and_values<E: KvsEngine>(engine: &E) -> Result<()> {
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0x00, 0x9f, 0x92, 0x96, 0xff];
+    engine.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(engine.get_bytes(&key)?, Some(value));
+
+    engine.set_bytes(b"text".to_vec(), vec![0xc3, 0x28])?;
+    match engine.get("text".to_owned()) {
+        Err(KvError::Utf8(_)) => {}
+        other => panic!("expected Utf8, got {:?}", other),
+    }
+
+    engine.remove_bytes(&key)?;
+    assert_eq!(engine.get_bytes(&key)?, None);
+    Ok(())
+}
+
+#[test]
+fn kvs_binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    binary_keys_and_values(&store)?;
+
+    let key = vec![0x80; 16];
+    store.set_bytes(key.clone(), vec![0xaa; 1000])?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(vec![0xaa; 1000]));
+    Ok(())
+}
+
+#[test]
+fn sled_binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_keys_and_values(&SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A directory written by sled 0.24 should be refused with a pointer to
+// `kvs-admin migrate`, and migrate into a store the current sled opens.
+#[test]
+fn old_sled_format_is_detected_and_migrated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old = temp_dir.path().join("old");
+    {
+        let db = sled_legacy::Db::start_default(&old).unwrap();
+        for i in 0..1500 {
+            db.set(format!("key{}", i), format!("value{}", i).into_bytes())
+                .unwrap();
+        }
+        db.flush().unwrap();
+    }
+
+    match SledKvsEngine::open(&old) {
+        Err(KvError::OldSledFormat { version, .. }) => assert_eq!(version, "0.28 or older"),
+        Err(e) => panic!("expected an old format error, got {:?}", e),
+        Ok(_) => panic!("expected an old format error"),
+    }
+
+    let new = temp_dir.path().join("new");
+    assert_eq!(migrate::migrate_sled(&old, &new)?, 1500);
+    assert_eq!(marker::recorded_engine(&new)?, Some("sled".to_owned()));
+    let engine = SledKvsEngine::open(&new)?;
+    assert_eq!(
+        engine.get("key1499".to_owned())?,
+        Some("value1499".to_owned())
+    );
+    drop(engine);
+    assert!(migrate::migrate_sled(&new, &temp_dir.path().join("again")).is_err());
+    Ok(())
+}
+
+fn scans<E: KvsEngine>(engine: &E) -> Result<()> {
+    for i in 0..300 {
+        engine.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    engine.set("other".to_owned(), "x".to_owned())?;
+    engine.remove("key150".to_owned())?;
+    engine.set_bytes(vec![0xff, 0xff], b"high".to_vec())?;
+
+    let keys: Vec<Vec<u8>> = engine
+        .scan(b"key100".to_vec()..b"key200".to_vec())
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys.len(), 99);
+    assert_eq!(keys[0], b"key100");
+    assert_eq!(keys[98], b"key199");
+    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+
+    let pairs = engine.scan_prefix(b"key").collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 299);
+    assert_eq!(pairs[0], (b"key000".to_vec(), b"value0".to_vec()));
+
+    let pairs = engine.scan_prefix(&[0xff]).collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs, vec![(vec![0xff, 0xff], b"high".to_vec())]);
+    assert_eq!(engine.scan(..).count(), 301);
+    assert_eq!(engine.scan(b"z".to_vec()..b"a".to_vec()).count(), 0);
+    assert_eq!(engine.scan_prefix(b"missing").count(), 0);
+    Ok(())
+}
+
+#[test]
+fn kvs_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    scans(&store)?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan_prefix(b"key").count(), 299);
+    Ok(())
+}
+
+#[test]
+fn sled_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scans(&SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn write_batches<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value2")
+        .set("key3", "value3")
+        .remove("key1")
+        .remove("missing")
+        .set("key3", "value4");
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    engine.write_batch(WriteBatch::new())?;
+    assert_eq!(engine.scan(..).count(), 2);
+    Ok(())
+}
+
+#[test]
+fn kvs_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    write_batches(&store)?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batches(&SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A batch cut short by a crash is dropped as a whole.
+#[test]
+fn torn_batch_is_dropped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let mut batch = WriteBatch::new();
+    for i in 1..10 {
+        batch.set(format!("key{}", i), format!("value{}", i));
+    }
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Cut into the last record of the batch: every record before it is
+    // intact on its own.
+    let log_path = temp_dir.path().join("log_0");
+    let len = fs::metadata(&log_path)?.len();
+    let file = fs::OpenOptions::new().write(true).open(&log_path)?;
+    file.set_len(len - 4)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    for i in 1..10 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    Ok(())
+}
+
+fn compare_and_swaps<E: KvsEngine>(engine: &E) -> Result<()> {
+    let key = b"key1".to_vec();
+    engine.compare_and_swap(key.clone(), None, Some(b"value1".to_vec()))?;
+    match engine.compare_and_swap(key.clone(), None, Some(b"value2".to_vec())) {
+        Err(KvError::Conflict) => {}
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+    match engine.compare_and_swap(
+        key.clone(),
+        Some(b"value0".to_vec()),
+        Some(b"value2".to_vec()),
+    ) {
+        Err(KvError::Conflict) => {}
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+    assert_eq!(engine.get_bytes(&key)?, Some(b"value1".to_vec()));
+
+    engine.compare_and_swap(
+        key.clone(),
+        Some(b"value1".to_vec()),
+        Some(b"value2".to_vec()),
+    )?;
+    assert_eq!(engine.get_bytes(&key)?, Some(b"value2".to_vec()));
+    engine.compare_and_swap(key.clone(), Some(b"value2".to_vec()), None)?;
+    assert_eq!(engine.get_bytes(&key)?, None);
+    engine.compare_and_swap(key.clone(), None, None)?;
+    assert_eq!(engine.get_bytes(&key)?, None);
+
+    engine.set_if_absent(key.clone(), b"value3".to_vec())?;
+    assert!(engine
+        .set_if_absent(key.clone(), b"value4".to_vec())
+        .is_err());
+    assert!(engine
+        .remove_if_equals(key.clone(), b"value4".to_vec())
+        .is_err());
+    engine.remove_if_equals(key.clone(), b"value3".to_vec())?;
+    assert_eq!(engine.get_bytes(&key)?, None);
+    Ok(())
+}
+
+// Concurrent read-modify-write loops lose no increments.
+fn concurrent_increments<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let current = engine.get_bytes(b"counter")?.unwrap();
+                        let n: u32 = String::from_utf8(current.clone())?.parse().unwrap();
+                        let next = (n + 1).to_string().into_bytes();
+                        match engine.compare_and_swap(
+                            b"counter".to_vec(),
+                            Some(current),
+                            Some(next),
+                        ) {
+                            Ok(()) => break,
+                            Err(KvError::Conflict) => continue,
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    compare_and_swaps(&store)?;
+    concurrent_increments(&store)
+}
+
+#[test]
+fn sled_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    compare_and_swaps(&engine)?;
+    concurrent_increments(&engine)
+}
+
+fn expiring_keys<E: KvsEngine>(engine: &E) -> Result<()> {
+    let ttl = Duration::from_millis(200);
+    engine.set_with_ttl(b"key1".to_vec(), b"value1".to_vec(), ttl)?;
+    engine.set_with_ttl(b"key2".to_vec(), b"value2".to_vec(), ttl)?;
+    engine.set_with_ttl(b"key3".to_vec(), b"value3".to_vec(), ttl)?;
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    assert!(engine.ttl(b"key1")?.unwrap() <= ttl);
+    assert_eq!(engine.ttl(b"key4")?, None);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    engine.persist(b"key2")?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    engine.expire(b"key4", ttl)?;
+    assert_eq!(engine.ttl(b"key2")?, None);
+    assert_eq!(engine.ttl(b"key3")?, None);
+    match engine.expire(b"missing", ttl) {
+        Err(KvError::KeyNotExit) => {}
+        other => panic!("expected KeyNotExit, got {:?}", other),
+    }
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key4".to_owned())?, None);
+    match engine.ttl(b"key1") {
+        Err(KvError::KeyNotExit) => {}
+        other => panic!("expected KeyNotExit, got {:?}", other),
+    }
+    assert!(engine.remove("key1".to_owned()).is_err());
+    let keys: Vec<Vec<u8>> = engine
+        .scan(..)
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"key2".to_vec(), b"key3".to_vec()]);
+
+    // An expired key counts as absent for conditional writes.
+    engine.set_if_absent(b"key1".to_vec(), b"value5".to_vec())?;
+    assert_eq!(engine.ttl(b"key1")?, None);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().reap_interval(Duration::from_secs(3600));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    expiring_keys(&store)?;
+
+    let hour = Duration::from_secs(3600);
+    store.set_with_ttl(b"key5".to_vec(), b"value5".to_vec(), hour)?;
+    store.set_with_ttl(
+        b"key6".to_vec(),
+        b"value6".to_vec(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+    drop(store);
+
+    // Deadlines survive a restart, read back from the log and then from the
+    // hint of the compacted segment.
+    for _ in 0..2 {
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        assert!(store.ttl(b"key5")?.unwrap() > hour - Duration::from_secs(60));
+        assert_eq!(store.get("key6".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        store.compact()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn sled_expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions::new().reap_interval(Duration::from_secs(3600));
+    let engine = SledKvsEngine::open_with(temp_dir.path(), options.clone())?;
+    expiring_keys(&engine)?;
+
+    let hour = Duration::from_secs(3600);
+    engine.set_with_ttl(b"key5".to_vec(), b"value5".to_vec(), hour)?;
+    drop(engine);
+    let engine = SledKvsEngine::open_with(temp_dir.path(), options)?;
+    assert!(engine.ttl(b"key5")?.unwrap() > hour - Duration::from_secs(60));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_expired_keys_are_reaped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().reap_interval(Duration::from_millis(20));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        let key = format!("key{}", i).into_bytes();
+        store.set_with_ttl(key, b"value".to_vec(), Duration::from_millis(50))?;
+    }
+    store.set("live".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(store.stats().keys, 1);
+    assert_eq!(store.get("live".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().reap_interval(Duration::from_secs(3600));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        let key = format!("key{}", i).into_bytes();
+        store.set_with_ttl(key, vec![0; 1000], Duration::from_millis(50))?;
+    }
+    store.set("live".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.stats().keys, 101);
+
+    store.compact()?;
+    assert_eq!(store.stats().keys, 1);
+    assert!(dir_size(temp_dir.path())? < 10_000);
+    assert_eq!(store.get("live".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_expired_keys_are_reaped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions::new().reap_interval(Duration::from_millis(20));
+    let engine = SledKvsEngine::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        let key = format!("key{}", i).into_bytes();
+        engine.set_with_ttl(key, b"value".to_vec(), Duration::from_millis(50))?;
+    }
+    engine.set("live".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(500));
+    drop(engine);
+
+    let db = sled::open(temp_dir.path())?;
+    assert_eq!(db.len(), 1);
+    assert!(db.open_tree("kvs_expiry")?.is_empty());
+    assert!(db.open_tree("kvs_deadlines")?.is_empty());
+    Ok(())
+}
+
+fn snapshots<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    let snapshot = engine.snapshot()?;
+
+    engine.set("key1".to_owned(), "value4".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key4", "value4").remove("key3");
+    engine.write_batch(batch)?;
+    let later = engine.snapshot()?;
+    engine.set("key2".to_owned(), "value5".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key4".to_owned())?, None);
+    let pairs = snapshot.scan(..).collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"key3".to_vec(), b"value3".to_vec()),
+        ]
+    );
+
+    assert!(later.seq() > snapshot.seq());
+    assert_eq!(later.get("key2".to_owned())?, None);
+    let pairs = later.scan_prefix(b"key").collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key1".to_vec(), b"value4".to_vec()),
+            (b"key4".to_vec(), b"value4".to_vec()),
+        ]
+    );
+
+    // Releasing the older snapshot leaves the newer one intact.
+    drop(snapshot);
+    engine.set("key1".to_owned(), "value6".to_owned())?;
+    assert_eq!(later.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(engine.get("key1".to_owned())?, Some("value6".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    snapshots(&store)
+}
+
+#[test]
+fn sled_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshots(&SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A sled snapshot keeps the deadlines it saw, and never sees half of a write
+// made while it was taken.
+#[test]
+fn sled_snapshot_is_point_in_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions::new().reap_interval(Duration::from_millis(10));
+    let engine = SledKvsEngine::open_with(temp_dir.path(), options)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_with_ttl(b"key2".to_vec(), b"value2".to_vec(), Duration::from_secs(1))?;
+    let snapshot = engine.snapshot()?;
+    engine.expire(b"key1", Duration::from_secs(1))?;
+    engine.persist(b"key2")?;
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, None);
+    let pairs = snapshot.scan(..).collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs, vec![(b"key1".to_vec(), b"value1".to_vec())]);
+    drop(snapshot);
+
+    // Every batch keeps both counters equal.
+    let writer = engine.clone();
+    let writes = thread::spawn(move || -> Result<()> {
+        for i in 0..500u32 {
+            let mut batch = WriteBatch::new();
+            batch
+                .set("count_a", i.to_string())
+                .set("count_b", i.to_string());
+            writer.write_batch(batch)?;
+        }
+        Ok(())
+    });
+    while !writes.is_finished() {
+        let snapshot = engine.snapshot()?;
+        let a = snapshot.get("count_a".to_owned())?;
+        let pairs = snapshot
+            .scan_prefix(b"count_")
+            .collect::<Result<Vec<_>>>()?;
+        thread::sleep(Duration::from_millis(1));
+        assert_eq!(snapshot.get("count_b".to_owned())?, a);
+        let values: Vec<_> = pairs.into_iter().map(|(_, value)| value).collect();
+        match a {
+            Some(a) => assert_eq!(values, vec![a.clone().into_bytes(), a.into_bytes()]),
+            None => assert!(values.is_empty()),
+        }
+    }
+    writes.join().unwrap()
+}
+
+// Compaction keeps the records an open snapshot sees, and reclaims them once
+// it is released.
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(4096)
+        .compaction(CompactionPolicy::Manual);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("old{}", i))?;
+    }
+    let snapshot = store.snapshot()?;
+    for round in 0..10 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("new{}-{}", round, i))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+    let size_with_snapshot = dir_size(temp_dir.path())?;
+
+    for i in 0..100 {
+        assert_eq!(
+            snapshot.get(format!("key{}", i))?,
+            Some(format!("old{}", i))
+        );
+    }
+    assert_eq!(snapshot.scan(..).count(), 100);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("new9-1".to_owned()));
+
+    drop(snapshot);
+    assert!(dir_size(temp_dir.path())? < size_with_snapshot / 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("new9-1".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("new9-99".to_owned()));
+    Ok(())
+}
+
+fn transactions<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("alice".to_owned(), "100".to_owned())?;
+    engine.set("bob".to_owned(), "0".to_owned())?;
+
+    // Each transfer moves 1 from alice to bob; conflicting ones retry.
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..10 {
+                    engine.transaction(|txn| {
+                        let alice: u32 = txn.get("alice".to_owned())?.unwrap().parse().unwrap();
+                        let bob: u32 = txn.get("bob".to_owned())?.unwrap().parse().unwrap();
+                        txn.set("alice".to_owned(), (alice - 1).to_string());
+                        txn.set("bob".to_owned(), (bob + 1).to_string());
+                        Ok(())
+                    })?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get("alice".to_owned())?, Some("60".to_owned()));
+    assert_eq!(engine.get("bob".to_owned())?, Some("40".to_owned()));
+
+    // Reads see the transaction's own writes.
+    let mut txn = engine.begin();
+    txn.set("carol".to_owned(), "5".to_owned());
+    txn.remove("bob".to_owned());
+    txn.remove("dave".to_owned());
+    assert_eq!(txn.get("carol".to_owned())?, Some("5".to_owned()));
+    assert_eq!(txn.get("bob".to_owned())?, None);
+    assert_eq!(engine.get("carol".to_owned())?, None);
+    txn.commit()?;
+    assert_eq!(engine.get("carol".to_owned())?, Some("5".to_owned()));
+    assert_eq!(engine.get("bob".to_owned())?, None);
+
+    // A write to a key read since `begin` fails the commit, writing nothing.
+    let mut txn = engine.begin();
+    assert_eq!(txn.get("alice".to_owned())?, Some("60".to_owned()));
+    txn.set("carol".to_owned(), "6".to_owned());
+    engine.set("alice".to_owned(), "61".to_owned())?;
+    match txn.commit() {
+        Err(KvError::Conflict) => {}
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+    assert_eq!(engine.get("carol".to_owned())?, Some("5".to_owned()));
+
+    // So does creating a key read as absent.
+    let mut txn = engine.begin();
+    assert_eq!(txn.get("erin".to_owned())?, None);
+    txn.set("carol".to_owned(), "7".to_owned());
+    engine.set("erin".to_owned(), "1".to_owned())?;
+    assert!(txn.commit().is_err());
+    assert_eq!(engine.get("carol".to_owned())?, Some("5".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    transactions(&store)
+}
+
+#[test]
+fn sled_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    transactions(&engine)
+}
+
+// A directory can only be open once at a time, even within one process.
+#[test]
+fn data_dir_is_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    match KvStore::open(temp_dir.path()).err() {
+        Some(KvError::Locked { pid, .. }) => assert_eq!(pid, std::process::id()),
+        other => panic!("expected the directory to be locked, got {:?}", other),
+    }
+
+    // An open snapshot keeps the directory locked.
+    let snapshot = store.snapshot()?;
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(snapshot);
+    let store = KvStore::open(temp_dir.path())?;
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    match SledKvsEngine::open(temp_dir.path()).err() {
+        Some(KvError::Locked { pid, .. }) => assert_eq!(pid, std::process::id()),
+        other => panic!("expected the directory to be locked, got {:?}", other),
+    }
+    let snapshot = engine.snapshot()?;
+    drop(engine);
+    assert!(SledKvsEngine::open(temp_dir.path()).is_err());
+    drop(snapshot);
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    drop(engine);
+    Ok(())
+}
+
+// Read-only stores share the directory and leave `LOCK` as it is. One opened
+// beside a writer keeps reading the data as of the open, whatever the writer
+// merges away meanwhile.
+#[test]
+fn read_only_opens_share_the_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(1024)
+        .compaction(CompactionPolicy::OnSeal);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), "0".to_owned())?;
+    }
+    drop(store);
+    let lock_path = temp_dir.path().join("LOCK");
+    let lock = fs::read(&lock_path)?;
+
+    let read_only = KvStoreOptions::new().read_only(true);
+    let first = KvStore::open_with(temp_dir.path(), read_only.clone())?;
+    let second = KvStore::open_with(temp_dir.path(), read_only.clone())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("0".to_owned()));
+    match KvStore::open(temp_dir.path()).err() {
+        Some(KvError::Locked { pid, .. }) => assert_eq!(pid, 0),
+        other => panic!("expected the directory to be locked, got {:?}", other),
+    }
+    drop(first);
+    drop(second);
+    assert_eq!(fs::read(&lock_path)?, lock);
+    fs::remove_file(&lock_path)?;
+    drop(KvStore::open_with(temp_dir.path(), read_only.clone())?);
+    assert!(!lock_path.exists());
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let reader = KvStore::open_with(temp_dir.path(), read_only)?;
+    for round in 1..=20 {
+        for i in 0..20 {
+            store.set(format!("key{}", i), round.to_string())?;
+        }
+    }
+    store.compact()?;
+    assert!(count_segments(temp_dir.path())? <= 2);
+    for i in 0..20 {
+        assert_eq!(reader.get(format!("key{}", i))?, Some("0".to_owned()));
+    }
+    assert_eq!(reader.scan(..).count(), 20);
+    assert_eq!(store.get("key1".to_owned())?, Some("20".to_owned()));
+    Ok(())
+}
+
+// The lock is released by the time the last handle is dropped, never later
+// by a background thread still holding the store.
+#[test]
+fn reopen_right_after_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .durability(Durability::FsyncEvery(Duration::from_millis(1)))
+        .reap_interval(Duration::from_millis(1));
+    for i in 0..200 {
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set_with_ttl(
+            format!("key{}", i).into_bytes(),
+            Vec::new(),
+            Duration::from_millis(1),
+        )?;
+        thread::sleep(Duration::from_micros(500));
+    }
+    Ok(())
+}
+
+fn checkpoints<E: KvsEngine>(engine: &E, open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    for i in 0..200 {
+        engine.set(format!("key{}", i), "x".repeat(100))?;
+    }
+    engine.remove("key0".to_owned())?;
+    engine.set_with_ttl(
+        b"expiring".to_vec(),
+        b"value".to_vec(),
+        Duration::from_secs(3600),
+    )?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = temp_dir.path().join("checkpoint");
+    engine.checkpoint(&dest)?;
+    engine.set("key1".to_owned(), "changed".to_owned())?;
+    engine.set("key200".to_owned(), "new".to_owned())?;
+
+    // A destination that is not empty is refused.
+    assert!(engine.checkpoint(&dest).is_err());
+
+    let restored = temp_dir.path().join("restored");
+    let report = backup::restore(&dest, &restored)?;
+    assert_eq!(report.keys, 200);
+    let copy = open(&restored)?;
+    assert_eq!(copy.get("key0".to_owned())?, None);
+    assert_eq!(copy.get("key1".to_owned())?, Some("x".repeat(100)));
+    assert_eq!(copy.get("key200".to_owned())?, None);
+    assert!(copy.ttl(b"expiring")?.is_some());
+    copy.set("key201".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.get("key201".to_owned())?, None);
+
+    // A checkpoint cut short has no engine file and cannot be restored.
+    drop(copy);
+    fs::remove_file(dest.join("engine"))?;
+    assert!(backup::restore(&dest, &temp_dir.path().join("partial")).is_err());
+    assert!(!temp_dir.path().join("partial").exists());
+    Ok(())
+}
+
+#[test]
+fn kvs_checkpoints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(4096)
+        .compaction(CompactionPolicy::SealedSegments(3));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    checkpoints(&store, |dir| KvStore::open(dir))
+}
+
+#[test]
+fn sled_checkpoints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    checkpoints(&engine, |dir| SledKvsEngine::open(dir))
+}
+
+// Writes and compactions carry on while a checkpoint is taken, and every
+// checkpoint still opens with each key at a value it really had.
+#[test]
+fn checkpoint_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(4096)
+        .compaction(CompactionPolicy::OnSeal);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    checkpoints_during_writes(store, |dir| KvStore::open(dir))
+}
+
+#[test]
+fn sled_checkpoint_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open_with(
+        temp_dir.path(),
+        SledOptions::new().durability(Durability::None),
+    )?;
+    checkpoints_during_writes(engine, |dir| SledKvsEngine::open(dir))
+}
+
+fn checkpoints_during_writes<E: KvsEngine>(
+    store: E,
+    open: impl Fn(&Path) -> Result<E>,
+) -> Result<()> {
+    for i in 0..100 {
+        store.set(format!("key{}", i), "0".to_owned())?;
+    }
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 1..=50 {
+                for i in 0..100 {
+                    store.set(format!("key{}", i), round.to_string())?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let checkpoints = TempDir::new().expect("unable to create temporary working directory");
+    for n in 0..5 {
+        let dest = checkpoints.path().join(n.to_string());
+        store.checkpoint(&dest)?;
+        let copy = open(&dest)?;
+        // Each round writes the keys in order, so a consistent copy has the
+        // first keys at one round and the rest at the round before.
+        let mut rounds = Vec::new();
+        for i in 0..100 {
+            let value = copy.get(format!("key{}", i))?.unwrap();
+            rounds.push(value.parse::<u32>().unwrap());
+        }
+        assert!(rounds.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert!(rounds[0] - rounds[99] <= 1, "{:?}", rounds);
+    }
+    writer.join().unwrap()
+}
+
+// An archive rebuilds the store as of any write since it started, across
+// compactions and restarts.
+#[test]
+fn archive_restores_any_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let archive_dir = temp_dir.path().join("archive");
+    let options = KvStoreOptions::new()
+        .segment_size(1024)
+        .compaction(CompactionPolicy::OnSeal)
+        .archive(&archive_dir);
+    let mut points = Vec::new();
+    for round in 0..6 {
+        let store = KvStore::open_with(&data_dir, options.clone())?;
+        for i in 0..20 {
+            store.set(format!("key{}", i), round.to_string())?;
+        }
+        store.remove(format!("key{}", round))?;
+        points.push((round, store.snapshot()?.seq()));
+        if round % 2 == 1 {
+            store.compact()?;
+        }
+    }
+    let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+    for &(round, seq) in &points {
+        let dest = restored_dir.path().join(round.to_string());
+        let restored = archive::restore(&archive_dir, &dest, RestorePoint::Seq(seq))?;
+        assert_eq!(restored.seq, seq);
+        assert_eq!(restored.keys, 19);
+        let store = KvStore::open(&dest)?;
+        assert_eq!(store.snapshot()?.seq(), seq);
+        assert_eq!(store.get(format!("key{}", round))?, None);
+        for i in (0..20).filter(|&i| i != round) {
+            assert_eq!(store.get(format!("key{}", i))?, Some(round.to_string()));
+        }
+    }
+
+    let (_, last) = points[5];
+    let dest = restored_dir.path().join("later");
+    match archive::restore(&archive_dir, &dest, RestorePoint::Seq(last + 1)) {
+        Err(KvError::Archive(_)) => {}
+        other => panic!("expected an archive error, got {:?}", other),
+    }
+    assert!(!dest.exists());
+    Ok(())
+}
+
+// A restore point given as a time takes every write made up to the end of
+// that second.
+#[test]
+fn archive_restores_to_a_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive_dir = temp_dir.path().join("archive");
+    let options = KvStoreOptions::new().archive(&archive_dir);
+    let store = KvStore::open_with(temp_dir.path().join("data"), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let now = || {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    };
+    let second = now();
+    while now() == second {
+        thread::sleep(Duration::from_millis(50));
+    }
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+
+    let dest = temp_dir.path().join("restored");
+    let restored = archive::restore(&archive_dir, &dest, RestorePoint::Time(second))?;
+    assert_eq!(restored.keys, 1);
+    let restored = KvStore::open(&dest)?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(restored.get("key2".to_owned())?, None);
+
+    match archive::restore(
+        &archive_dir,
+        &temp_dir.path().join("early"),
+        RestorePoint::Time(second - 60),
+    ) {
+        Err(KvError::Archive(_)) => {}
+        other => panic!("expected an archive error, got {:?}", other),
+    }
+    Ok(())
+}
+
+// Segments sealed while the store was opened without its archive leave a
+// gap the archive refuses to paper over.
+#[test]
+fn archive_with_gap_is_refused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let archive_dir = temp_dir.path().join("archive");
+    let options = KvStoreOptions::new().segment_size(1024);
+    let store = KvStore::open_with(&data_dir, options.clone().archive(&archive_dir))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with(&data_dir, options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    drop(store);
+    match KvStore::open_with(&data_dir, options.archive(&archive_dir)) {
+        Err(KvError::Archive(_)) => Ok(()),
+        Err(e) => Err(e),
+        Ok(_) => panic!("expected an archive error"),
+    }
+}
+
+#[test]
+fn restore_point_from_str() {
+    assert_eq!(
+        "42".parse::<RestorePoint>().ok(),
+        Some(RestorePoint::Seq(42))
+    );
+    assert_eq!(
+        "@1700000000".parse::<RestorePoint>().ok(),
+        Some(RestorePoint::Time(1_700_000_000))
+    );
+    assert!("yesterday".parse::<RestorePoint>().is_err());
+}
+
+fn dump_and_load<E: KvsEngine, F: KvsEngine>(
+    source: &E,
+    target: &F,
+    format: DumpFormat,
+) -> Result<()> {
+    source.set("key1".to_owned(), "line one\nline \"two\", too".to_owned())?;
+    source.set_bytes(vec![0, 0xff], vec![0xfe, 1])?;
+    source.set_with_ttl(
+        b"key2".to_vec(),
+        b"value2".to_vec(),
+        Duration::from_secs(3600),
+    )?;
+    source.set_with_ttl(
+        b"key3".to_vec(),
+        b"value3".to_vec(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+
+    let deadline = source
+        .deadline(b"key2")?
+        .expect("key2 should have a deadline");
+    assert_eq!(source.deadline(b"key1")?, None);
+    match source.deadline(b"key3") {
+        Err(KvError::KeyNotExit) => {}
+        other => panic!("expected KeyNotExit for an expired key, got {:?}", other),
+    }
+
+    // The dump carries the stored deadline, not one worked out again from
+    // the time left.
+    let mut out = Vec::new();
+    assert_eq!(dump::dump(source, &mut out, format, None, |_, _| {})?, 3);
+    assert!(String::from_utf8_lossy(&out).contains(&deadline.to_string()));
+    assert_eq!(dump::load(target, &out[..], format, None, |_, _| {})?, 3);
+    assert_eq!(
+        target.get("key1".to_owned())?,
+        Some("line one\nline \"two\", too".to_owned())
+    );
+    assert_eq!(target.get_bytes(&[0, 0xff])?, Some(vec![0xfe, 1]));
+    assert_eq!(target.get("key2".to_owned())?, Some("value2".to_owned()));
+    let ttl = target.ttl(b"key2")?.expect("key2 should keep its deadline");
+    assert!(ttl > Duration::from_secs(3500) && ttl <= Duration::from_secs(3600));
+    assert_eq!(target.get("key3".to_owned())?, None);
+
+    // Resuming after a key dumps and loads only the keys that follow it.
+    let mut rest = Vec::new();
+    assert_eq!(
+        dump::dump(source, &mut rest, format, Some(b"key1"), |_, _| {})?,
+        1
+    );
+    assert_eq!(
+        dump::load(target, &out[..], format, Some(b"key1"), |_, _| {})?,
+        1
+    );
+    Ok(())
+}
+
+#[test]
+fn kvs_to_sled_dump_and_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = KvStore::open(temp_dir.path().join("kvs"))?;
+    let target = SledKvsEngine::open(temp_dir.path().join("sled"))?;
+    dump_and_load(&source, &target, DumpFormat::JsonLines)
+}
+
+#[test]
+fn sled_to_kvs_dump_and_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = SledKvsEngine::open(temp_dir.path().join("sled"))?;
+    let target = KvStore::open(temp_dir.path().join("kvs"))?;
+    dump_and_load(&source, &target, DumpFormat::Csv)
+}
+
+#[test]
+fn malformed_dump_is_refused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let jsonl = b"{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\"}\n";
+    match dump::load(&store, &jsonl[..], DumpFormat::JsonLines, None, |_, _| {}) {
+        Err(KvError::BadDump { record: 2, .. }) => {}
+        other => panic!("expected a malformed dump error, got {:?}", other),
+    }
+    let csv = b"key,value,encoding,expires_at\nc,1,,\nd,!!,base64,\n";
+    match dump::load(&store, &csv[..], DumpFormat::Csv, None, |_, _| {}) {
+        Err(KvError::BadDump { record: 3, .. }) => {}
+        other => panic!("expected a malformed dump error, got {:?}", other),
+    }
+    Ok(())
+}
+
+// `convert` should copy every key and deadline to the other engine, and only
+// mark the copy as a store of that engine once it checks out. `cut_over`
+// should then swap the copy in.
+#[test]
+fn convert_between_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs_dir = temp_dir.path().join("kvs");
+    let store = KvStore::open(&kvs_dir)?;
+    for i in 0..2500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set_bytes(vec![0, 0xff], vec![0xfe])?;
+    store.set_with_ttl(
+        b"expiring".to_vec(),
+        b"soon".to_vec(),
+        Duration::from_secs(60),
+    )?;
+    store.remove("key0".to_owned())?;
+    let original = convert::checksum(&store)?;
+    drop(store);
+    marker::record_engine(&kvs_dir, "kvs")?;
+
+    let sled_dir = temp_dir.path().join("sled");
+    let converted = convert::convert(&kvs_dir, &sled_dir, "sled", |_| {})?;
+    assert_eq!(converted.keys, 2501);
+    assert_eq!(converted.checksum, original);
+    assert_eq!(marker::recorded_engine(&sled_dir)?, Some("sled".to_owned()));
+    let sled = SledKvsEngine::open(&sled_dir)?;
+    assert_eq!(convert::checksum(&sled)?, original);
+    assert_eq!(sled.get_bytes(&[0, 0xff])?, Some(vec![0xfe]));
+    assert!(sled.ttl(b"expiring")?.unwrap() > Duration::from_secs(50));
+    drop(sled);
+
+    // And back again.
+    let back_dir = temp_dir.path().join("back");
+    convert::convert(&sled_dir, &back_dir, "kvs", |_| {})?;
+    let store = KvStore::open(&back_dir)?;
+    assert_eq!(convert::checksum(&store)?, original);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    drop(store);
+
+    // The destination must be new.
+    assert!(convert::convert(&kvs_dir, &sled_dir, "sled", |_| {}).is_err());
+
+    // The cut-over waits for the original to be closed, then swaps the two.
+    let store = KvStore::open(&kvs_dir)?;
+    assert!(convert::cut_over(&kvs_dir, &sled_dir).is_err());
+    drop(store);
+    convert::cut_over(&kvs_dir, &sled_dir)?;
+    assert_eq!(marker::recorded_engine(&kvs_dir)?, Some("sled".to_owned()));
+    assert_eq!(marker::recorded_engine(&sled_dir)?, Some("kvs".to_owned()));
+    assert_eq!(
+        convert::checksum(&SledKvsEngine::open(&kvs_dir)?)?,
+        original
+    );
+    assert_eq!(convert::checksum(&KvStore::open(&sled_dir)?)?, original);
+    Ok(())
+}
+
+// Writes made while `DualWrite` backfills should end up on both engines,
+// and keys only the secondary had should be gone once it has.
+#[test]
+fn dual_write_mirrors_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = KvStore::open(temp_dir.path().join("kvs"))?;
+    let secondary = SledKvsEngine::open(temp_dir.path().join("sled"))?;
+    for i in 0..2500 {
+        primary.set(format!("key{:04}", i), "old".to_owned())?;
+    }
+    secondary.set("stale".to_owned(), "value".to_owned())?;
+
+    let engine = DualWrite::new(primary.clone(), secondary.clone());
+    let writer = engine.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for i in (0..2499).step_by(7) {
+            writer.set(format!("key{:04}", i), "new".to_owned())?;
+            writer.remove(format!("key{:04}", i + 1))?;
+        }
+        let mut batch = WriteBatch::new();
+        batch.set(b"batched".to_vec(), b"value".to_vec());
+        writer.write_batch(batch)?;
+        writer.compare_and_swap(
+            b"key0002".to_vec(),
+            Some(b"old".to_vec()),
+            Some(b"swapped".to_vec()),
+        )?;
+        writer.transaction(|txn| {
+            txn.set("txn".to_owned(), "value".to_owned());
+            Ok(())
+        })?;
+        writer.set_with_ttl(
+            b"expiring".to_vec(),
+            b"soon".to_vec(),
+            Duration::from_secs(60),
+        )?;
+        Ok(())
+    });
+    engine.backfill(|_| {})?;
+    handle.join().unwrap()?;
+    engine.set("after".to_owned(), "backfill".to_owned())?;
+
+    assert_eq!(convert::checksum(&primary)?, convert::checksum(&secondary)?);
+    assert_eq!(secondary.get("stale".to_owned())?, None);
+    assert_eq!(
+        secondary.get("key0002".to_owned())?,
+        Some("swapped".to_owned())
+    );
+    assert_eq!(secondary.get("key0007".to_owned())?, Some("new".to_owned()));
+    assert_eq!(secondary.get("key0008".to_owned())?, None);
+    assert_eq!(secondary.get("txn".to_owned())?, Some("value".to_owned()));
+    assert!(secondary.ttl(b"expiring")?.is_some());
+    assert_eq!(engine.get("after".to_owned())?, Some("backfill".to_owned()));
+    Ok(())
+}
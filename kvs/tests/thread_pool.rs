@@ -0,0 +1,65 @@
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+const TASK_NUM: usize = 20;
+const ADD_COUNT: usize = 1000;
+
+fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        let sender = sender.clone();
+        pool.spawn(move || {
+            for _ in 0..ADD_COUNT {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..TASK_NUM {
+        receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("task did not finish");
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM * ADD_COUNT);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(RayonThreadPool::new(4)?)
+}
+
+// Every worker panics once; the pool must still run the jobs after that.
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    for _ in 0..TASK_NUM {
+        pool.spawn(|| panic!("intentional panic in a pool job"));
+    }
+    spawn_counter(pool)
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    let pool = RayonThreadPool::new(4)?;
+    for _ in 0..TASK_NUM {
+        pool.spawn(|| panic!("intentional panic in a pool job"));
+    }
+    spawn_counter(pool)
+}